@@ -1,7 +1,12 @@
 MEMORY {
     BOOT2 : ORIGIN = 0x10000000, LENGTH = 0x100
-    FLASH : ORIGIN = 0x10000100, LENGTH = 2048K - 0x100
+    /* 最終セクタ(4K)は永続設定、その下の4セクタ(16K)はKVストアに使うのでプログラムを置かない */
+    FLASH : ORIGIN = 0x10000100, LENGTH = 2048K - 0x100 - 4K - 16K
+    /* SRAM0-3 (ストライプ) */
     RAM   : ORIGIN = 0x20000000, LENGTH = 256K
+    /* コアごとに使い分けてバスの取り合いを避ける4Kずつのバンク。SRAM4はcore0、SRAM5はcore1 */
+    SRAM4 : ORIGIN = 0x20040000, LENGTH = 4K
+    SRAM5 : ORIGIN = 0x20041000, LENGTH = 4K
 }
 
 EXTERN(BOOT2_FIRMWARE)
@@ -12,4 +17,50 @@ SECTIONS {
     {
         KEEP(*(.boot2));
     } > BOOT2
-} INSERT BEFORE .text;
\ No newline at end of file
+} INSERT BEFORE .text;
+
+/* ヒープの大きさ。ビルド時に RUSTFLAGS="-C link-arg=--defsym=_heap_size=8192" などで変えられる */
+PROVIDE(_heap_size = 1K);
+
+SECTIONS {
+    /* ### Heap */
+    /* .uninitの後ろに置く。flip-linkが静的データごとRAMの上端に寄せるので、core0のスタックはその下になる */
+    .heap (NOLOAD) : ALIGN(8)
+    {
+        __heap_start = .;
+        . += _heap_size;
+        __heap_end = .;
+    } > RAM
+} INSERT AFTER .uninit;
+
+SECTIONS {
+    /* ### RAMで実行するコード */
+    /* .dataの直後に置き、起動時に.dataと一緒にフラッシュからコピーされる */
+    .ram_text : ALIGN(4)
+    {
+        __sram_text = .;
+        *(.ram_text .ram_text.*);
+        . = ALIGN(4);
+        __eram_text = .;
+    } > RAM AT>FLASH
+} INSERT AFTER .data;
+
+SECTIONS {
+    /* ### SRAM4/SRAM5 */
+    /* 起動時にmemlayout::initで0にするので、0で初期化するstaticだけを置く */
+    .sram4 (NOLOAD) : ALIGN(4)
+    {
+        __ssram4 = .;
+        *(.sram4 .sram4.*);
+        . = ALIGN(4);
+        __esram4 = .;
+    } > SRAM4
+
+    .sram5 (NOLOAD) : ALIGN(4)
+    {
+        __ssram5 = .;
+        *(.sram5 .sram5.*);
+        . = ALIGN(4);
+        __esram5 = .;
+    } > SRAM5
+} INSERT AFTER .got;
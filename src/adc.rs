@@ -0,0 +1,473 @@
+// ADC0〜ADC3と内蔵温度センサーの読み出し
+// ADC3(GPIO29)はPicoではVSYS/3の分圧が繋がっている
+use crate::command::{ok_reply, write_milli, Args, CommandError, Reply};
+use crate::globals::{ADC, MAX_MESSAGE_SIZE};
+use crate::msgpool::MessageKind;
+use crate::pinpool::{self, PoolPin};
+use crate::sharedmessage::{SHARED_MESSAGE_CORE0_TO_CORE1, SHARED_MESSAGE_CORE1_TO_CORE0};
+use core::fmt::Write;
+use cortex_m::interrupt;
+use embedded_hal_0_2::adc::OneShot;
+use heapless::String;
+use rp_pico::hal::adc::{AdcPin, TempSense};
+use rp_pico::hal::Adc;
+
+pub const ADC_VREF_MV: u32 = 3300;
+pub const ADC_BITS: u8 = 12;
+pub const ADC_FIRST_GPIO: u8 = 26;
+pub const NUM_ADC_PINS: u8 = 4;
+pub const TEMP_SENSOR_CHANNEL: u8 = 4;
+// 1回のコマンドで取るサンプル数の上限 (1サンプル2us、USBポーリングを止めすぎないように)
+pub const MAX_TOTAL_SAMPLES: u32 = 1024;
+pub const MAX_OVERSAMPLE_BITS: u8 = 4;
+// ストリームはcore0の10ms割り込みで回す
+pub const STREAM_TICK_MS: u32 = 10;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum AdcInput {
+    Pin(u8), // ADC0〜ADC3
+    Temp,
+}
+
+impl AdcInput {
+    pub fn parse(s: &str) -> Result<Self, CommandError> {
+        if s == "temp" {
+            return Ok(AdcInput::Temp);
+        }
+        let ch = s.parse::<u8>().map_err(|_| CommandError::InvalidArgument)?;
+        if ch < NUM_ADC_PINS {
+            Ok(AdcInput::Pin(ch))
+        } else {
+            Err(CommandError::OutOfRange)
+        }
+    }
+
+    // ADCのAINSEL番号 (温度センサーは4)
+    pub fn channel(&self) -> u8 {
+        match self {
+            AdcInput::Pin(ch) => *ch,
+            AdcInput::Temp => TEMP_SENSOR_CHANNEL,
+        }
+    }
+
+    pub fn gpio(&self) -> Option<u8> {
+        match self {
+            AdcInput::Pin(ch) => Some(ADC_FIRST_GPIO + ch),
+            AdcInput::Temp => None,
+        }
+    }
+}
+
+impl core::fmt::Display for AdcInput {
+    fn fmt(&self, f: &mut core::fmt::Formatter<'_>) -> core::fmt::Result {
+        match self {
+            AdcInput::Pin(ch) => write!(f, "{}", ch),
+            AdcInput::Temp => f.write_str("temp"),
+        }
+    }
+}
+
+// 平均回数とオーバーサンプリングのビット数
+// オーバーサンプリングは4^bits回の和をbitsだけ右シフトして分解能をbitsビット上げる
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Sampling {
+    pub average: u32,
+    pub oversample_bits: u8,
+}
+
+impl Default for Sampling {
+    fn default() -> Self {
+        Self {
+            average: 1,
+            oversample_bits: 0,
+        }
+    }
+}
+
+impl Sampling {
+    // `avg <n>` と `os <bits>` を任意の順番で受け付ける
+    pub fn parse(args: &mut Args) -> Result<Self, CommandError> {
+        let mut sampling = Sampling::default();
+        while let Some(key) = args.next_opt() {
+            match key {
+                "avg" => sampling.average = args.next_u32()?,
+                "os" => {
+                    sampling.oversample_bits =
+                        u8::try_from(args.next_u32()?).map_err(|_| CommandError::OutOfRange)?
+                }
+                _ => return Err(CommandError::InvalidArgument),
+            }
+        }
+        if sampling.average == 0
+            || sampling.oversample_bits > MAX_OVERSAMPLE_BITS
+            || !sampling
+                .total_samples()
+                .is_some_and(|n| n <= MAX_TOTAL_SAMPLES)
+        {
+            return Err(CommandError::OutOfRange);
+        }
+        Ok(sampling)
+    }
+
+    // 1回の読み出しでADCを変換する回数。u32に収まらなければNone
+    pub fn total_samples(&self) -> Option<u32> {
+        1u32.checked_shl(2 * self.oversample_bits as u32)
+            .and_then(|per_average| self.average.checked_mul(per_average))
+    }
+
+    pub fn resolution_bits(&self) -> u8 {
+        ADC_BITS + self.oversample_bits
+    }
+
+    pub fn acquire(&self, mut read: impl FnMut() -> u16) -> u32 {
+        let per_average = 1u32 << (2 * self.oversample_bits as u32);
+        // parseで回数は抑えているが、合計はu64で取って溢れないようにする
+        let mut sum = 0u64;
+        for _ in 0..self.average {
+            let mut oversampled = 0u64;
+            for _ in 0..per_average {
+                oversampled += read() as u64;
+            }
+            sum += oversampled >> self.oversample_bits;
+        }
+        (sum / self.average as u64) as u32
+    }
+}
+
+pub fn raw_to_millivolts(raw: u32, bits: u8) -> u32 {
+    let full_scale = (1u32 << bits) - 1;
+    (raw as u64 * ADC_VREF_MV as u64 / full_scale as u64) as u32
+}
+
+// データシートの式 T = 27 - (V - 0.706) / 0.001721 をミリ度で計算する
+pub fn raw_to_millicelsius(raw: u32, bits: u8) -> i32 {
+    let full_scale = (1u64 << bits) - 1;
+    let microvolts = (raw as u64 * ADC_VREF_MV as u64 * 1000 / full_scale) as i64;
+    (27_000 - (microvolts - 706_000) * 1000 / 1721) as i32
+}
+
+pub fn write_reading(
+    out: &mut impl Write,
+    input: AdcInput,
+    raw: u32,
+    bits: u8,
+) -> core::fmt::Result {
+    write!(
+        out,
+        " adc ch={} raw={} bits={} mv={}",
+        input,
+        raw,
+        bits,
+        raw_to_millivolts(raw, bits)
+    )?;
+    if input == AdcInput::Temp {
+        out.write_str(" degc=")?;
+        write_milli(out, raw_to_millicelsius(raw, bits))?;
+    }
+    Ok(())
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum AdcCommand {
+    Read(AdcInput, Sampling),
+    Stream {
+        input: AdcInput,
+        period_ms: u32,
+        sampling: Sampling,
+    },
+    StreamOff,
+}
+
+impl AdcCommand {
+    pub fn parse(args: &mut Args) -> Result<Self, CommandError> {
+        match args.next_str()? {
+            "read" => {
+                let input = AdcInput::parse(args.next_str()?)?;
+                Ok(AdcCommand::Read(input, Sampling::parse(args)?))
+            }
+            "stream" => {
+                let target = args.next_str()?;
+                if target == "off" {
+                    return Ok(AdcCommand::StreamOff);
+                }
+                let input = AdcInput::parse(target)?;
+                let period_ms = args.next_u32()?;
+                if period_ms < STREAM_TICK_MS || period_ms % STREAM_TICK_MS != 0 {
+                    return Err(CommandError::OutOfRange);
+                }
+                Ok(AdcCommand::Stream {
+                    input,
+                    period_ms,
+                    sampling: Sampling::parse(args)?,
+                })
+            }
+            _ => Err(CommandError::InvalidArgument),
+        }
+    }
+}
+
+struct Stream {
+    input: AdcInput,
+    sampling: Sampling,
+    period_ticks: u32,
+    countdown: u32,
+    pin: Option<AdcPin<PoolPin>>,
+}
+
+pub struct AdcState {
+    adc: Adc,
+    temp: Option<TempSense>,
+    stream: Option<Stream>,
+    // DMAキャプチャ中はADCを占有される
+    capturing: bool,
+}
+
+impl AdcState {
+    pub fn new(adc: Adc) -> Self {
+        Self {
+            adc,
+            temp: None,
+            stream: None,
+            capturing: false,
+        }
+    }
+
+    pub(crate) fn claim_pin(input: AdcInput) -> Result<Option<AdcPin<PoolPin>>, CommandError> {
+        match input.gpio() {
+            Some(gpio) => {
+                let pin = pinpool::take(gpio)?;
+                // 26〜29以外はここに来ないのでエラーにはならない
+                AdcPin::new(pin)
+                    .map(Some)
+                    .map_err(|_| CommandError::InvalidArgument)
+            }
+            None => Ok(None),
+        }
+    }
+
+    pub(crate) fn release_pin(pin: Option<AdcPin<PoolPin>>) {
+        if let Some(pin) = pin {
+            pinpool::give(pin.release());
+        }
+    }
+
+    fn sample(
+        &mut self,
+        pin: Option<&mut AdcPin<PoolPin>>,
+        sampling: &Sampling,
+    ) -> Result<u32, CommandError> {
+        if pin.is_none() {
+            self.enable_temp_sensor();
+        }
+        let adc = &mut self.adc;
+        match pin {
+            Some(pin) => Ok(sampling.acquire(|| adc.read(pin).unwrap_or(0))),
+            None => {
+                let temp = self.temp.as_mut().ok_or(CommandError::NotReady)?;
+                Ok(sampling.acquire(|| adc.read(temp).unwrap_or(0)))
+            }
+        }
+    }
+
+    pub(crate) fn enable_temp_sensor(&mut self) {
+        if self.temp.is_none() {
+            self.temp = self.adc.take_temp_sensor();
+        }
+    }
+
+    // 他の機能が借りているADCピンを1回だけ読む。キャプチャ中は読めない
+    #[cfg(feature = "midi")]
+    pub(crate) fn read_pin(&mut self, pin: &mut AdcPin<PoolPin>) -> Option<u16> {
+        if self.capturing {
+            return None;
+        }
+        self.adc.read(pin).ok()
+    }
+
+    // ストリームと同時にはキャプチャできない
+    pub(crate) fn begin_capture(&mut self) -> Result<&mut Adc, CommandError> {
+        if self.capturing || self.stream.is_some() {
+            return Err(CommandError::Busy);
+        }
+        self.capturing = true;
+        Ok(&mut self.adc)
+    }
+
+    pub(crate) fn end_capture(&mut self) -> &mut Adc {
+        self.capturing = false;
+        &mut self.adc
+    }
+
+    pub fn execute(&mut self, cmd: AdcCommand) -> Result<Reply, CommandError> {
+        if self.capturing {
+            return Err(CommandError::Busy);
+        }
+        match cmd {
+            AdcCommand::Read(input, sampling) => {
+                // ストリーム中のピンは借りられないので、そのままストリームのピンで読む
+                let raw = match self.stream.take() {
+                    Some(mut stream) if stream.input == input => {
+                        let raw = self.sample(stream.pin.as_mut(), &sampling);
+                        self.stream = Some(stream);
+                        raw?
+                    }
+                    other => {
+                        self.stream = other;
+                        let mut pin = Self::claim_pin(input)?;
+                        let raw = self.sample(pin.as_mut(), &sampling);
+                        Self::release_pin(pin);
+                        raw?
+                    }
+                };
+                Ok(ok_reply(|r| {
+                    write_reading(r, input, raw, sampling.resolution_bits())
+                }))
+            }
+            AdcCommand::Stream {
+                input,
+                period_ms,
+                sampling,
+            } => {
+                self.stop_stream();
+                let pin = Self::claim_pin(input)?;
+                let period_ticks = period_ms / STREAM_TICK_MS;
+                self.stream = Some(Stream {
+                    input,
+                    sampling,
+                    period_ticks,
+                    countdown: period_ticks,
+                    pin,
+                });
+                Ok(ok_reply(|r| {
+                    write!(r, " adc stream ch={} period_ms={}", input, period_ms)
+                }))
+            }
+            AdcCommand::StreamOff => {
+                self.stop_stream();
+                Ok(ok_reply(|r| r.write_str(" adc stream off")))
+            }
+        }
+    }
+
+    fn stop_stream(&mut self) {
+        if let Some(stream) = self.stream.take() {
+            Self::release_pin(stream.pin);
+        }
+    }
+
+    // 周期が来ていればサンプリングしてcore1向けの生サンプルメッセージを返す
+    fn poll_stream(&mut self) -> Option<String<MAX_MESSAGE_SIZE>> {
+        let mut stream = self.stream.take()?;
+        stream.countdown -= 1;
+        let mut msg = None;
+        if stream.countdown == 0 {
+            stream.countdown = stream.period_ticks;
+            if let Ok(raw) = self.sample(stream.pin.as_mut(), &stream.sampling) {
+                let mut s = String::new();
+                let _ = write!(
+                    s,
+                    "{} {} {}",
+                    stream.input,
+                    raw,
+                    stream.sampling.resolution_bits()
+                );
+                msg = Some(s);
+            }
+        }
+        self.stream = Some(stream);
+        msg
+    }
+}
+
+pub fn handle_command(args: &mut Args) -> Result<Reply, CommandError> {
+    let cmd = AdcCommand::parse(args)?;
+    interrupt::free(|cs| {
+        ADC.borrow(cs)
+            .borrow_mut()
+            .as_mut()
+            .ok_or(CommandError::NotReady)?
+            .execute(cmd)
+    })
+}
+
+// core0の10ms割り込みから呼ぶ
+pub fn poll_stream() {
+    interrupt::free(|cs| {
+        let sample = ADC
+            .borrow(cs)
+            .borrow_mut()
+            .as_mut()
+            .and_then(|adc| adc.poll_stream());
+        if let Some(sample) = sample {
+            // ホストの行と混ざらないよう種類を付けて送る
+            SHARED_MESSAGE_CORE0_TO_CORE1
+                .borrow(cs)
+                .write_kind(MessageKind::AdcSample, &sample);
+        }
+    });
+}
+
+// core1側: 生サンプルを電圧/温度に変換してホスト向けの行にする
+pub fn format_stream_sample(msg: &str) -> Option<String<MAX_MESSAGE_SIZE>> {
+    let mut args = Args::new(msg);
+    let input = AdcInput::parse(args.next_str().ok()?).ok()?;
+    let raw = args.next_u32().ok()?;
+    let bits = u8::try_from(args.next_u32().ok()?).ok()?;
+    let mut line = String::new();
+    line.push_str("DATA").ok()?;
+    write_reading(&mut line, input, raw, bits).ok()?;
+    Some(line)
+}
+
+// core1で受け取ったADCサンプルを変換してcore0へ送り返す
+pub fn forward_stream_sample(msg: &str) {
+    if let Some(line) = format_stream_sample(msg) {
+        interrupt::free(|cs| {
+            SHARED_MESSAGE_CORE1_TO_CORE0.borrow(cs).write(line);
+        });
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn parse(line: &str) -> Result<Sampling, CommandError> {
+        Sampling::parse(&mut Args::new(line))
+    }
+
+    #[test]
+    fn sample_count_limits() {
+        assert_eq!(parse("avg 4 os 4").unwrap().total_samples(), Some(1024));
+        assert_eq!(parse("avg 5 os 4"), Err(CommandError::OutOfRange));
+        // シフトで0に巻き戻る組み合わせも弾く
+        assert_eq!(parse("avg 16777216 os 4"), Err(CommandError::OutOfRange));
+        assert_eq!(parse("avg 4294967295"), Err(CommandError::OutOfRange));
+        assert_eq!(parse("avg 0"), Err(CommandError::OutOfRange));
+        assert_eq!(parse("os 5"), Err(CommandError::OutOfRange));
+    }
+
+    #[test]
+    fn oversampling_adds_resolution() {
+        let sampling = parse("avg 4 os 4").unwrap();
+        assert_eq!(sampling.resolution_bits(), 16);
+        let mut reads = 0;
+        let raw = sampling.acquire(|| {
+            reads += 1;
+            4095
+        });
+        assert_eq!(reads, 1024);
+        assert_eq!(raw, 4095 << 4);
+    }
+
+    #[test]
+    fn formats_raw_stream_samples() {
+        assert_eq!(
+            format_stream_sample("1 4095 12").unwrap().as_str(),
+            "DATA adc ch=1 raw=4095 bits=12 mv=3300"
+        );
+        // サンプルはメッセージの種類で見分けるので、本文にタグは付かない
+        assert_eq!(format_stream_sample("@adc 1 4095 12"), None);
+        assert_eq!(format_stream_sample("1 4095"), None);
+    }
+}
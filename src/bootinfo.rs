@@ -0,0 +1,160 @@
+// 起動理由と起動回数
+// 起動回数は電源を切っても残るようにKVストアの `boot_count` (u32) に保存する
+// `info` コマンドで読めるほか、USBのシリアルが開かれたときに一度だけ BOOT 行を送る
+use crate::command::{ok_reply, Args, CommandError, Reply};
+use crate::crashlog;
+use crate::globals::{BOOT_INFO, KV, MAX_MESSAGE_SIZE};
+use crate::kvstore::Value;
+use crate::supervisor::{self, HangCause};
+use crate::timer;
+use core::fmt::Write;
+use core::sync::atomic::{AtomicBool, Ordering};
+use cortex_m::interrupt;
+use defmt::{info, warn};
+use heapless::String;
+use rp_pico::hal::pac;
+
+pub const BOOT_COUNT_KEY: &str = "boot_count";
+pub const FIRMWARE_NAME: &str = env!("CARGO_PKG_NAME");
+pub const FIRMWARE_VERSION: &str = env!("CARGO_PKG_VERSION");
+
+static BANNER_PENDING: AtomicBool = AtomicBool::new(false);
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ResetReason {
+    PowerOn,
+    RunPin,
+    // デバッガからのリセット (PSMの再起動)
+    Debugger,
+    Watchdog,
+    // パニックかHardFaultの後の再起動
+    Crash,
+    // `reboot` による再起動
+    Reboot,
+    Unknown,
+}
+
+impl ResetReason {
+    pub fn name(&self) -> &'static str {
+        match self {
+            ResetReason::PowerOn => "power_on",
+            ResetReason::RunPin => "run_pin",
+            ResetReason::Debugger => "debugger",
+            ResetReason::Watchdog => "watchdog",
+            ResetReason::Crash => "crash",
+            ResetReason::Reboot => "reboot",
+            ResetReason::Unknown => "unknown",
+        }
+    }
+}
+
+// supervisor::take_last_hangとcrashlog::take_recordの後に呼ぶ
+pub fn read_reset_reason() -> ResetReason {
+    if crashlog::has_last_crash() {
+        return ResetReason::Crash;
+    }
+    let (watchdog, chip) = unsafe {
+        (
+            (*pac::WATCHDOG::ptr()).reason().read(),
+            (*pac::VREG_AND_CHIP_RESET::ptr()).chip_reset().read(),
+        )
+    };
+    if watchdog.timer().bit_is_set() || watchdog.force().bit_is_set() {
+        if supervisor::last_reset_requested() {
+            ResetReason::Reboot
+        } else {
+            ResetReason::Watchdog
+        }
+    } else if chip.had_psm_restart().bit_is_set() {
+        ResetReason::Debugger
+    } else if chip.had_run().bit_is_set() {
+        ResetReason::RunPin
+    } else if chip.had_por().bit_is_set() {
+        ResetReason::PowerOn
+    } else {
+        ResetReason::Unknown
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct BootInfo {
+    pub reason: ResetReason,
+    pub hang: HangCause,
+    // KVストアが使えなければNone
+    pub boot_count: Option<u32>,
+}
+
+impl BootInfo {
+    pub fn write_summary(&self, out: &mut impl Write) -> core::fmt::Result {
+        write!(
+            out,
+            " fw={}/{} reset={} hang={} boots=",
+            FIRMWARE_NAME,
+            FIRMWARE_VERSION,
+            self.reason.name(),
+            self.hang.name()
+        )?;
+        match self.boot_count {
+            Some(count) => write!(out, "{}", count),
+            None => out.write_str("unknown"),
+        }
+    }
+}
+
+fn increment_boot_count() -> Option<u32> {
+    interrupt::free(|cs| {
+        let mut kv = KV.borrow(cs).borrow_mut();
+        let kv = kv.as_mut()?;
+        let count = kv
+            .get_u32(BOOT_COUNT_KEY)
+            .ok()
+            .flatten()
+            .unwrap_or(0)
+            .wrapping_add(1);
+        if kv.set(BOOT_COUNT_KEY, Value::U32(count)).is_err() {
+            warn!("boot count not saved");
+        }
+        Some(count)
+    })
+}
+
+// 起動直後、KVストアをマウントしてからcore1を起動する前に呼ぶ
+pub fn record_boot() -> BootInfo {
+    let boot = BootInfo {
+        reason: read_reset_reason(),
+        hang: supervisor::last_hang(),
+        boot_count: increment_boot_count(),
+    };
+    info!("reset reason: {}", boot.reason.name());
+    interrupt::free(|cs| {
+        BOOT_INFO.borrow(cs).replace(Some(boot));
+    });
+    BANNER_PENDING.store(true, Ordering::Relaxed);
+    boot
+}
+
+// ホストがシリアルを開いたら一度だけ送る行
+pub fn take_banner() -> Option<String<MAX_MESSAGE_SIZE>> {
+    if !BANNER_PENDING.swap(false, Ordering::Relaxed) {
+        return None;
+    }
+    let boot = interrupt::free(|cs| *BOOT_INFO.borrow(cs).borrow())?;
+    let mut line = String::new();
+    line.push_str("BOOT").ok()?;
+    boot.write_summary(&mut line).ok()?;
+    Some(line)
+}
+
+pub fn handle_command(args: &mut Args) -> Result<Reply, CommandError> {
+    if args.next_opt().is_some() {
+        return Err(CommandError::InvalidArgument);
+    }
+    let boot =
+        interrupt::free(|cs| *BOOT_INFO.borrow(cs).borrow()).ok_or(CommandError::NotReady)?;
+    let uptime = timer::uptime_us() / 1000;
+    Ok(ok_reply(|r| {
+        r.write_str(" info")?;
+        boot.write_summary(r)?;
+        write!(r, " uptime_ms={}", uptime)
+    }))
+}
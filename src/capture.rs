@@ -0,0 +1,866 @@
+// ADC FIFO + DMAによる連続キャプチャ
+// DMAチャンネル2本を互いにチェーンさせ、リングバッファのブロックを交互に埋める
+// 埋まったブロックはcore1が統計(min/max/mean/RMS)を計算してホストへ返す
+use crate::adc::{AdcInput, AdcState, NUM_ADC_PINS, TEMP_SENSOR_CHANNEL};
+use crate::command::{ok_reply, Args, CommandError, Reply};
+use crate::crc::crc16;
+use crate::globals::{ADC, CAPTURE, MAX_MESSAGE_SIZE};
+use crate::pinpool::PoolPin;
+use crate::sharedmessage::SHARED_MESSAGE_CORE1_TO_CORE0;
+use crate::spinlock::{self, CaptureLock};
+use crate::vendor::VendorClass;
+use core::cell::UnsafeCell;
+use core::fmt::Write;
+use cortex_m::interrupt;
+use heapless::{String, Vec};
+use rp_pico::hal::adc::AdcPin;
+use rp_pico::hal::pac;
+use usb_device::bus::UsbBus;
+use usbd_serial::SerialPort;
+
+pub const BLOCK_SAMPLES: usize = 2048;
+pub const NUM_BLOCKS: usize = 4;
+pub const ADC_CLOCK_HZ: u32 = 48_000_000;
+pub const MAX_SAMPLE_RATE: u32 = 500_000;
+// 1変換に96サイクルかかるので、それより短い周期は指定できない
+const MIN_CONVERSION_CYCLES: u64 = 96;
+const MAX_CHANNELS: usize = NUM_ADC_PINS as usize + 1;
+
+const DMA_CHANNELS: [usize; 2] = [0, 1];
+const DMA_TREQ_ADC: u32 = 36;
+
+// ダンプの2進フレーム
+// magic(2) kind(1) channels(1) seq(4) count(2) rate_hz(4) samples(count*2) crc16(2) 全てリトルエンディアン
+pub const FRAME_MAGIC: [u8; 2] = [0xA5, 0x5A];
+pub const FRAME_KIND_ADC_BLOCK: u8 = 0x01;
+pub const FRAME_HEADER_LEN: usize = 14;
+pub const FRAME_LEN: usize = FRAME_HEADER_LEN + BLOCK_SAMPLES * 2 + 2;
+
+// ADCクロックの分周設定 1サンプル = (1 + int + frac/256) クロック
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct ClockDivider {
+    pub int: u16,
+    pub frac: u8,
+}
+
+impl ClockDivider {
+    pub fn for_rate(rate_hz: u32) -> Result<Self, CommandError> {
+        if rate_hz == 0 || rate_hz > MAX_SAMPLE_RATE {
+            return Err(CommandError::OutOfRange);
+        }
+        // 1/256クロック単位の周期
+        let period = (ADC_CLOCK_HZ as u64 * 256 + rate_hz as u64 / 2) / rate_hz as u64;
+        if period <= MIN_CONVERSION_CYCLES * 256 {
+            return Ok(Self { int: 0, frac: 0 });
+        }
+        let div = period - 256;
+        if div > 0xFF_FFFF {
+            return Err(CommandError::OutOfRange);
+        }
+        Ok(Self {
+            int: (div >> 8) as u16,
+            frac: div as u8,
+        })
+    }
+
+    pub fn actual_rate_hz(&self) -> u32 {
+        let div = ((self.int as u64) << 8) | self.frac as u64;
+        let period = if div == 0 {
+            MIN_CONVERSION_CYCLES * 256
+        } else {
+            (div + 256).max(MIN_CONVERSION_CYCLES * 256)
+        };
+        (ADC_CLOCK_HZ as u64 * 256 / period) as u32
+    }
+}
+
+// キャプチャ対象のチャンネル (bit0〜3がADC0〜3、bit4が温度センサー)
+// ラウンドロビンは常に番号の小さい順に回る
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub struct ChannelSet(pub u8);
+
+impl ChannelSet {
+    // `0,1,temp` のようなカンマ区切り
+    pub fn parse(s: &str) -> Result<Self, CommandError> {
+        let mut mask = 0u8;
+        for token in s.split(',') {
+            mask |= 1 << AdcInput::parse(token)?.channel();
+        }
+        Ok(Self(mask))
+    }
+
+    pub fn len(&self) -> usize {
+        self.0.count_ones() as usize
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.0 == 0
+    }
+
+    pub fn inputs(&self) -> impl Iterator<Item = AdcInput> + '_ {
+        (0..=TEMP_SENSOR_CHANNEL)
+            .filter(|ch| self.0 & (1 << ch) != 0)
+            .map(|ch| {
+                if ch == TEMP_SENSOR_CHANNEL {
+                    AdcInput::Temp
+                } else {
+                    AdcInput::Pin(ch)
+                }
+            })
+    }
+
+    pub fn first_channel(&self) -> u8 {
+        self.0.trailing_zeros() as u8
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct ChannelStats {
+    pub input: AdcInput,
+    pub count: u32,
+    pub min: u16,
+    pub max: u16,
+    pub mean: u16,
+    pub rms: u16,
+}
+
+// phaseはブロック先頭のサンプルがラウンドロビンの何番目のチャンネルか
+pub fn block_stats(
+    samples: &[u16],
+    channels: ChannelSet,
+    phase: usize,
+) -> Vec<ChannelStats, MAX_CHANNELS> {
+    let n = channels.len();
+    let mut sums = [0u64; MAX_CHANNELS];
+    let mut squares = [0u64; MAX_CHANNELS];
+    let mut mins = [u16::MAX; MAX_CHANNELS];
+    let mut maxs = [0u16; MAX_CHANNELS];
+    let mut counts = [0u32; MAX_CHANNELS];
+    if n == 0 {
+        return Vec::new();
+    }
+    for (i, &sample) in samples.iter().enumerate() {
+        let slot = (phase + i) % n;
+        sums[slot] += sample as u64;
+        squares[slot] += sample as u64 * sample as u64;
+        mins[slot] = mins[slot].min(sample);
+        maxs[slot] = maxs[slot].max(sample);
+        counts[slot] += 1;
+    }
+    channels
+        .inputs()
+        .enumerate()
+        .filter(|&(slot, _)| counts[slot] > 0)
+        .map(|(slot, input)| {
+            let count = counts[slot] as u64;
+            ChannelStats {
+                input,
+                count: counts[slot],
+                min: mins[slot],
+                max: maxs[slot],
+                mean: (sums[slot] / count) as u16,
+                rms: isqrt(squares[slot] / count) as u16,
+            }
+        })
+        .collect()
+}
+
+pub fn isqrt(value: u64) -> u64 {
+    if value < 2 {
+        return value;
+    }
+    let mut x = value;
+    let mut y = x.div_ceil(2);
+    while y < x {
+        x = y;
+        y = (x + value / x) / 2;
+    }
+    x
+}
+
+// フレームを書き出してその長さを返す
+pub fn encode_frame(
+    out: &mut [u8],
+    seq: u32,
+    channels: ChannelSet,
+    rate_hz: u32,
+    samples: &[u16],
+) -> usize {
+    let len = FRAME_HEADER_LEN + samples.len() * 2;
+    out[0..2].copy_from_slice(&FRAME_MAGIC);
+    out[2] = FRAME_KIND_ADC_BLOCK;
+    out[3] = channels.0;
+    out[4..8].copy_from_slice(&seq.to_le_bytes());
+    out[8..10].copy_from_slice(&(samples.len() as u16).to_le_bytes());
+    out[10..14].copy_from_slice(&rate_hz.to_le_bytes());
+    for (chunk, sample) in out[FRAME_HEADER_LEN..len]
+        .as_chunks_mut::<2>()
+        .0
+        .iter_mut()
+        .zip(samples)
+    {
+        chunk.copy_from_slice(&sample.to_le_bytes());
+    }
+    let crc = crc16(&out[..len]);
+    out[len..len + 2].copy_from_slice(&crc.to_le_bytes());
+    len + 2
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum CaptureCommand {
+    Start {
+        channels: ChannelSet,
+        divider: ClockDivider,
+        report_every: u32,
+    },
+    Stop,
+    Status,
+    Dump {
+        target: DumpTarget,
+    },
+}
+
+// ダンプフレームを送る先
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum DumpTarget {
+    Serial,
+    Bulk,
+}
+
+impl CaptureCommand {
+    pub fn parse(args: &mut Args) -> Result<Self, CommandError> {
+        match args.next_str()? {
+            "start" => {
+                let channels = ChannelSet::parse(args.next_str()?)?;
+                let divider = ClockDivider::for_rate(args.next_u32()?)?;
+                let mut report_every = 1;
+                while let Some(key) = args.next_opt() {
+                    match key {
+                        "report" => report_every = args.next_u32()?,
+                        _ => return Err(CommandError::InvalidArgument),
+                    }
+                }
+                if report_every == 0 {
+                    return Err(CommandError::OutOfRange);
+                }
+                Ok(CaptureCommand::Start {
+                    channels,
+                    divider,
+                    report_every,
+                })
+            }
+            "stop" => Ok(CaptureCommand::Stop),
+            "status" => Ok(CaptureCommand::Status),
+            "dump" => {
+                let target = match args.next_opt() {
+                    None => DumpTarget::Serial,
+                    Some("bulk") => DumpTarget::Bulk,
+                    Some(_) => return Err(CommandError::InvalidArgument),
+                };
+                Ok(CaptureCommand::Dump { target })
+            }
+            _ => Err(CommandError::InvalidArgument),
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum BlockState {
+    Free,
+    Filling,
+    Ready,
+    Processing,
+    Done,
+}
+
+#[derive(Debug, Clone, Copy)]
+struct BlockMeta {
+    state: BlockState,
+    seq: u32,
+}
+
+struct RingMeta {
+    blocks: [BlockMeta; NUM_BLOCKS],
+    channels: ChannelSet,
+    rate_hz: u32,
+    report_every: u32,
+    overruns: u32,
+}
+
+// 両コアから触るリングバッファ
+// サンプル領域はブロックの状態で所有者が決まり、状態の遷移はCaptureLockで守る
+pub struct CaptureRing {
+    samples: UnsafeCell<[[u16; BLOCK_SAMPLES]; NUM_BLOCKS]>,
+    meta: UnsafeCell<RingMeta>,
+}
+
+unsafe impl Sync for CaptureRing {}
+
+pub static CAPTURE_RING: CaptureRing = CaptureRing::new();
+
+impl Default for CaptureRing {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl CaptureRing {
+    pub const fn new() -> Self {
+        Self {
+            samples: UnsafeCell::new([[0; BLOCK_SAMPLES]; NUM_BLOCKS]),
+            meta: UnsafeCell::new(RingMeta {
+                blocks: [BlockMeta {
+                    state: BlockState::Free,
+                    seq: 0,
+                }; NUM_BLOCKS],
+                channels: ChannelSet(0),
+                rate_hz: 0,
+                report_every: 1,
+                overruns: 0,
+            }),
+        }
+    }
+
+    fn with_meta<R>(&self, f: impl FnOnce(&mut RingMeta) -> R) -> R {
+        interrupt::free(|_| {
+            let _guard = spinlock::claim::<CaptureLock>();
+            f(unsafe { &mut *self.meta.get() })
+        })
+    }
+
+    fn block_addr(&self, index: usize) -> u32 {
+        unsafe { (*self.samples.get())[index].as_ptr() as u32 }
+    }
+
+    // 呼び出し側はブロックがDMAに書かれていない状態であることを保証する
+    unsafe fn block(&self, index: usize) -> &[u16; BLOCK_SAMPLES] {
+        &(*self.samples.get())[index]
+    }
+
+    // DMAに渡す次のブロックを選ぶ
+    // 空きか処理済みの最古ブロックを優先し、無ければ未処理の最古ブロックを上書きする
+    fn next_block(meta: &mut RingMeta) -> usize {
+        let oldest = |states: &[BlockState]| {
+            meta.blocks
+                .iter()
+                .enumerate()
+                .filter(|(_, b)| states.contains(&b.state))
+                .min_by_key(|(_, b)| b.seq)
+                .map(|(i, _)| i)
+        };
+        if let Some(i) = oldest(&[BlockState::Free, BlockState::Done]) {
+            return i;
+        }
+        // 処理中と書き込み中以外にReadyが必ず1つ残っている
+        let i = oldest(&[BlockState::Ready]).unwrap_or(0);
+        meta.overruns += 1;
+        i
+    }
+}
+
+struct Running {
+    channels: ChannelSet,
+    divider: ClockDivider,
+    pins: Vec<AdcPin<PoolPin>, { NUM_ADC_PINS as usize }>,
+    // 各DMAチャンネルが今書いているブロック
+    block_of_channel: [usize; 2],
+    next_seq: u32,
+}
+
+struct DumpTx {
+    frame: [u8; FRAME_LEN],
+    len: usize,
+    sent: usize,
+    target: DumpTarget,
+}
+
+pub struct CaptureState {
+    dma: pac::DMA,
+    running: Option<Running>,
+    dump: DumpTx,
+}
+
+fn dma_ctrl(chain_to: usize) -> u32 {
+    const EN: u32 = 1 << 0;
+    const DATA_SIZE_HALFWORD: u32 = 1 << 2;
+    const INCR_WRITE: u32 = 1 << 5;
+    EN | DATA_SIZE_HALFWORD | INCR_WRITE | ((chain_to as u32) << 11) | (DMA_TREQ_ADC << 15)
+}
+
+impl CaptureState {
+    pub fn new(dma: pac::DMA) -> Self {
+        Self {
+            dma,
+            running: None,
+            dump: DumpTx {
+                frame: [0; FRAME_LEN],
+                len: 0,
+                sent: 0,
+                target: DumpTarget::Serial,
+            },
+        }
+    }
+
+    pub fn execute(
+        &mut self,
+        adc: &mut AdcState,
+        cmd: CaptureCommand,
+    ) -> Result<Reply, CommandError> {
+        match cmd {
+            CaptureCommand::Start {
+                channels,
+                divider,
+                report_every,
+            } => {
+                if self.running.is_some() {
+                    return Err(CommandError::Busy);
+                }
+                self.start(adc, channels, divider, report_every)?;
+                Ok(ok_reply(|r| {
+                    write!(
+                        r,
+                        " cap start mask={:#04x} rate_hz={}",
+                        channels.0,
+                        divider.actual_rate_hz()
+                    )
+                }))
+            }
+            CaptureCommand::Stop => {
+                self.stop(adc);
+                Ok(ok_reply(|r| r.write_str(" cap stop")))
+            }
+            CaptureCommand::Status => {
+                let overruns = CAPTURE_RING.with_meta(|meta| meta.overruns);
+                let running = self.running.as_ref();
+                Ok(ok_reply(|r| {
+                    write!(
+                        r,
+                        " cap running={} mask={:#04x} rate_hz={} blocks={} overruns={}",
+                        running.is_some() as u8,
+                        running.map(|run| run.channels.0).unwrap_or(0),
+                        running.map(|run| run.divider.actual_rate_hz()).unwrap_or(0),
+                        running.map(|run| run.next_seq).unwrap_or(0),
+                        overruns
+                    )
+                }))
+            }
+            CaptureCommand::Dump { target } => {
+                if self.dump.sent < self.dump.len {
+                    return Err(CommandError::Busy);
+                }
+                let (seq, len) = self.prepare_dump().ok_or(CommandError::NotReady)?;
+                self.dump.target = target;
+                Ok(ok_reply(|r| {
+                    write!(
+                        r,
+                        " cap dump seq={} bytes={} to={}",
+                        seq,
+                        len,
+                        match target {
+                            DumpTarget::Serial => "serial",
+                            DumpTarget::Bulk => "bulk",
+                        }
+                    )
+                }))
+            }
+        }
+    }
+
+    fn start(
+        &mut self,
+        adc_state: &mut AdcState,
+        channels: ChannelSet,
+        divider: ClockDivider,
+        report_every: u32,
+    ) -> Result<(), CommandError> {
+        let mut pins = Vec::new();
+        for input in channels.inputs() {
+            match AdcState::claim_pin(input) {
+                Ok(Some(pin)) => {
+                    let _ = pins.push(pin);
+                }
+                Ok(None) => adc_state.enable_temp_sensor(),
+                Err(err) => {
+                    pins.into_iter()
+                        .for_each(|pin| AdcState::release_pin(Some(pin)));
+                    return Err(err);
+                }
+            }
+        }
+        let adc = match adc_state.begin_capture() {
+            Ok(adc) => adc,
+            Err(err) => {
+                pins.into_iter()
+                    .for_each(|pin| AdcState::release_pin(Some(pin)));
+                return Err(err);
+            }
+        };
+
+        let rate_hz = divider.actual_rate_hz();
+        CAPTURE_RING.with_meta(|meta| {
+            for (i, block) in meta.blocks.iter_mut().enumerate() {
+                block.state = if i < DMA_CHANNELS.len() {
+                    BlockState::Filling
+                } else {
+                    BlockState::Free
+                };
+                block.seq = i as u32;
+            }
+            meta.channels = channels;
+            meta.rate_hz = rate_hz;
+            meta.report_every = report_every;
+            meta.overruns = 0;
+        });
+
+        // ADCのFIFOアドレスは固定なのでFIFOを作る前にDMAを設定しておける
+        let fifo_addr = unsafe { (*pac::ADC::ptr()).fifo().as_ptr() as u32 };
+        for (i, &ch) in DMA_CHANNELS.iter().enumerate() {
+            let other = DMA_CHANNELS[1 - i];
+            let regs = self.dma.ch(ch);
+            regs.ch_read_addr().write(|w| unsafe { w.bits(fifo_addr) });
+            regs.ch_write_addr()
+                .write(|w| unsafe { w.bits(CAPTURE_RING.block_addr(i)) });
+            regs.ch_trans_count()
+                .write(|w| unsafe { w.bits(BLOCK_SAMPLES as u32) });
+            // AL1_CTRLはトリガーしない別名レジスタ
+            regs.ch_al1_ctrl()
+                .write(|w| unsafe { w.bits(dma_ctrl(other)) });
+        }
+        let channel_bits = DMA_CHANNELS.iter().fold(0u32, |acc, &ch| acc | 1 << ch);
+        self.dma.ints0().write(|w| unsafe { w.bits(channel_bits) });
+        self.dma
+            .inte0()
+            .modify(|r, w| unsafe { w.bits(r.bits() | channel_bits) });
+        // 最初のチャンネルだけ起動し、ADCのDREQが来るのを待たせる
+        self.dma
+            .multi_chan_trigger()
+            .write(|w| unsafe { w.bits(1 << DMA_CHANNELS[0]) });
+        unsafe { pac::NVIC::unmask(pac::Interrupt::DMA_IRQ_0) };
+
+        let mut fifo = adc
+            .build_fifo()
+            .clock_divider(divider.int, divider.frac)
+            .enable_dma()
+            .start_paused();
+        // HALのround_robinは型付きのチャンネルしか受け取らないのでレジスタを直接設定する
+        unsafe {
+            (*pac::ADC::ptr()).cs().modify(|_, w| {
+                w.ainsel()
+                    .bits(channels.first_channel())
+                    .rrobin()
+                    .bits(if channels.len() > 1 { channels.0 } else { 0 })
+            });
+        }
+        fifo.resume();
+
+        self.running = Some(Running {
+            channels,
+            divider,
+            pins,
+            block_of_channel: [0, 1],
+            next_seq: DMA_CHANNELS.len() as u32,
+        });
+        Ok(())
+    }
+
+    fn stop(&mut self, adc_state: &mut AdcState) {
+        let Some(running) = self.running.take() else {
+            return;
+        };
+        // FIFOとDREQを止めてから転送途中のDMAを中断する
+        adc_state.end_capture().build_fifo().start_paused().stop();
+        let channel_bits = DMA_CHANNELS.iter().fold(0u32, |acc, &ch| acc | 1 << ch);
+        self.dma
+            .inte0()
+            .modify(|r, w| unsafe { w.bits(r.bits() & !channel_bits) });
+        self.dma
+            .chan_abort()
+            .write(|w| unsafe { w.bits(channel_bits) });
+        while self.dma.chan_abort().read().bits() & channel_bits != 0 {}
+        self.dma.ints0().write(|w| unsafe { w.bits(channel_bits) });
+        running
+            .pins
+            .into_iter()
+            .for_each(|pin| AdcState::release_pin(Some(pin)));
+    }
+
+    // DMA_IRQ_0から呼ぶ
+    fn on_dma_irq(&mut self) {
+        let Some(running) = self.running.as_mut() else {
+            return;
+        };
+        let status = self.dma.ints0().read().bits();
+        for (i, &ch) in DMA_CHANNELS.iter().enumerate() {
+            if status & (1 << ch) == 0 {
+                continue;
+            }
+            self.dma.ints0().write(|w| unsafe { w.bits(1 << ch) });
+            let seq = running.next_seq;
+            let finished = running.block_of_channel[i];
+            let next = CAPTURE_RING.with_meta(|meta| {
+                meta.blocks[finished].state = BlockState::Ready;
+                let next = CaptureRing::next_block(meta);
+                meta.blocks[next] = BlockMeta {
+                    state: BlockState::Filling,
+                    seq,
+                };
+                next
+            });
+            running.next_seq += 1;
+            running.block_of_channel[i] = next;
+            // 相手チャンネルが書いている間に書き込み先だけ差し替える (転送数は再トリガーで再ロードされる)
+            self.dma
+                .ch(ch)
+                .ch_write_addr()
+                .write(|w| unsafe { w.bits(CAPTURE_RING.block_addr(next)) });
+        }
+    }
+
+    // 最新の完了ブロックをフレームに詰める
+    fn prepare_dump(&mut self) -> Option<(u32, usize)> {
+        let dump = &mut self.dump;
+        CAPTURE_RING.with_meta(|meta| {
+            let (index, block) = meta
+                .blocks
+                .iter()
+                .enumerate()
+                .filter(|(_, b)| matches!(b.state, BlockState::Ready | BlockState::Done))
+                .max_by_key(|(_, b)| b.seq)?;
+            // ロック中はDMAがこのブロックを割り当てられないので安全に読める
+            let samples = unsafe { CAPTURE_RING.block(index) };
+            dump.len = encode_frame(
+                &mut dump.frame,
+                block.seq,
+                meta.channels,
+                meta.rate_hz,
+                samples,
+            );
+            dump.sent = 0;
+            Some((block.seq, dump.len))
+        })
+    }
+
+    fn pump_dump<B: UsbBus>(
+        &mut self,
+        serial: &mut SerialPort<'_, B>,
+        vendor: &mut VendorClass<'_, B>,
+    ) {
+        let dump = &mut self.dump;
+        while dump.sent < dump.len {
+            let rest = &dump.frame[dump.sent..dump.len];
+            let written = match dump.target {
+                DumpTarget::Serial => serial.write(rest),
+                DumpTarget::Bulk => vendor.write_packet(rest),
+            };
+            match written {
+                Ok(n) if n > 0 => dump.sent += n,
+                _ => break,
+            }
+        }
+    }
+}
+
+pub fn handle_command(args: &mut Args) -> Result<Reply, CommandError> {
+    let cmd = CaptureCommand::parse(args)?;
+    interrupt::free(|cs| {
+        let mut adc = ADC.borrow(cs).borrow_mut();
+        let mut capture = CAPTURE.borrow(cs).borrow_mut();
+        match (adc.as_mut(), capture.as_mut()) {
+            (Some(adc), Some(capture)) => capture.execute(adc, cmd),
+            _ => Err(CommandError::NotReady),
+        }
+    })
+}
+
+pub fn handle_dma_irq_0() {
+    interrupt::free(|cs| {
+        if let Some(capture) = CAPTURE.borrow(cs).borrow_mut().as_mut() {
+            capture.on_dma_irq();
+        }
+    });
+}
+
+// USBポーリングのついでにダンプの残りを送る
+pub fn pump_dump<B: UsbBus>(serial: &mut SerialPort<'_, B>, vendor: &mut VendorClass<'_, B>) {
+    interrupt::free(|cs| {
+        if let Some(capture) = CAPTURE.borrow(cs).borrow_mut().as_mut() {
+            capture.pump_dump(serial, vendor);
+        }
+    });
+}
+
+// core1側: 埋まったブロックの統計を計算してcore0へ返す
+pub fn process_ready_blocks() {
+    loop {
+        let claimed = CAPTURE_RING.with_meta(|meta| {
+            let (index, block) = meta
+                .blocks
+                .iter_mut()
+                .enumerate()
+                .filter(|(_, b)| b.state == BlockState::Ready)
+                .min_by_key(|(_, b)| b.seq)?;
+            block.state = BlockState::Processing;
+            Some((index, block.seq, meta.channels, meta.report_every))
+        });
+        let Some((index, seq, channels, report_every)) = claimed else {
+            return;
+        };
+        // Processing中のブロックはDMAに割り当てられない
+        let samples = unsafe { CAPTURE_RING.block(index) };
+        let n = channels.len().max(1);
+        let phase = ((seq as usize % n) * (BLOCK_SAMPLES % n)) % n;
+        let stats = block_stats(samples, channels, phase);
+        CAPTURE_RING.with_meta(|meta| meta.blocks[index].state = BlockState::Done);
+
+        if seq % report_every == 0 {
+            let line = format_stats(seq, &stats);
+            interrupt::free(|cs| {
+                SHARED_MESSAGE_CORE1_TO_CORE0.borrow(cs).write(line);
+            });
+        }
+    }
+}
+
+pub fn format_stats(seq: u32, stats: &[ChannelStats]) -> String<MAX_MESSAGE_SIZE> {
+    let mut line = String::new();
+    let _ = write!(line, "DATA cap seq={}", seq);
+    for s in stats {
+        let _ = write!(
+            line,
+            " ch={}:{},{},{},{}",
+            s.input, s.min, s.max, s.mean, s.rms
+        );
+    }
+    line
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn divider_rounds_to_nearest_period() {
+        let divider = ClockDivider::for_rate(1_000).unwrap();
+        assert_eq!(
+            divider,
+            ClockDivider {
+                int: 47999,
+                frac: 0
+            }
+        );
+        assert_eq!(divider.actual_rate_hz(), 1_000);
+
+        // 48MHz*256/44100 = 278639.45 -> 278639
+        let divider = ClockDivider::for_rate(44_100).unwrap();
+        assert_eq!(
+            divider,
+            ClockDivider {
+                int: 1087,
+                frac: 111
+            }
+        );
+        assert_eq!(divider.actual_rate_hz(), 44_100);
+
+        let divider = ClockDivider::for_rate(499_000).unwrap();
+        assert_eq!(divider, ClockDivider { int: 95, frac: 49 });
+        assert_eq!(divider.actual_rate_hz(), 499_005);
+    }
+
+    #[test]
+    fn divider_limits() {
+        // 変換時間より短い周期は分周なし (96サイクル) になる
+        let divider = ClockDivider::for_rate(MAX_SAMPLE_RATE).unwrap();
+        assert_eq!(divider, ClockDivider { int: 0, frac: 0 });
+        assert_eq!(divider.actual_rate_hz(), MAX_SAMPLE_RATE);
+        assert_eq!(
+            ClockDivider { int: 10, frac: 0 }.actual_rate_hz(),
+            MAX_SAMPLE_RATE
+        );
+        // 分周比は24bitまで
+        let divider = ClockDivider::for_rate(733).unwrap();
+        assert_eq!(
+            divider,
+            ClockDivider {
+                int: 65483,
+                frac: 80
+            }
+        );
+        assert_eq!(divider.actual_rate_hz(), 732);
+        assert_eq!(ClockDivider::for_rate(732), Err(CommandError::OutOfRange));
+        assert_eq!(ClockDivider::for_rate(0), Err(CommandError::OutOfRange));
+        assert_eq!(
+            ClockDivider::for_rate(MAX_SAMPLE_RATE + 1),
+            Err(CommandError::OutOfRange)
+        );
+    }
+
+    #[test]
+    fn stats_follow_round_robin_phase() {
+        // 先頭はADC1から始まる
+        let stats = block_stats(&[10, 20, 30, 40], ChannelSet(0b11), 1);
+        assert_eq!(
+            stats.as_slice(),
+            &[
+                ChannelStats {
+                    input: AdcInput::Pin(0),
+                    count: 2,
+                    min: 20,
+                    max: 40,
+                    mean: 30,
+                    // sqrt((400 + 1600) / 2) = 31.6
+                    rms: 31,
+                },
+                ChannelStats {
+                    input: AdcInput::Pin(1),
+                    count: 2,
+                    min: 10,
+                    max: 30,
+                    mean: 20,
+                    rms: 22,
+                },
+            ]
+        );
+        assert!(block_stats(&[1, 2, 3], ChannelSet(0), 0).is_empty());
+    }
+
+    #[test]
+    fn stats_skip_channels_without_samples() {
+        let stats = block_stats(&[4095], ChannelSet(0b1_0001), 1);
+        assert_eq!(stats.len(), 1);
+        assert_eq!(stats[0].input, AdcInput::Temp);
+        assert_eq!(
+            (stats[0].min, stats[0].max, stats[0].rms),
+            (4095, 4095, 4095)
+        );
+    }
+
+    #[test]
+    fn integer_square_root() {
+        let cases = [(0, 0), (1, 1), (2, 1), (3, 1), (4, 2), (15, 3), (16, 4)];
+        for (value, root) in cases {
+            assert_eq!(isqrt(value), root, "{value}");
+        }
+        assert_eq!(isqrt(4095 * 4095), 4095);
+        assert_eq!(isqrt(u64::MAX), u32::MAX as u64);
+    }
+
+    #[test]
+    fn frame_layout_and_crc() {
+        let mut out = [0u8; 32];
+        let len = encode_frame(
+            &mut out,
+            0x0102_0304,
+            ChannelSet(0b1_0001),
+            500_000,
+            &[0x0123, 0x0FFF],
+        );
+        assert_eq!(len, FRAME_HEADER_LEN + 4 + 2);
+        assert_eq!(
+            &out[..FRAME_HEADER_LEN],
+            &[0xA5, 0x5A, 0x01, 0x11, 0x04, 0x03, 0x02, 0x01, 0x02, 0x00, 0x20, 0xA1, 0x07, 0x00]
+        );
+        assert_eq!(&out[FRAME_HEADER_LEN..len - 2], &[0x23, 0x01, 0xFF, 0x0F]);
+        assert_eq!(&out[len - 2..len], &0x4AA2u16.to_le_bytes());
+        assert_eq!(crc16(&out[..len - 2]), 0x4AA2);
+    }
+}
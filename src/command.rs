@@ -0,0 +1,202 @@
+// USBから受信した `*<コマンド> <引数...>` を解釈して各機能に振り分ける
+// パース部分はハードウェアに依存しないのでホスト上でも動かせる
+use crate::adc;
+use crate::bootinfo;
+use crate::capture;
+use crate::config;
+use crate::crashlog;
+use crate::freqcounter;
+use crate::globals::MAX_MESSAGE_SIZE;
+use crate::heap;
+use crate::i2c;
+use crate::kv;
+use crate::memlayout;
+use crate::msgpool;
+use crate::pwm;
+use crate::reboot;
+use crate::spi;
+use crate::spinlock;
+use crate::stack;
+use crate::uartbridge;
+use crate::vendor;
+use core::fmt::Write;
+use core::str::SplitWhitespace;
+use heapless::{String, Vec};
+
+pub type Reply = String<MAX_MESSAGE_SIZE>;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum CommandError {
+    MissingArgument,
+    InvalidArgument,
+    OutOfRange,
+    PinBusy,
+    NotReady,
+    Busy,
+    NackAddress,
+    NackData,
+    Nack,
+    ArbitrationLoss,
+    BusError,
+    Storage,
+}
+
+impl CommandError {
+    pub fn code(&self) -> &'static str {
+        match self {
+            CommandError::MissingArgument => "E_MISSING_ARG",
+            CommandError::InvalidArgument => "E_INVALID_ARG",
+            CommandError::OutOfRange => "E_RANGE",
+            CommandError::PinBusy => "E_PIN_BUSY",
+            CommandError::NotReady => "E_NOT_READY",
+            CommandError::Busy => "E_BUSY",
+            CommandError::NackAddress => "E_NACK_ADDR",
+            CommandError::NackData => "E_NACK_DATA",
+            CommandError::Nack => "E_NACK",
+            CommandError::ArbitrationLoss => "E_ARB_LOST",
+            CommandError::BusError => "E_BUS",
+            CommandError::Storage => "E_STORAGE",
+        }
+    }
+}
+
+// 引数トークンの読み出しヘルパー
+pub struct Args<'a> {
+    tokens: SplitWhitespace<'a>,
+}
+
+impl<'a> Args<'a> {
+    pub fn new(s: &'a str) -> Self {
+        Self {
+            tokens: s.split_whitespace(),
+        }
+    }
+
+    pub fn next_str(&mut self) -> Result<&'a str, CommandError> {
+        self.tokens.next().ok_or(CommandError::MissingArgument)
+    }
+
+    pub fn next_opt(&mut self) -> Option<&'a str> {
+        self.tokens.next()
+    }
+
+    pub fn next_u32(&mut self) -> Result<u32, CommandError> {
+        parse_u32(self.next_str()?)
+    }
+
+    pub fn next_milli(&mut self) -> Result<u32, CommandError> {
+        parse_milli(self.next_str()?)
+    }
+}
+
+// 10進数と0x付き16進数を受け付ける
+pub fn parse_u32(s: &str) -> Result<u32, CommandError> {
+    let parsed = if let Some(hex) = s.strip_prefix("0x").or_else(|| s.strip_prefix("0X")) {
+        u32::from_str_radix(hex, 16)
+    } else {
+        s.parse::<u32>()
+    };
+    parsed.map_err(|_| CommandError::InvalidArgument)
+}
+
+// `12.5` のような小数を1000倍の整数にする (小数点以下3桁まで)
+pub fn parse_milli(s: &str) -> Result<u32, CommandError> {
+    let (int, frac) = s.split_once('.').unwrap_or((s, ""));
+    if frac.len() > 3 || !frac.bytes().all(|b| b.is_ascii_digit()) {
+        return Err(CommandError::InvalidArgument);
+    }
+    let int = int
+        .parse::<u32>()
+        .map_err(|_| CommandError::InvalidArgument)?;
+    let mut frac_value = 0u32;
+    for i in 0..3 {
+        let digit = frac
+            .as_bytes()
+            .get(i)
+            .map(|b| (b - b'0') as u32)
+            .unwrap_or(0);
+        frac_value = frac_value * 10 + digit;
+    }
+    int.checked_mul(1000)
+        .and_then(|v| v.checked_add(frac_value))
+        .ok_or(CommandError::OutOfRange)
+}
+
+// ミリ単位の値を小数点付きで書き出す
+pub fn write_milli(out: &mut impl Write, value: i32) -> core::fmt::Result {
+    let sign = if value < 0 { "-" } else { "" };
+    let abs = value.unsigned_abs();
+    write!(out, "{}{}.{:03}", sign, abs / 1000, abs % 1000)
+}
+
+// `0a1b2c` のような16進文字列をバイト列にする
+pub fn parse_hex_bytes<const N: usize>(s: &str) -> Result<Vec<u8, N>, CommandError> {
+    if !s.len().is_multiple_of(2) || !s.bytes().all(|b| b.is_ascii_hexdigit()) {
+        return Err(CommandError::InvalidArgument);
+    }
+    let mut bytes = Vec::new();
+    for i in (0..s.len()).step_by(2) {
+        let byte =
+            u8::from_str_radix(&s[i..i + 2], 16).map_err(|_| CommandError::InvalidArgument)?;
+        bytes.push(byte).map_err(|_| CommandError::OutOfRange)?;
+    }
+    Ok(bytes)
+}
+
+pub fn write_hex(out: &mut impl Write, data: &[u8]) -> core::fmt::Result {
+    for byte in data {
+        write!(out, "{:02x}", byte)?;
+    }
+    Ok(())
+}
+
+pub fn ok_reply(f: impl FnOnce(&mut Reply) -> core::fmt::Result) -> Reply {
+    let mut reply = Reply::new();
+    let _ = reply.push_str("OK");
+    let _ = f(&mut reply);
+    reply
+}
+
+pub fn err_reply(err: CommandError) -> Reply {
+    let mut reply = Reply::new();
+    let _ = write!(reply, "ERR {}", err.code());
+    reply
+}
+
+pub fn to_reply(result: Result<Reply, CommandError>) -> Reply {
+    result.unwrap_or_else(err_reply)
+}
+
+// 既知のコマンドであれば応答を返す
+// 知らないコマンドはNoneを返して従来通りcore1へ転送させる
+pub fn dispatch(line: &str) -> Option<Reply> {
+    let line = line.trim();
+    let (name, rest) = line.split_once(' ').unwrap_or((line, ""));
+    let mut args = Args::new(rest);
+    match name {
+        "adc" => Some(to_reply(adc::handle_command(&mut args))),
+        "cap" => Some(to_reply(capture::handle_command(&mut args))),
+        "pwm" => Some(to_reply(pwm::handle_command(&mut args))),
+        "freq" => Some(to_reply(freqcounter::handle_command(&mut args))),
+        "i2c" => Some(to_reply(i2c::handle_command(&mut args))),
+        "spi" => Some(to_reply(spi::handle_command(&mut args))),
+        "uart" => Some(to_reply(uartbridge::handle_command(&mut args))),
+        "bulk" => Some(to_reply(vendor::handle_command(&mut args))),
+        "config" => Some(to_reply(config::handle_command(&mut args))),
+        "kv" => Some(to_reply(kv::handle_command(&mut args))),
+        "info" => Some(to_reply(bootinfo::handle_command(&mut args))),
+        "lastpanic" => Some(to_reply(crashlog::handle_command(&mut args))),
+        "stack" => Some(to_reply(stack::handle_command(&mut args))),
+        "heap" => Some(to_reply(heap::handle_command(&mut args))),
+        "pool" => Some(to_reply(msgpool::handle_command(&mut args))),
+        "mem" => Some(to_reply(memlayout::handle_command(&mut args))),
+        "locks" => Some(to_reply(spinlock::handle_command(&mut args))),
+        "reboot" => Some(to_reply(reboot::handle_command(&mut args))),
+        // 空白もそのまま打ち込むので引数を分割しない
+        #[cfg(feature = "hid")]
+        "type" => Some(to_reply(crate::hid::handle_type(rest))),
+        #[cfg(feature = "midi")]
+        "midi" => Some(to_reply(crate::midi::handle_command(&mut args))),
+        _ => None,
+    }
+}
@@ -0,0 +1,582 @@
+// フラッシュ最終セクタに置く永続設定
+// magic(4) version(1) reserved(1) payload_len(2) payload crc32(4) 全てリトルエンディアン
+// payloadは tag(1) len(1) data の並びで、知らないタグは読み飛ばす
+// 壊れているか未書き込みなら既定値で動く
+//
+// USBのVID/PIDと文字列はビルド時の環境変数 USB_VID / USB_PID / USB_MANUFACTURER / USB_PRODUCT で
+// 既定値を変えられ、実行時は `config` コマンドで設定に保存した値が優先される
+use crate::command::{ok_reply, parse_u32, Args, CommandError, Reply};
+use crate::crc::crc32;
+use crate::flash::{self, FLASH_SIZE, PAGE_SIZE, SECTOR_SIZE, UNIQUE_ID_LEN};
+use crate::globals::CONFIG;
+use core::fmt::Write;
+use cortex_m::interrupt;
+use defmt::warn;
+use heapless::String;
+
+pub const CONFIG_OFFSET: u32 = FLASH_SIZE - SECTOR_SIZE;
+pub const RECORD_LEN: usize = PAGE_SIZE;
+pub const MAX_SERIAL_LEN: usize = 32;
+pub const MAX_NAME_LEN: usize = 48;
+
+pub const DEFAULT_VID: u16 = match option_env!("USB_VID") {
+    Some(s) => parse_id(s),
+    None => 0x16C0,
+};
+pub const DEFAULT_PID: u16 = match option_env!("USB_PID") {
+    Some(s) => parse_id(s),
+    None => 0x27DD,
+};
+pub const DEFAULT_MANUFACTURER: &str = match option_env!("USB_MANUFACTURER") {
+    Some(s) => check_name(s),
+    None => "My Company",
+};
+pub const DEFAULT_PRODUCT: &str = match option_env!("USB_PRODUCT") {
+    Some(s) => check_name(s),
+    None => "RP2040 USB Serial test",
+};
+
+const MAGIC: u32 = 0x4746_4350; // "PCFG"
+const VERSION: u8 = 1;
+const HEADER_LEN: usize = 8;
+const CRC_LEN: usize = 4;
+const MAX_PAYLOAD_LEN: usize = RECORD_LEN - HEADER_LEN - CRC_LEN;
+
+const TAG_SERIAL: u8 = 0x01;
+const TAG_VID: u8 = 0x02;
+const TAG_PID: u8 = 0x03;
+const TAG_MANUFACTURER: u8 = 0x04;
+const TAG_PRODUCT: u8 = 0x05;
+
+// ビルド時の環境変数の検査。不正ならコンパイルエラーになる
+// `0x16C0` のような16進のみ受け付け、0は使えない
+const fn parse_id(s: &str) -> u16 {
+    let bytes = s.as_bytes();
+    if bytes.len() < 3 || bytes.len() > 6 || bytes[0] != b'0' || (bytes[1] | 0x20) != b'x' {
+        panic!("USB_VID/USB_PID must be hex like 0x1234");
+    }
+    let mut value = 0u32;
+    let mut i = 2;
+    while i < bytes.len() {
+        let digit = match bytes[i] {
+            b'0'..=b'9' => bytes[i] - b'0',
+            b'a'..=b'f' => bytes[i] - b'a' + 10,
+            b'A'..=b'F' => bytes[i] - b'A' + 10,
+            _ => panic!("USB_VID/USB_PID must be hex like 0x1234"),
+        };
+        value = value * 16 + digit as u32;
+        i += 1;
+    }
+    if value == 0 {
+        panic!("USB_VID/USB_PID must not be zero");
+    }
+    value as u16
+}
+
+const fn check_name(s: &str) -> &str {
+    let bytes = s.as_bytes();
+    if bytes.is_empty() || bytes.len() > MAX_NAME_LEN {
+        panic!("USB_MANUFACTURER/USB_PRODUCT must be 1 to 48 characters");
+    }
+    let mut i = 0;
+    while i < bytes.len() {
+        if !(bytes[i].is_ascii_graphic() || bytes[i] == b' ') {
+            panic!("USB_MANUFACTURER/USB_PRODUCT must be printable ASCII");
+        }
+        i += 1;
+    }
+    s
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum RecordStatus {
+    Ok,
+    Empty,
+    Corrupt,
+}
+
+impl RecordStatus {
+    pub fn name(&self) -> &'static str {
+        match self {
+            RecordStatus::Ok => "ok",
+            RecordStatus::Empty => "empty",
+            RecordStatus::Corrupt => "corrupt",
+        }
+    }
+}
+
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct Config {
+    // USBシリアル番号の上書き。無ければフラッシュのユニークIDを使う
+    pub serial: Option<String<MAX_SERIAL_LEN>>,
+    // 以下は無ければビルド時の既定値
+    pub vid: Option<u16>,
+    pub pid: Option<u16>,
+    pub manufacturer: Option<String<MAX_NAME_LEN>>,
+    pub product: Option<String<MAX_NAME_LEN>>,
+}
+
+// USBの文字列ディスクリプタに入れるので表示可能なASCIIに限る
+pub fn validate_string<const N: usize>(s: &str) -> Result<String<N>, CommandError> {
+    if s.is_empty() || !s.bytes().all(|b| b.is_ascii_graphic() || b == b' ') {
+        return Err(CommandError::InvalidArgument);
+    }
+    String::try_from(s).map_err(|_| CommandError::OutOfRange)
+}
+
+pub fn validate_id(value: u32) -> Result<u16, CommandError> {
+    match u16::try_from(value) {
+        Ok(0) | Err(_) => Err(CommandError::OutOfRange),
+        Ok(id) => Ok(id),
+    }
+}
+
+fn decode_text<const N: usize>(data: &[u8]) -> Result<String<N>, RecordStatus> {
+    core::str::from_utf8(data)
+        .ok()
+        .and_then(|s| validate_string(s).ok())
+        .ok_or(RecordStatus::Corrupt)
+}
+
+struct Writer {
+    buf: [u8; RECORD_LEN],
+    pos: usize,
+}
+
+impl Writer {
+    fn tlv(&mut self, tag: u8, data: &[u8]) {
+        self.buf[self.pos] = tag;
+        self.buf[self.pos + 1] = data.len() as u8;
+        self.buf[self.pos + 2..self.pos + 2 + data.len()].copy_from_slice(data);
+        self.pos += 2 + data.len();
+    }
+}
+
+impl Config {
+    // 消去後の状態に合わせて未使用部分は0xFFで埋める
+    pub fn encode(&self) -> [u8; RECORD_LEN] {
+        let mut w = Writer {
+            buf: [0xFF; RECORD_LEN],
+            pos: HEADER_LEN,
+        };
+        if let Some(serial) = &self.serial {
+            w.tlv(TAG_SERIAL, serial.as_bytes());
+        }
+        if let Some(vid) = self.vid {
+            w.tlv(TAG_VID, &vid.to_le_bytes());
+        }
+        if let Some(pid) = self.pid {
+            w.tlv(TAG_PID, &pid.to_le_bytes());
+        }
+        if let Some(manufacturer) = &self.manufacturer {
+            w.tlv(TAG_MANUFACTURER, manufacturer.as_bytes());
+        }
+        if let Some(product) = &self.product {
+            w.tlv(TAG_PRODUCT, product.as_bytes());
+        }
+        let payload_len = w.pos - HEADER_LEN;
+        debug_assert!(payload_len <= MAX_PAYLOAD_LEN);
+        w.buf[0..4].copy_from_slice(&MAGIC.to_le_bytes());
+        w.buf[4] = VERSION;
+        w.buf[5] = 0;
+        w.buf[6..8].copy_from_slice(&(payload_len as u16).to_le_bytes());
+        let crc = crc32(&w.buf[..w.pos]);
+        w.buf[w.pos..w.pos + CRC_LEN].copy_from_slice(&crc.to_le_bytes());
+        w.buf
+    }
+
+    pub fn decode(buf: &[u8]) -> Result<Self, RecordStatus> {
+        if buf.len() < HEADER_LEN + CRC_LEN {
+            return Err(RecordStatus::Corrupt);
+        }
+        let magic = u32::from_le_bytes([buf[0], buf[1], buf[2], buf[3]]);
+        if magic == 0xFFFF_FFFF {
+            return Err(RecordStatus::Empty);
+        }
+        if magic != MAGIC || buf[4] != VERSION {
+            return Err(RecordStatus::Corrupt);
+        }
+        let payload_len = u16::from_le_bytes([buf[6], buf[7]]) as usize;
+        let end = HEADER_LEN + payload_len;
+        if payload_len > MAX_PAYLOAD_LEN || end + CRC_LEN > buf.len() {
+            return Err(RecordStatus::Corrupt);
+        }
+        let stored = u32::from_le_bytes([buf[end], buf[end + 1], buf[end + 2], buf[end + 3]]);
+        if crc32(&buf[..end]) != stored {
+            return Err(RecordStatus::Corrupt);
+        }
+
+        let mut config = Config::default();
+        let mut payload = &buf[HEADER_LEN..end];
+        while let [tag, len, rest @ ..] = payload {
+            let len = *len as usize;
+            if len > rest.len() {
+                return Err(RecordStatus::Corrupt);
+            }
+            let (data, next) = rest.split_at(len);
+            // 中身が不正なら全体を壊れたものとして既定値に戻す
+            let id = || match data {
+                [lo, hi] => validate_id(u16::from_le_bytes([*lo, *hi]) as u32)
+                    .map_err(|_| RecordStatus::Corrupt),
+                _ => Err(RecordStatus::Corrupt),
+            };
+            match *tag {
+                TAG_SERIAL => config.serial = Some(decode_text(data)?),
+                TAG_VID => config.vid = Some(id()?),
+                TAG_PID => config.pid = Some(id()?),
+                TAG_MANUFACTURER => config.manufacturer = Some(decode_text(data)?),
+                TAG_PRODUCT => config.product = Some(decode_text(data)?),
+                // 知らないタグは新しいファームウェアが書いたものなので無視する
+                _ => {}
+            }
+            payload = next;
+        }
+        Ok(config)
+    }
+
+    pub fn load() -> (Self, RecordStatus) {
+        match Self::decode(flash::read(CONFIG_OFFSET, RECORD_LEN)) {
+            Ok(config) => (config, RecordStatus::Ok),
+            Err(status) => {
+                if status == RecordStatus::Corrupt {
+                    warn!("Config record is corrupt, using defaults");
+                }
+                (Config::default(), status)
+            }
+        }
+    }
+
+    pub fn store(&self) -> Result<(), CommandError> {
+        let record = self.encode();
+        flash::write_sector(CONFIG_OFFSET, &record).map_err(|_| CommandError::Storage)
+    }
+}
+
+pub struct ConfigState {
+    pub config: Config,
+    pub status: RecordStatus,
+    pub unique_id: [u8; UNIQUE_ID_LEN],
+}
+
+impl ConfigState {
+    pub fn new(unique_id: [u8; UNIQUE_ID_LEN]) -> Self {
+        let (config, status) = Config::load();
+        Self {
+            config,
+            status,
+            unique_id,
+        }
+    }
+
+    // USBのシリアル番号。上書きが無ければユニークIDの16進表記
+    pub fn usb_serial(&self) -> String<MAX_SERIAL_LEN> {
+        if let Some(serial) = &self.config.serial {
+            return serial.clone();
+        }
+        let mut s = String::new();
+        for byte in self.unique_id {
+            let _ = write!(s, "{:02X}", byte);
+        }
+        s
+    }
+
+    pub fn usb_vid(&self) -> u16 {
+        self.config.vid.unwrap_or(DEFAULT_VID)
+    }
+
+    pub fn usb_pid(&self) -> u16 {
+        self.config.pid.unwrap_or(DEFAULT_PID)
+    }
+
+    pub fn usb_manufacturer(&self) -> &str {
+        self.config
+            .manufacturer
+            .as_deref()
+            .unwrap_or(DEFAULT_MANUFACTURER)
+    }
+
+    pub fn usb_product(&self) -> &str {
+        self.config.product.as_deref().unwrap_or(DEFAULT_PRODUCT)
+    }
+
+    pub fn execute(&mut self, cmd: ConfigCommand) -> Result<Reply, CommandError> {
+        match cmd {
+            ConfigCommand::Show => {
+                let serial = self.usb_serial();
+                Ok(ok_reply(|r| {
+                    write!(r, " config record={} uid=", self.status.name())?;
+                    for byte in self.unique_id {
+                        write!(r, "{:02X}", byte)?;
+                    }
+                    let source = if self.config.serial.is_some() {
+                        "config"
+                    } else {
+                        "uid"
+                    };
+                    write!(
+                        r,
+                        " serial={} source={} vid=0x{:04X} pid=0x{:04X} manufacturer=\"{}\" product=\"{}\"",
+                        serial,
+                        source,
+                        self.usb_vid(),
+                        self.usb_pid(),
+                        self.usb_manufacturer(),
+                        self.usb_product()
+                    )
+                }))
+            }
+            ConfigCommand::Set(setting) => {
+                let name = setting.name();
+                let mut config = self.config.clone();
+                match setting {
+                    Setting::Serial(serial) => config.serial = serial,
+                    Setting::Vid(vid) => config.vid = vid,
+                    Setting::Pid(pid) => config.pid = pid,
+                    Setting::Manufacturer(name) => config.manufacturer = name,
+                    Setting::Product(name) => config.product = name,
+                }
+                self.save(config)?;
+                // ディスクリプタは起動時に作るので反映は再起動後
+                Ok(ok_reply(|r| {
+                    write!(r, " config {} saved reboot=required", name)
+                }))
+            }
+            ConfigCommand::Reset => {
+                self.save(Config::default())?;
+                Ok(ok_reply(|r| r.write_str(" config reset reboot=required")))
+            }
+        }
+    }
+
+    fn save(&mut self, config: Config) -> Result<(), CommandError> {
+        config.store()?;
+        self.config = config;
+        self.status = RecordStatus::Ok;
+        Ok(())
+    }
+}
+
+// Noneなら既定値 (シリアル番号はユニークID) に戻す
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum Setting {
+    Serial(Option<String<MAX_SERIAL_LEN>>),
+    Vid(Option<u16>),
+    Pid(Option<u16>),
+    Manufacturer(Option<String<MAX_NAME_LEN>>),
+    Product(Option<String<MAX_NAME_LEN>>),
+}
+
+impl Setting {
+    pub fn name(&self) -> &'static str {
+        match self {
+            Setting::Serial(_) => "serial",
+            Setting::Vid(_) => "vid",
+            Setting::Pid(_) => "pid",
+            Setting::Manufacturer(_) => "manufacturer",
+            Setting::Product(_) => "product",
+        }
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum ConfigCommand {
+    Show,
+    // `config <項目> <値>` / `config <項目> default`
+    Set(Setting),
+    Reset,
+}
+
+// 残りの引数を空白1つで繋いだ文字列にする (製品名などは空白を含む)
+fn rest_text<'a, const N: usize>(
+    first: &'a str,
+    args: &mut Args<'a>,
+) -> Result<String<N>, CommandError> {
+    let mut text = String::<N>::new();
+    text.push_str(first).map_err(|_| CommandError::OutOfRange)?;
+    while let Some(word) = args.next_opt() {
+        text.push(' ').map_err(|_| CommandError::OutOfRange)?;
+        text.push_str(word).map_err(|_| CommandError::OutOfRange)?;
+    }
+    validate_string(&text)
+}
+
+impl ConfigCommand {
+    pub fn parse(args: &mut Args) -> Result<Self, CommandError> {
+        let name = args.next_str()?;
+        match name {
+            "show" => return Ok(ConfigCommand::Show),
+            "reset" => return Ok(ConfigCommand::Reset),
+            _ => {}
+        }
+        let value = args.next_str()?;
+        let value = (value != "default").then_some(value);
+        let setting = match name {
+            "serial" => Setting::Serial(value.map(validate_string).transpose()?),
+            "vid" => Setting::Vid(
+                value
+                    .map(|v| parse_u32(v).and_then(validate_id))
+                    .transpose()?,
+            ),
+            "pid" => Setting::Pid(
+                value
+                    .map(|v| parse_u32(v).and_then(validate_id))
+                    .transpose()?,
+            ),
+            "manufacturer" => Setting::Manufacturer(value.map(|v| rest_text(v, args)).transpose()?),
+            "product" => Setting::Product(value.map(|v| rest_text(v, args)).transpose()?),
+            _ => return Err(CommandError::InvalidArgument),
+        };
+        Ok(ConfigCommand::Set(setting))
+    }
+}
+
+pub fn handle_command(args: &mut Args) -> Result<Reply, CommandError> {
+    let cmd = ConfigCommand::parse(args)?;
+    interrupt::free(|cs| {
+        CONFIG
+            .borrow(cs)
+            .borrow_mut()
+            .as_mut()
+            .ok_or(CommandError::NotReady)?
+            .execute(cmd)
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // 任意のpayloadで正しいヘッダーとCRCのレコードを作る
+    fn record(payload: &[u8]) -> [u8; RECORD_LEN] {
+        let mut buf = [0xFF; RECORD_LEN];
+        buf[0..4].copy_from_slice(&MAGIC.to_le_bytes());
+        buf[4] = VERSION;
+        buf[5] = 0;
+        buf[6..8].copy_from_slice(&(payload.len() as u16).to_le_bytes());
+        let end = HEADER_LEN + payload.len();
+        buf[HEADER_LEN..end].copy_from_slice(payload);
+        let crc = crc32(&buf[..end]);
+        buf[end..end + CRC_LEN].copy_from_slice(&crc.to_le_bytes());
+        buf
+    }
+
+    fn parse(line: &str) -> Result<ConfigCommand, CommandError> {
+        ConfigCommand::parse(&mut Args::new(line))
+    }
+
+    #[test]
+    fn round_trip() {
+        let config = Config {
+            serial: Some(String::try_from("SN-0001").unwrap()),
+            vid: Some(0x1209),
+            pid: Some(0x0001),
+            manufacturer: Some(String::try_from("Example Labs").unwrap()),
+            product: Some(String::try_from("Pico Probe").unwrap()),
+        };
+        assert_eq!(Config::decode(&config.encode()), Ok(config));
+        assert_eq!(
+            Config::decode(&Config::default().encode()),
+            Ok(Config::default())
+        );
+    }
+
+    #[test]
+    fn erased_record_is_empty() {
+        assert_eq!(
+            Config::decode(&[0xFF; RECORD_LEN]),
+            Err(RecordStatus::Empty)
+        );
+    }
+
+    #[test]
+    fn damaged_header_or_crc_is_corrupt() {
+        let config = Config {
+            vid: Some(0x1209),
+            ..Config::default()
+        };
+        let good = config.encode();
+
+        let mut bad_magic = good;
+        bad_magic[0] ^= 1;
+        assert_eq!(Config::decode(&bad_magic), Err(RecordStatus::Corrupt));
+
+        let mut bad_version = good;
+        bad_version[4] = VERSION + 1;
+        assert_eq!(Config::decode(&bad_version), Err(RecordStatus::Corrupt));
+
+        let mut bad_crc = good;
+        bad_crc[HEADER_LEN + 2] ^= 0x80;
+        assert_eq!(Config::decode(&bad_crc), Err(RecordStatus::Corrupt));
+
+        let mut bad_len = good;
+        bad_len[6..8].copy_from_slice(&(RECORD_LEN as u16).to_le_bytes());
+        assert_eq!(Config::decode(&bad_len), Err(RecordStatus::Corrupt));
+
+        assert_eq!(Config::decode(&good[..4]), Err(RecordStatus::Corrupt));
+    }
+
+    #[test]
+    fn unknown_tags_are_skipped() {
+        let buf = record(&[0x7F, 3, 1, 2, 3, TAG_PID, 2, 0x34, 0x12]);
+        assert_eq!(
+            Config::decode(&buf),
+            Ok(Config {
+                pid: Some(0x1234),
+                ..Config::default()
+            })
+        );
+    }
+
+    #[test]
+    fn invalid_stored_values_are_corrupt() {
+        // VIDが0
+        let buf = record(&[TAG_VID, 2, 0, 0]);
+        assert_eq!(Config::decode(&buf), Err(RecordStatus::Corrupt));
+        // VIDの長さが違う
+        let buf = record(&[TAG_VID, 1, 0x12]);
+        assert_eq!(Config::decode(&buf), Err(RecordStatus::Corrupt));
+        // 表示できない文字
+        let buf = record(&[TAG_PRODUCT, 3, b'a', 0x07, b'b']);
+        assert_eq!(Config::decode(&buf), Err(RecordStatus::Corrupt));
+        // UTF-8でない
+        let buf = record(&[TAG_MANUFACTURER, 2, 0xC3, 0x28]);
+        assert_eq!(Config::decode(&buf), Err(RecordStatus::Corrupt));
+        // 長さがpayloadを超える
+        let buf = record(&[TAG_SERIAL, 10, b'a']);
+        assert_eq!(Config::decode(&buf), Err(RecordStatus::Corrupt));
+    }
+
+    #[test]
+    fn parses_commands() {
+        assert_eq!(parse("show"), Ok(ConfigCommand::Show));
+        assert_eq!(parse("reset"), Ok(ConfigCommand::Reset));
+        assert_eq!(
+            parse("vid 0x1209"),
+            Ok(ConfigCommand::Set(Setting::Vid(Some(0x1209))))
+        );
+        assert_eq!(
+            parse("serial default"),
+            Ok(ConfigCommand::Set(Setting::Serial(None)))
+        );
+        assert_eq!(
+            parse("product default"),
+            Ok(ConfigCommand::Set(Setting::Product(None)))
+        );
+        // 製品名は空白を1つに詰めて繋ぐ
+        assert_eq!(
+            parse("product Pico   Logic Probe"),
+            Ok(ConfigCommand::Set(Setting::Product(Some(
+                String::try_from("Pico Logic Probe").unwrap()
+            ))))
+        );
+        assert_eq!(parse("vid 0"), Err(CommandError::OutOfRange));
+        assert_eq!(parse("pid 0x10000"), Err(CommandError::OutOfRange));
+        assert_eq!(parse("color red"), Err(CommandError::InvalidArgument));
+        assert_eq!(parse("serial"), Err(CommandError::MissingArgument));
+        let long = "x".repeat(MAX_NAME_LEN + 1);
+        assert_eq!(
+            parse(&format!("manufacturer {long}")),
+            Err(CommandError::OutOfRange)
+        );
+    }
+}
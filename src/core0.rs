@@ -1,10 +1,38 @@
+use crate::adc::{self, AdcState};
+use crate::bootinfo;
+use crate::capture::CaptureState;
+use crate::config::ConfigState;
 use crate::core1;
+use crate::crashlog;
+use crate::flash;
+use crate::freqcounter;
+#[cfg(feature = "hid")]
+use crate::globals::KEYBOARD;
 use crate::globals::{
-    ALARM0, ALARM1, ALARM2, ALARM3, CORE1_STACK, LED_PIN, SERIAL, USB_DEV, USB_RECIEVER,
+    ADC, ALARM0, ALARM1, ALARM2, ALARM3, BRIDGE_SERIAL, CAPTURE, CONFIG, CORE1_STACK, I2C, KV,
+    LED_PIN, PIN_POOL, PWM, REBOOT, RESETS, SERIAL, SPI, UART_BRIDGE, USB_DEV, USB_RECIEVER,
+    VENDOR, WATCHDOG,
 };
-use crate::sharedmessage::SHARED_MESSAGE_CORE0_TO_CORE1;
+#[cfg(feature = "midi")]
+use crate::globals::{MIDI, MIDI_CLASS};
+#[cfg(feature = "hid")]
+use crate::hid::HidKeyboard;
+use crate::i2c::{self, I2cState};
+use crate::kv;
+use crate::lockout;
+#[cfg(feature = "midi")]
+use crate::midi::{self, MidiClass, MidiState};
+use crate::pinpool::PinPool;
+use crate::pwm::PwmState;
+use crate::reboot::{self, RebootState};
+use crate::sharedmessage::{SHARED_MESSAGE_CORE0_TO_CORE1, SHARED_MESSAGE_CORE1_TO_CORE0};
+use crate::spi::SpiState;
+use crate::stack;
+use crate::supervisor;
+use crate::uartbridge::UartBridgeState;
 use crate::usb;
-use defmt::info;
+use crate::vendor::VendorClass;
+use defmt::{info, warn};
 use rp_pico::hal::fugit::MicrosDurationU32;
 
 extern crate alloc;
@@ -13,7 +41,6 @@ use alloc::string::String;
 
 use defmt_rtt as _;
 
-use panic_probe as _;
 // usbシリアル通信サポート
 // USB Device support
 use usb_device::device::StringDescriptors;
@@ -27,19 +54,16 @@ use rp_pico as bsp;
 // use sparkfun_pro_micro_rp2040 as bsp;
 use bsp::hal::{
     clocks::init_clocks_and_plls, multicore::Multicore, pac, sio::Sio, timer::Alarm,
-    watchdog::Watchdog, Timer,
+    watchdog::Watchdog, Adc, Clock, Timer,
 };
 
-const USB_VID: u16 = 0x16C0;
-const USB_PID: u16 = 0x27DD;
-const USB_SERIAL_NUMBER_EN: &str = "picopico";
-const USB_MANUFACTURER_EN: &str = "My Company";
-const USB_PRODUCT_NAME_EN: &str = "RP2040 USB Serial test";
 const USB_POLLING_INTERVAL: MicrosDurationU32 = MicrosDurationU32::micros(2_000); // 2ms  5msにするとusbデバイスが切れる
 
 const TIMER_INTERVAL_10MS: MicrosDurationU32 = MicrosDurationU32::micros(10_000); // 100ms
 
 pub fn main() -> ! {
+    // スタックの最大使用量を測るため、まだ使っていない部分を塗っておく
+    stack::paint_core0();
     let mut s = String::from("Hello, ");
     s.push_str("Heap!");
     info!("String: {}", s.as_str());
@@ -65,6 +89,36 @@ pub fn main() -> ! {
     )
     .ok()
     .unwrap();
+    cortex_m::interrupt::free(|cs| {
+        WATCHDOG.borrow(cs).replace(Some(watchdog));
+        REBOOT.borrow(cs).replace(Some(RebootState::new()));
+    });
+    // ウォッチドッグで落ちていたら、止まっていたコアを報告する
+    supervisor::take_last_hang();
+    // パニックかHardFaultで落ちていたら記録を取り出す
+    crashlog::take_record();
+
+    // ユニークIDの読み出しはXIPを止めるので、割り込みとcore1が動き出す前に済ませる
+    let config = ConfigState::new(flash::read_unique_id());
+    // 文字列ディスクリプタはUSBデバイスと同じ寿命が要る
+    let usb_serial_number: &'static str =
+        Box::leak(String::from(config.usb_serial().as_str()).into_boxed_str());
+    let usb_manufacturer: &'static str =
+        Box::leak(String::from(config.usb_manufacturer()).into_boxed_str());
+    let usb_product: &'static str = Box::leak(String::from(config.usb_product()).into_boxed_str());
+    let usb_vid_pid = UsbVidPid(config.usb_vid(), config.usb_pid());
+    cortex_m::interrupt::free(|cs| {
+        CONFIG.borrow(cs).replace(Some(config));
+    });
+    // KVストアのマウントも消去や書き込みをすることがあるので同じくここで行う
+    match kv::mount() {
+        Ok(store) => cortex_m::interrupt::free(|cs| {
+            KV.borrow(cs).replace(Some(store));
+        }),
+        Err(_) => warn!("kv store mount failed"),
+    }
+    // 起動回数をKVストアに書くのでこれもcore1の起動前に行う
+    bootinfo::record_boot();
 
     // core1起動前にFIFOを一応初期化状態にする
     sio.fifo.drain();
@@ -73,31 +127,19 @@ pub fn main() -> ! {
     // usbポーリングのタイマー割り込みセットアップ
     let mut timer = Timer::new(pac.TIMER, &mut pac.RESETS, &clocks);
     // Alarmをグローバルに保存
-    cortex_m::interrupt::free(|cs| {
-        ALARM0.borrow(cs).replace(Some(timer.alarm_0().unwrap()));
-    });
-    cortex_m::interrupt::free(|cs| {
-        ALARM1.borrow(cs).replace(Some(timer.alarm_1().unwrap()));
-    });
-    cortex_m::interrupt::free(|cs| {
-        ALARM2.borrow(cs).replace(Some(timer.alarm_2().unwrap()));
-    });
-    cortex_m::interrupt::free(|cs| {
-        ALARM3.borrow(cs).replace(Some(timer.alarm_3().unwrap()));
-    });
+    ALARM0.replace(timer.alarm_0().unwrap());
+    ALARM1.replace(timer.alarm_1().unwrap());
+    ALARM2.replace(timer.alarm_2().unwrap());
+    ALARM3.replace(timer.alarm_3().unwrap());
 
     // Alarm の割り込みを有効化し、最初の割り込みをセット（USB_POLLING_INTERVAL後）
-    cortex_m::interrupt::free(|cs| {
-        if let Some(alarm) = ALARM0.borrow(cs).borrow_mut().as_mut() {
-            alarm.schedule(USB_POLLING_INTERVAL).unwrap();
-            alarm.enable_interrupt();
-        }
+    ALARM0.with(|alarm| {
+        alarm.schedule(USB_POLLING_INTERVAL).unwrap();
+        alarm.enable_interrupt();
     });
-    cortex_m::interrupt::free(|cs| {
-        if let Some(alarm) = ALARM1.borrow(cs).borrow_mut().as_mut() {
-            alarm.schedule(TIMER_INTERVAL_10MS).unwrap();
-            alarm.enable_interrupt();
-        }
+    ALARM1.with(|alarm| {
+        alarm.schedule(TIMER_INTERVAL_10MS).unwrap();
+        alarm.enable_interrupt();
     });
 
     unsafe { pac::NVIC::unmask(pac::Interrupt::TIMER_IRQ_0) };
@@ -119,21 +161,39 @@ pub fn main() -> ! {
         &mut pac.RESETS,
     ))));
     let serial = SerialPort::new(usb_bus);
+    // 2つ目のシリアルはUARTブリッジ用
+    let bridge_serial = SerialPort::new(usb_bus);
+    // バイナリ転送用のバルクインターフェース
+    let vendor = VendorClass::new(usb_bus);
+    #[cfg(feature = "hid")]
+    let keyboard = HidKeyboard::new(usb_bus);
+    #[cfg(feature = "midi")]
+    let midi_class = MidiClass::new(usb_bus);
     let usb_string_desc_en = StringDescriptors::new(LangID::EN_US)
-        .manufacturer(USB_MANUFACTURER_EN)
-        .product(USB_PRODUCT_NAME_EN)
-        .serial_number(USB_SERIAL_NUMBER_EN);
+        .manufacturer(usb_manufacturer)
+        .product(usb_product)
+        .serial_number(usb_serial_number);
     let usb_string_descs = [usb_string_desc_en];
     // Set a USB device
-    let usb_dev = UsbDeviceBuilder::new(usb_bus, UsbVidPid(USB_VID, USB_PID))
+    let usb_dev = UsbDeviceBuilder::new(usb_bus, usb_vid_pid)
         .strings(&usb_string_descs)
         .expect("Failed to create USB device")
-        .device_class(2)
+        // CDC2つとベンダー固有インターフェースを持つのでIAD付きの複合デバイスにする
+        .composite_with_iads()
         .build();
     // Set the USB device and serial port to the global variable
     cortex_m::interrupt::free(|cs| {
         USB_DEV.borrow(cs).replace(Some(usb_dev));
         SERIAL.borrow(cs).replace(Some(serial));
+        BRIDGE_SERIAL.borrow(cs).replace(Some(bridge_serial));
+        VENDOR.borrow(cs).replace(Some(vendor));
+        #[cfg(feature = "hid")]
+        KEYBOARD.borrow(cs).replace(Some(keyboard));
+        #[cfg(feature = "midi")]
+        {
+            MIDI_CLASS.borrow(cs).replace(Some(midi_class));
+            MIDI.borrow(cs).replace(Some(MidiState::new()));
+        }
     });
     // This is the correct pin on the Raspberry Pico board. On other boards, even if they have an
     // on-board LED, it might need to be changed.
@@ -145,8 +205,73 @@ pub fn main() -> ! {
     // LED to one of the GPIO pins, and reference that pin here. Don't forget adding an appropriate resistor
     // in series with the LED.
     let led_pin = pins.led.into_push_pull_output();
+    LED_PIN.replace(led_pin);
+
+    // コマンドから使う汎用GPIOをピンプールに預ける
+    // GPIO23(電源モード), GPIO24(VBUS検出), GPIO25(LED)はボードで使っているので除外
+    let pin_pool = PinPool::new([
+        pins.gpio0.into_dyn_pin(),
+        pins.gpio1.into_dyn_pin(),
+        pins.gpio2.into_dyn_pin(),
+        pins.gpio3.into_dyn_pin(),
+        pins.gpio4.into_dyn_pin(),
+        pins.gpio5.into_dyn_pin(),
+        pins.gpio6.into_dyn_pin(),
+        pins.gpio7.into_dyn_pin(),
+        pins.gpio8.into_dyn_pin(),
+        pins.gpio9.into_dyn_pin(),
+        pins.gpio10.into_dyn_pin(),
+        pins.gpio11.into_dyn_pin(),
+        pins.gpio12.into_dyn_pin(),
+        pins.gpio13.into_dyn_pin(),
+        pins.gpio14.into_dyn_pin(),
+        pins.gpio15.into_dyn_pin(),
+        pins.gpio16.into_dyn_pin(),
+        pins.gpio17.into_dyn_pin(),
+        pins.gpio18.into_dyn_pin(),
+        pins.gpio19.into_dyn_pin(),
+        pins.gpio20.into_dyn_pin(),
+        pins.gpio21.into_dyn_pin(),
+        pins.gpio22.into_dyn_pin(),
+        pins.gpio26.into_dyn_pin(),
+        pins.gpio27.into_dyn_pin(),
+        pins.gpio28.into_dyn_pin(),
+        pins.voltage_monitor.into_dyn_pin(),
+    ]);
     cortex_m::interrupt::free(|cs| {
-        LED_PIN.borrow(cs).replace(Some(led_pin));
+        PIN_POOL.borrow(cs).replace(Some(pin_pool));
+    });
+
+    let adc = Adc::new(pac.ADC, &mut pac.RESETS);
+    cortex_m::interrupt::free(|cs| {
+        ADC.borrow(cs).replace(Some(AdcState::new(adc)));
+    });
+
+    // ADCキャプチャ用のDMAはレジスタを直接叩くのでリセット解除だけしておく
+    pac.RESETS.reset().modify(|_, w| w.dma().clear_bit());
+    while pac.RESETS.reset_done().read().dma().bit_is_clear() {}
+    cortex_m::interrupt::free(|cs| {
+        CAPTURE.borrow(cs).replace(Some(CaptureState::new(pac.DMA)));
+    });
+
+    // PWMも任意のピンから使うのでスライスはレジスタで直接設定する
+    pac.RESETS.reset().modify(|_, w| w.pwm().clear_bit());
+    while pac.RESETS.reset_done().read().pwm().bit_is_clear() {}
+    let pwm = PwmState::new(pac.PWM, clocks.system_clock.freq().to_Hz());
+    cortex_m::interrupt::free(|cs| {
+        PWM.borrow(cs).replace(Some(pwm));
+    });
+
+    // I2C/SPI/UARTはコマンドで初期化されるまでPACのまま持っておく
+    let i2c = I2cState::new(pac.I2C0, pac.I2C1, clocks.system_clock.freq().to_Hz());
+    let spi = SpiState::new(pac.SPI0, pac.SPI1, clocks.peripheral_clock.freq().to_Hz());
+    let uart_bridge =
+        UartBridgeState::new(pac.UART0, pac.UART1, clocks.peripheral_clock.freq().to_Hz());
+    cortex_m::interrupt::free(|cs| {
+        I2C.borrow(cs).replace(Some(i2c));
+        SPI.borrow(cs).replace(Some(spi));
+        UART_BRIDGE.borrow(cs).replace(Some(uart_bridge));
+        RESETS.borrow(cs).replace(Some(pac.RESETS));
     });
 
     let usb_reciever = usb::UsbMessageReciver::new();
@@ -155,6 +280,8 @@ pub fn main() -> ! {
     });
 
     // core1の起動
+    lockout::core1_starting();
+    stack::paint_core1();
     let mut multicore = Multicore::new(&mut pac.PSM, &mut pac.PPB, &mut sio.fifo);
     unsafe {
         #[allow(static_mut_refs)]
@@ -171,31 +298,48 @@ pub fn main() -> ! {
     let response = sio.fifo.read_blocking();
     info!("FIFO read: {}", response);
 
+    // 両コアの割り込みが動き出してからウォッチドッグを起動する
+    supervisor::start();
+
     loop {
         cortex_m::asm::wfi(); // Wait for interrupt
     }
 }
 
+#[inline(never)]
+#[link_section = ".ram_text"]
 pub fn handle_timer_irq_0() {
     // usbポーリングをする大事な割り込みタスク usbポーリングは2msecぐらいが良い
-    cortex_m::interrupt::free(|cs| {
-        // Alarm0の割り込みフラグをクリアし、次の割り込みをスケジュール
-        if let Some(alarm) = ALARM0.borrow(cs).borrow_mut().as_mut() {
-            alarm.clear_interrupt();
-            alarm.schedule(USB_POLLING_INTERVAL).ok();
-        }
+    // Alarm0の割り込みフラグをクリアし、次の割り込みをスケジュール
+    ALARM0.with(|alarm| {
+        alarm.clear_interrupt();
+        alarm.schedule(USB_POLLING_INTERVAL).ok();
+    });
+    cortex_m::interrupt::free(|_| {
         // USBポーリング
         usb::poll_usb();
     });
+    supervisor::checkin_core0();
 }
 pub fn handle_timer_irq_1() {
-    cortex_m::interrupt::free(|cs| {
-        // Alarm1の割り込みフラグをクリアし、次の割り込みをスケジュール
-        if let Some(alarm) = ALARM1.borrow(cs).borrow_mut().as_mut() {
-            alarm.clear_interrupt();
-            alarm.schedule(TIMER_INTERVAL_10MS).ok();
-        }
+    // Alarm1の割り込みフラグをクリアし、次の割り込みをスケジュール
+    ALARM1.with(|alarm| {
+        alarm.clear_interrupt();
+        alarm.schedule(TIMER_INTERVAL_10MS).ok();
     });
+    // ADCストリームのサンプリング
+    adc::poll_stream();
+    // 周波数カウンタのゲート処理
+    let freq_line = freqcounter::poll();
+    // 進行中のI2Cスキャンを少し進める
+    let i2c_line = i2c::poll();
+    // MIDIに対応付けたGPIO/ADCの変化を見る
+    #[cfg(feature = "midi")]
+    midi::poll();
+    // 確認済みの `reboot` を実行する
+    reboot::poll();
+    // 両コアのチェックインが揃っていればウォッチドッグに餌をやる
+    supervisor::poll();
     cortex_m::interrupt::free(|cs| {
         // ロックが取得できずバッファに残っている物をqueueに送信
         SHARED_MESSAGE_CORE0_TO_CORE1.borrow(cs).flush();
@@ -205,6 +349,27 @@ pub fn handle_timer_irq_1() {
             if let Some(usb_reciever) = USB_RECIEVER.borrow(cs).borrow_mut().as_mut() {
                 usb_reciever.poll(serial);
             }
+            // ホストがシリアルを開いたら起動情報を一度だけ送る
+            if serial.dtr() {
+                if let Some(line) = bootinfo::take_banner() {
+                    usb::write_line(serial, line.as_str());
+                }
+                if let Some(line) = crashlog::take_report() {
+                    usb::write_line(serial, line.as_str());
+                }
+            }
+            for line in [freq_line.as_ref(), i2c_line.as_ref()]
+                .into_iter()
+                .flatten()
+            {
+                usb::write_line(serial, line.as_str());
+            }
+            // core1から戻ってきた行をホストへ送る
+            SHARED_MESSAGE_CORE1_TO_CORE0
+                .borrow(cs)
+                .drain_all()
+                .into_iter()
+                .for_each(|line| usb::write_line(serial, &line));
         }
     });
 }
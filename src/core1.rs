@@ -1,7 +1,12 @@
 // src/core1.rs
+use crate::adc;
+use crate::capture;
 use crate::globals::{ALARM2, ALARM3};
 use crate::led;
-use crate::sharedmessage::SHARED_MESSAGE_CORE0_TO_CORE1;
+use crate::lockout;
+use crate::msgpool::MessageKind;
+use crate::sharedmessage::{SHARED_MESSAGE_CORE0_TO_CORE1, SHARED_MESSAGE_CORE1_TO_CORE0};
+use crate::supervisor;
 use cortex_m::asm;
 use cortex_m::interrupt;
 use defmt::info;
@@ -14,19 +19,18 @@ const TIMER_INTERVAL_5MS: MicrosDurationU32 = MicrosDurationU32::micros(5_000);
 
 pub fn core1_task() {
     info!("Core1 task started");
+    // スタックが溢れたら下のメモリを壊す前に止める (stack.rsを参照)
+    #[cfg(feature = "stack-guard")]
+    crate::stack::install_core1_guard();
     // core0で初期化されたクロックとタイマーを使用するために、Peripheralsをstealして取得
     // let mut pac = unsafe { pac::Peripherals::steal() };
-    interrupt::free(|cs| {
-        if let Some(alarm) = ALARM2.borrow(cs).borrow_mut().as_mut() {
-            alarm.schedule(TIMER_INTERVAL_100MS).unwrap();
-            alarm.enable_interrupt();
-        }
+    ALARM2.with(|alarm| {
+        alarm.schedule(TIMER_INTERVAL_100MS).unwrap();
+        alarm.enable_interrupt();
     });
-    interrupt::free(|cs| {
-        if let Some(alarm) = ALARM3.borrow(cs).borrow_mut().as_mut() {
-            alarm.schedule(TIMER_INTERVAL_5MS).unwrap();
-            alarm.enable_interrupt();
-        }
+    ALARM3.with(|alarm| {
+        alarm.schedule(TIMER_INTERVAL_5MS).unwrap();
+        alarm.enable_interrupt();
     });
     unsafe {
         pac::NVIC::unmask(pac::Interrupt::TIMER_IRQ_2); // Core1用
@@ -42,6 +46,9 @@ pub fn core1_task() {
     let value = fifo.read_blocking();
     info!("Received value from Core0: {}", value);
     fifo.write_blocking(value + 1); // Core0に値を返す
+
+    // これ以降FIFOはフラッシュ操作中にcore1を止めるために使う
+    lockout::core1_ready();
     info!("Core1 task completed, entering WFI loop");
 
     loop {
@@ -49,30 +56,38 @@ pub fn core1_task() {
     }
 }
 
+pub fn handle_sio_irq() {
+    lockout::handle_core1_sio_irq();
+}
+
+#[inline(never)]
+#[link_section = ".ram_text"]
 pub fn handle_timer_irq_2() {
-    interrupt::free(|cs| {
-        if let Some(alarm) = ALARM2.borrow(cs).borrow_mut().as_mut() {
-            alarm.clear_interrupt();
-            alarm.schedule(TIMER_INTERVAL_100MS).ok();
-        }
+    ALARM2.with(|alarm| {
+        alarm.clear_interrupt();
+        alarm.schedule(TIMER_INTERVAL_100MS).ok();
     });
-    led::led_toggle();
+    led::tick();
 }
 
+#[inline(never)]
+#[link_section = ".ram_text"]
 pub fn handle_timer_irq_3() {
-    interrupt::free(|cs| {
-        if let Some(alarm) = ALARM3.borrow(cs).borrow_mut().as_mut() {
-            alarm.clear_interrupt();
-            alarm.schedule(TIMER_INTERVAL_5MS).ok();
-        }
+    ALARM3.with(|alarm| {
+        alarm.clear_interrupt();
+        alarm.schedule(TIMER_INTERVAL_5MS).ok();
+    });
+    // DMAキャプチャのブロック統計
+    capture::process_ready_blocks();
+    let msgs = interrupt::free(|cs| SHARED_MESSAGE_CORE0_TO_CORE1.borrow(cs).drain_all());
+    msgs.into_iter().for_each(|msg| match msg.kind() {
+        // ADCの生サンプルは変換してcore0へ返す
+        MessageKind::AdcSample => adc::forward_stream_sample(&msg),
+        MessageKind::Text => info!("Core1 received message: {}", &*msg),
     });
     interrupt::free(|cs| {
-        SHARED_MESSAGE_CORE0_TO_CORE1
-            .borrow(cs)
-            .drain_all()
-            .into_iter()
-            .for_each(|msg| {
-                info!("Core1 received message: {}", msg.as_str());
-            });
-    })
+        // ロックが取得できずバッファに残っている物をqueueに送信
+        SHARED_MESSAGE_CORE1_TO_CORE0.borrow(cs).flush();
+    });
+    supervisor::checkin_core1();
 }
@@ -0,0 +1,318 @@
+// リセットしても残るパニック・HardFaultの記録
+// パニックならメッセージ・場所、HardFaultならスタックに積まれたレジスタとSPを、
+// コア番号と一緒に.uninit (起動時に初期化されないRAM) に書いてウォッチドッグでリセットする
+// 次の起動で取り出してUSBのシリアルへ一度だけ PANIC / HARDFAULT 行を送り、
+// `lastpanic` コマンドでも読めるようにする。PCとLRはELFと突き合わせて関数名にできる
+// 記録はmagicとCRCで確かめ、取り出したら消すので同じものを二度報告しない
+// ヒープの確保に失敗してパニックしたときは、要求された大きさも一緒に残す
+use crate::command::{ok_reply, Args, CommandError, Reply};
+use crate::crc::crc32;
+use crate::globals::{LAST_CRASH, MAX_MESSAGE_SIZE};
+use core::fmt::Write;
+use core::mem::{size_of, MaybeUninit};
+use core::ptr::{addr_of, addr_of_mut, read_volatile, write_volatile};
+use core::sync::atomic::{AtomicBool, AtomicU32, Ordering};
+use cortex_m::interrupt;
+use cortex_m_rt::ExceptionFrame;
+use heapless::String;
+use rp_pico::hal::{pac, sio::Sio};
+
+pub const MAX_FILE_LEN: usize = 48;
+pub const MAX_PANIC_MESSAGE_LEN: usize = 128;
+
+const MAGIC: u32 = 0x5043_5233; // "PCR3"
+const KIND_PANIC: u32 = 1;
+const KIND_HARD_FAULT: u32 = 2;
+// 例外フレームの xPSR bit9 が立っていれば、積むときに4バイトの詰め物が入っている
+const XPSR_STACK_ALIGN: u32 = 1 << 9;
+const EXCEPTION_FRAME_LEN: u32 = 32;
+
+#[repr(C)]
+#[derive(Clone, Copy)]
+struct CrashRecord {
+    magic: u32,
+    kind: u32,
+    core: u32,
+    // HardFaultのときだけ
+    frame: FaultFrame,
+    line: u32,
+    // 確保に失敗した大きさ。0なら失敗していない
+    alloc_failed_size: u32,
+    file_len: u32,
+    message_len: u32,
+    file: [u8; MAX_FILE_LEN],
+    message: [u8; MAX_PANIC_MESSAGE_LEN],
+    crc: u32,
+}
+
+const CRC_OFFSET: usize = size_of::<CrashRecord>() - 4;
+
+#[link_section = ".uninit.crashlog"]
+static mut RECORD: MaybeUninit<CrashRecord> = MaybeUninit::uninit();
+
+static REPORT_PENDING: AtomicBool = AtomicBool::new(false);
+// 確保の失敗はすぐパニックになるので、パニックの記録に入れるまでここに置いておく
+static ALLOC_FAILED_SIZE: AtomicU32 = AtomicU32::new(0);
+
+impl CrashRecord {
+    fn new(kind: u32) -> Self {
+        Self {
+            magic: MAGIC,
+            kind,
+            core: Sio::core() as u32,
+            frame: FaultFrame::default(),
+            line: 0,
+            alloc_failed_size: ALLOC_FAILED_SIZE.load(Ordering::Relaxed),
+            file_len: 0,
+            message_len: 0,
+            file: [0; MAX_FILE_LEN],
+            message: [0; MAX_PANIC_MESSAGE_LEN],
+            crc: 0,
+        }
+    }
+
+    fn crc(&self) -> u32 {
+        let bytes = unsafe {
+            core::slice::from_raw_parts(self as *const Self as *const u8, size_of::<Self>())
+        };
+        crc32(&bytes[..CRC_OFFSET])
+    }
+}
+
+// 溢れた分は捨てるWrite
+#[cfg(not(test))]
+struct Truncating<'a> {
+    buf: &'a mut [u8],
+    len: usize,
+}
+
+#[cfg(not(test))]
+impl Write for Truncating<'_> {
+    fn write_str(&mut self, s: &str) -> core::fmt::Result {
+        let n = s.len().min(self.buf.len() - self.len);
+        self.buf[self.len..self.len + n].copy_from_slice(&s.as_bytes()[..n]);
+        self.len += n;
+        Ok(())
+    }
+}
+
+// 途中で切れた文字は落とす
+fn to_text<const N: usize>(bytes: &[u8]) -> String<N> {
+    let valid = match core::str::from_utf8(bytes) {
+        Ok(s) => s,
+        Err(e) => core::str::from_utf8(&bytes[..e.valid_up_to()]).unwrap_or(""),
+    };
+    String::try_from(valid).unwrap_or_default()
+}
+
+// HardFaultの時点のレジスタ
+#[repr(C)]
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq)]
+pub struct FaultFrame {
+    pub r0: u32,
+    pub r1: u32,
+    pub r2: u32,
+    pub r3: u32,
+    pub r12: u32,
+    pub lr: u32,
+    pub pc: u32,
+    pub xpsr: u32,
+    // 例外フレームを積む前のSP
+    pub sp: u32,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum CrashKind {
+    Panic {
+        line: u32,
+        file: String<MAX_FILE_LEN>,
+        message: String<MAX_PANIC_MESSAGE_LEN>,
+    },
+    HardFault(FaultFrame),
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct CrashReport {
+    pub core: u8,
+    pub kind: CrashKind,
+    // ヒープの確保に失敗していたら要求された大きさ
+    pub alloc_failed_size: Option<u32>,
+}
+
+impl CrashReport {
+    pub fn name(&self) -> &'static str {
+        match self.kind {
+            CrashKind::Panic { .. } => "panic",
+            CrashKind::HardFault(_) => "hardfault",
+        }
+    }
+
+    pub fn write_summary(&self, out: &mut impl Write) -> core::fmt::Result {
+        write!(out, " kind={} core={}", self.name(), self.core)?;
+        if let Some(size) = self.alloc_failed_size {
+            write!(out, " oom={}", size)?;
+        }
+        match &self.kind {
+            CrashKind::Panic {
+                line,
+                file,
+                message,
+            } => write!(out, " at={}:{} msg={}", file, line, message),
+            CrashKind::HardFault(f) => write!(
+                out,
+                " pc=0x{:08x} lr=0x{:08x} xpsr=0x{:08x} sp=0x{:08x} r0=0x{:08x} r1=0x{:08x} r2=0x{:08x} r3=0x{:08x} r12=0x{:08x}",
+                f.pc, f.lr, f.xpsr, f.sp, f.r0, f.r1, f.r2, f.r3, f.r12
+            ),
+        }
+    }
+}
+
+// heap.rsで確保に失敗したときに呼ぶ
+pub fn note_alloc_failure(size: usize) {
+    ALLOC_FAILED_SIZE.store(size as u32, Ordering::Relaxed);
+}
+
+fn save_and_reset(mut record: CrashRecord) -> ! {
+    record.crc = record.crc();
+    unsafe { write_volatile(addr_of_mut!(RECORD) as *mut CrashRecord, record) };
+    // もう片方のコアも含めてチップ全体をリセットする
+    // WDSELはsupervisor::startでウォッチドッグを起動するまで空で、そのままではトリガーしても何も起きない
+    // pico-sdkのwatchdog_rebootと同じく発振器以外の全てを選んでから叩く
+    unsafe {
+        (*pac::PSM::ptr()).wdsel().write_with_zero(|w| {
+            w.bits(0x0001_FFFF);
+            w.xosc().clear_bit();
+            w.rosc().clear_bit();
+            w
+        });
+        (*pac::WATCHDOG::ptr())
+            .ctrl()
+            .modify(|_, w| w.trigger().set_bit())
+    };
+    loop {
+        cortex_m::asm::nop();
+    }
+}
+
+#[cfg(not(test))]
+#[panic_handler]
+fn panic(info: &core::panic::PanicInfo) -> ! {
+    interrupt::disable();
+    let mut record = CrashRecord::new(KIND_PANIC);
+    if let Some(location) = info.location() {
+        // 長いパスは末尾の方が役に立つ
+        let file = location.file().as_bytes();
+        let file = &file[file.len().saturating_sub(MAX_FILE_LEN)..];
+        record.file[..file.len()].copy_from_slice(file);
+        record.file_len = file.len() as u32;
+        record.line = location.line();
+    }
+    let mut message = Truncating {
+        buf: &mut record.message,
+        len: 0,
+    };
+    let _ = write!(message, "{}", info.message());
+    record.message_len = message.len as u32;
+    defmt::error!("{}", defmt::Display2Format(info));
+    save_and_reset(record)
+}
+
+// main.rsのHardFault例外ハンドラから呼ぶ
+pub fn record_hard_fault(ef: &ExceptionFrame) -> ! {
+    let mut record = CrashRecord::new(KIND_HARD_FAULT);
+    let mut sp = ef as *const ExceptionFrame as u32 + EXCEPTION_FRAME_LEN;
+    if ef.xpsr() & XPSR_STACK_ALIGN != 0 {
+        sp += 4;
+    }
+    record.frame = FaultFrame {
+        r0: ef.r0(),
+        r1: ef.r1(),
+        r2: ef.r2(),
+        r3: ef.r3(),
+        r12: ef.r12(),
+        lr: ef.lr(),
+        pc: ef.pc(),
+        xpsr: ef.xpsr(),
+        sp,
+    };
+    defmt::error!(
+        "HardFault on core{}: pc={:#010x} lr={:#010x}",
+        record.core,
+        ef.pc(),
+        ef.lr()
+    );
+    save_and_reset(record)
+}
+
+// 起動直後に呼ぶ。前回のパニックの記録があれば取り出して消す
+pub fn take_record() -> Option<CrashReport> {
+    let record = unsafe { read_volatile(addr_of!(RECORD) as *const CrashRecord) };
+    if record.magic != MAGIC {
+        return None;
+    }
+    unsafe { write_volatile(addr_of_mut!(RECORD) as *mut u32, 0) };
+    if record.crc != record.crc()
+        || record.file_len as usize > MAX_FILE_LEN
+        || record.message_len as usize > MAX_PANIC_MESSAGE_LEN
+    {
+        return None;
+    }
+    let kind = match record.kind {
+        KIND_PANIC => CrashKind::Panic {
+            line: record.line,
+            file: to_text(&record.file[..record.file_len as usize]),
+            message: to_text(&record.message[..record.message_len as usize]),
+        },
+        KIND_HARD_FAULT => CrashKind::HardFault(record.frame),
+        _ => return None,
+    };
+    let report = CrashReport {
+        core: record.core as u8,
+        kind,
+        alloc_failed_size: (record.alloc_failed_size != 0).then_some(record.alloc_failed_size),
+    };
+    defmt::warn!("last crash: {} on core{}", report.name(), report.core);
+    interrupt::free(|cs| {
+        LAST_CRASH.borrow(cs).replace(Some(report.clone()));
+    });
+    REPORT_PENDING.store(true, Ordering::Relaxed);
+    Some(report)
+}
+
+pub fn has_last_crash() -> bool {
+    interrupt::free(|cs| LAST_CRASH.borrow(cs).borrow().is_some())
+}
+
+// ホストがシリアルを開いたら一度だけ送る行
+pub fn take_report() -> Option<String<MAX_MESSAGE_SIZE>> {
+    if !REPORT_PENDING.swap(false, Ordering::Relaxed) {
+        return None;
+    }
+    interrupt::free(|cs| {
+        let last = LAST_CRASH.borrow(cs).borrow();
+        let last = last.as_ref()?;
+        let mut line = String::new();
+        match last.kind {
+            CrashKind::Panic { .. } => line.push_str("PANIC").ok()?,
+            CrashKind::HardFault(_) => line.push_str("HARDFAULT").ok()?,
+        }
+        last.write_summary(&mut line).ok()?;
+        Some(line)
+    })
+}
+
+pub fn handle_command(args: &mut Args) -> Result<Reply, CommandError> {
+    if args.next_opt().is_some() {
+        return Err(CommandError::InvalidArgument);
+    }
+    interrupt::free(|cs| {
+        let last = LAST_CRASH.borrow(cs).borrow();
+        Ok(ok_reply(|r| {
+            r.write_str(" lastpanic")?;
+            match last.as_ref() {
+                Some(report) => report.write_summary(r),
+                None => r.write_str(" none"),
+            }
+        }))
+    })
+}
@@ -0,0 +1,45 @@
+// CRCはここにまとめる
+// crc16はcaptureのフレーム、crc32は永続化するレコード (config, crashlog, kvstore) の検査に使う
+
+// CRC-16/CCITT-FALSE
+pub fn crc16(data: &[u8]) -> u16 {
+    let mut crc = 0xFFFFu16;
+    for &byte in data {
+        crc ^= (byte as u16) << 8;
+        for _ in 0..8 {
+            crc = if crc & 0x8000 != 0 {
+                (crc << 1) ^ 0x1021
+            } else {
+                crc << 1
+            };
+        }
+    }
+    crc
+}
+
+// CRC-32/ISO-HDLC (zlibと同じ)
+pub fn crc32(data: &[u8]) -> u32 {
+    let mut crc = 0xFFFF_FFFFu32;
+    for &byte in data {
+        crc ^= byte as u32;
+        for _ in 0..8 {
+            crc = if crc & 1 != 0 {
+                (crc >> 1) ^ 0xEDB8_8320
+            } else {
+                crc >> 1
+            };
+        }
+    }
+    !crc
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn smoke() {
+        assert_eq!(crc16(b"123456789"), 0x29B1);
+        assert_eq!(crc32(b"123456789"), 0xCBF4_3926);
+    }
+}
@@ -0,0 +1,53 @@
+// 両コアから触るグローバル
+// Shared (Mutex<RefCell<Option<T>>>) は割り込みを止めるだけなので同じコアの中でしか守れない
+// CrossCoreSharedは割り込みを止めたうえでハードウェアspinlock L を取り、もう片方のコアも締め出す
+// Lはspinlock.rsの割り当て表で定義したロックの型
+// spinlockは再入できないので、同じロックのwithの中で同じロックのwithを呼ばないこと
+use crate::spinlock::{self, Lock};
+use core::cell::UnsafeCell;
+use core::marker::PhantomData;
+use cortex_m::interrupt;
+
+pub struct CrossCoreShared<T, L: Lock> {
+    value: UnsafeCell<Option<T>>,
+    _lock: PhantomData<L>,
+}
+
+// 中身にはspinlockを取ってからしか触らない
+unsafe impl<T: Send, L: Lock> Sync for CrossCoreShared<T, L> {}
+
+impl<T, L: Lock> CrossCoreShared<T, L> {
+    pub const fn new() -> Self {
+        Self {
+            value: UnsafeCell::new(None),
+            _lock: PhantomData,
+        }
+    }
+
+    fn lock<R>(&self, f: impl FnOnce(&mut Option<T>) -> R) -> R {
+        interrupt::free(|_| {
+            let _guard = spinlock::claim::<L>();
+            f(unsafe { &mut *self.value.get() })
+        })
+    }
+
+    // 値を入れ替えて前の値を返す。初期化に使う
+    pub fn replace(&self, value: T) -> Option<T> {
+        self.lock(|v| v.replace(value))
+    }
+
+    pub fn take(&self) -> Option<T> {
+        self.lock(|v| v.take())
+    }
+
+    // まだ値が無ければfを呼ばずにNone
+    pub fn with<R>(&self, f: impl FnOnce(&mut T) -> R) -> Option<R> {
+        self.lock(|v| v.as_mut().map(f))
+    }
+}
+
+impl<T, L: Lock> Default for CrossCoreShared<T, L> {
+    fn default() -> Self {
+        Self::new()
+    }
+}
@@ -0,0 +1,212 @@
+// QSPIフラッシュの直接操作
+// 操作中はXIP(フラッシュからの実行)が止まるので、本体はRAMに置いてブートROMの関数で行う
+// 書き込みと消去はcore1をRAM上で待たせ(lockout)、core0の割り込みを止めてから行う
+use crate::lockout;
+use core::ptr::{read_volatile, write_volatile};
+use cortex_m::interrupt;
+use rp_pico::hal::rom_data;
+
+pub const FLASH_XIP_BASE: u32 = 0x1000_0000;
+// Picoに載っているW25Q16JV
+pub const FLASH_SIZE: u32 = 2 * 1024 * 1024;
+pub const SECTOR_SIZE: u32 = 4096;
+pub const PAGE_SIZE: usize = 256;
+pub const UNIQUE_ID_LEN: usize = 8;
+
+const BOOT2_SIZE_WORDS: usize = 64;
+const SECTOR_ERASE_CMD: u8 = 0x20;
+// Read Unique ID (4Bh) + ダミー4バイト + ID 8バイト
+const READ_UNIQUE_ID_CMD: u8 = 0x4B;
+const UNIQUE_ID_DUMMY_LEN: usize = 4;
+const UNIQUE_ID_XFER_LEN: usize = 1 + UNIQUE_ID_DUMMY_LEN + UNIQUE_ID_LEN;
+// SSIのFIFOは16段。受信が溢れないように送りすぎない
+const SSI_MAX_IN_FLIGHT: usize = 14;
+
+const XIP_SSI_SR: *const u32 = 0x1800_0028 as *const u32;
+const XIP_SSI_DR0: *mut u32 = 0x1800_0060 as *mut u32;
+const SSI_SR_TFNF: u32 = 1 << 1;
+const SSI_SR_RFNE: u32 = 1 << 3;
+const IO_QSPI_SS_CTRL: *mut u32 = 0x4001_800C as *mut u32;
+const SS_OUTOVER_MASK: u32 = 0x3 << 8;
+const SS_OUTOVER_LOW: u32 = 0x2 << 8;
+const SS_OUTOVER_HIGH: u32 = 0x3 << 8;
+
+// ROM関数の検索はフラッシュ上のコードなので、XIPを止める前にポインタを引いておく
+struct RomFns {
+    connect_internal_flash: unsafe extern "C" fn(),
+    flash_exit_xip: unsafe extern "C" fn(),
+    flash_range_erase: unsafe extern "C" fn(u32, usize, u32, u8),
+    flash_range_program: unsafe extern "C" fn(u32, *const u8, usize),
+    flash_flush_cache: unsafe extern "C" fn(),
+}
+
+impl RomFns {
+    fn lookup() -> Self {
+        Self {
+            connect_internal_flash: rom_data::connect_internal_flash::ptr(),
+            flash_exit_xip: rom_data::flash_exit_xip::ptr(),
+            flash_range_erase: rom_data::flash_range_erase::ptr(),
+            flash_range_program: rom_data::flash_range_program::ptr(),
+            flash_flush_cache: rom_data::flash_flush_cache::ptr(),
+        }
+    }
+}
+
+// 操作後はboot2を呼び直して起動時と同じ高速なXIP設定に戻す
+// フラッシュ操作はcore0だけが行うのでSRAM4に置く
+#[link_section = ".sram4"]
+static mut BOOT2_RAM: [u32; BOOT2_SIZE_WORDS] = [0; BOOT2_SIZE_WORDS];
+
+fn copy_boot2() -> *const u32 {
+    unsafe {
+        let boot2 = &raw mut BOOT2_RAM;
+        let src = FLASH_XIP_BASE as *const u32;
+        for i in 0..BOOT2_SIZE_WORDS {
+            (*boot2)[i] = read_volatile(src.add(i));
+        }
+        boot2 as *const u32
+    }
+}
+
+#[inline(always)]
+unsafe fn enter_xip(boot2: *const u32) {
+    // Thumbなので最下位ビットを立てて呼ぶ
+    let boot2: extern "C" fn() = core::mem::transmute(boot2 as usize | 1);
+    boot2();
+}
+
+#[inline(always)]
+unsafe fn cs_force(outover: u32) {
+    let ctrl = read_volatile(IO_QSPI_SS_CTRL);
+    write_volatile(IO_QSPI_SS_CTRL, (ctrl & !SS_OUTOVER_MASK) | outover);
+}
+
+#[inline(never)]
+#[link_section = ".ram_text"]
+unsafe fn read_unique_id_in_ram(rom: &RomFns, boot2: *const u32, out: *mut u8) {
+    (rom.connect_internal_flash)();
+    (rom.flash_exit_xip)();
+    cs_force(SS_OUTOVER_LOW);
+    let mut tx = 0;
+    let mut rx = 0;
+    while tx < UNIQUE_ID_XFER_LEN || rx < UNIQUE_ID_XFER_LEN {
+        let sr = read_volatile(XIP_SSI_SR);
+        if sr & SSI_SR_TFNF != 0 && tx < UNIQUE_ID_XFER_LEN && tx < rx + SSI_MAX_IN_FLIGHT {
+            let byte = if tx == 0 { READ_UNIQUE_ID_CMD } else { 0 };
+            write_volatile(XIP_SSI_DR0, byte as u32);
+            tx += 1;
+        }
+        if sr & SSI_SR_RFNE != 0 && rx < UNIQUE_ID_XFER_LEN {
+            let byte = read_volatile(XIP_SSI_DR0) as u8;
+            if rx > UNIQUE_ID_DUMMY_LEN {
+                *out.add(rx - UNIQUE_ID_DUMMY_LEN - 1) = byte;
+            }
+            rx += 1;
+        }
+    }
+    cs_force(SS_OUTOVER_HIGH);
+    (rom.flash_flush_cache)();
+    enter_xip(boot2);
+}
+
+// erase_lenやprogram_lenが0ならその操作は飛ばす
+#[inline(never)]
+#[link_section = ".ram_text"]
+unsafe fn erase_and_program_in_ram(
+    rom: &RomFns,
+    boot2: *const u32,
+    offset: u32,
+    erase_len: usize,
+    data: *const u8,
+    program_len: usize,
+) {
+    (rom.connect_internal_flash)();
+    (rom.flash_exit_xip)();
+    if erase_len > 0 {
+        (rom.flash_range_erase)(offset, erase_len, SECTOR_SIZE, SECTOR_ERASE_CMD);
+    }
+    if program_len > 0 {
+        (rom.flash_range_program)(offset, data, program_len);
+    }
+    (rom.flash_flush_cache)();
+    enter_xip(boot2);
+}
+
+fn erase_and_program(offset: u32, erase_len: usize, data: &[u8]) -> Result<(), FlashError> {
+    let rom = RomFns::lookup();
+    lockout::with_core1_parked(|| {
+        interrupt::free(|_| {
+            let boot2 = copy_boot2();
+            unsafe {
+                erase_and_program_in_ram(&rom, boot2, offset, erase_len, data.as_ptr(), data.len())
+            };
+        })
+    })
+    .map_err(|_| FlashError::Core1Busy)
+}
+
+// 起動直後、core1を起動する前に呼ぶ (core1を止める手順は踏まない)
+pub fn read_unique_id() -> [u8; UNIQUE_ID_LEN] {
+    let mut id = [0u8; UNIQUE_ID_LEN];
+    let rom = RomFns::lookup();
+    interrupt::free(|_| {
+        let boot2 = copy_boot2();
+        unsafe { read_unique_id_in_ram(&rom, boot2, id.as_mut_ptr()) };
+    });
+    id
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum FlashError {
+    Unaligned,
+    OutOfRange,
+    // core1が割り込み禁止のまま応答しなかった
+    Core1Busy,
+}
+
+// 1セクタを消去してdataを先頭から書く。dataはRAM上にありページ単位の長さであること
+pub fn write_sector(offset: u32, data: &[u8]) -> Result<(), FlashError> {
+    if !offset.is_multiple_of(SECTOR_SIZE) || !data.len().is_multiple_of(PAGE_SIZE) {
+        return Err(FlashError::Unaligned);
+    }
+    if data.len() > SECTOR_SIZE as usize || offset + SECTOR_SIZE > FLASH_SIZE {
+        return Err(FlashError::OutOfRange);
+    }
+    erase_and_program(offset, SECTOR_SIZE as usize, data)
+}
+
+pub fn erase_sector(offset: u32) -> Result<(), FlashError> {
+    if !offset.is_multiple_of(SECTOR_SIZE) {
+        return Err(FlashError::Unaligned);
+    }
+    if offset + SECTOR_SIZE > FLASH_SIZE {
+        return Err(FlashError::OutOfRange);
+    }
+    erase_and_program(offset, SECTOR_SIZE as usize, &[])
+}
+
+// 任意の位置にdataを書く。ページ単位でしか書けないので残りは0xFFで埋める
+// (NORフラッシュは0xFFを書いても中身が変わらないので、書き込み済みのページに追記できる)
+pub fn program(offset: u32, data: &[u8]) -> Result<(), FlashError> {
+    if offset as usize + data.len() > FLASH_SIZE as usize {
+        return Err(FlashError::OutOfRange);
+    }
+    let mut addr = offset as usize;
+    let mut rest = data;
+    while !rest.is_empty() {
+        let page_start = addr - addr % PAGE_SIZE;
+        let start = addr - page_start;
+        let len = rest.len().min(PAGE_SIZE - start);
+        let mut page = [0xFFu8; PAGE_SIZE];
+        page[start..start + len].copy_from_slice(&rest[..len]);
+        erase_and_program(page_start as u32, 0, &page)?;
+        addr += len;
+        rest = &rest[len..];
+    }
+    Ok(())
+}
+
+// XIP経由で読む
+pub fn read(offset: u32, len: usize) -> &'static [u8] {
+    unsafe { core::slice::from_raw_parts((FLASH_XIP_BASE + offset) as *const u8, len) }
+}
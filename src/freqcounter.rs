@@ -0,0 +1,348 @@
+// PWMスライスのBピン入力を使った周波数カウンタ
+// 立ち上がりエッジ数を数えるモードとHighの間だけクロックを数えるモードを交互に切り替え、
+// ゲート時間ごとに周波数とデューティー比を求める
+// 16bitのカウンタは入力が約6.5MHzを超えると10msの読み出しの間に一周してしまうので、
+// 一周するたびにPWM_IRQ_WRAPで数えておき、読み出すときに上位に足す
+use crate::command::{ok_reply, write_milli, Args, CommandError, Reply};
+use crate::globals::{MAX_MESSAGE_SIZE, PWM};
+use crate::pwm::{slice_of, PwmChannel, PwmPin};
+use crate::timer::now_us;
+use core::fmt::Write;
+use cortex_m::interrupt;
+use heapless::String;
+use rp_pico::hal::pac;
+
+pub const DEFAULT_GATE_MS: u32 = 100;
+pub const MIN_GATE_MS: u32 = 10;
+pub const MAX_GATE_MS: u32 = 10_000;
+// Highレベル計測時の分周 (125MHzなら500kHzで数える)
+pub const LEVEL_DIV: u8 = 250;
+// 1周のカウント数 (TOP=0xFFFF)
+const WRAP_COUNTS: u32 = 0x1_0000;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct FreqResult {
+    pub freq_millihz: u64,
+    pub duty_millipercent: u32,
+}
+
+pub fn freq_millihz(edges: u32, elapsed_us: u32) -> u64 {
+    if elapsed_us == 0 {
+        return 0;
+    }
+    edges as u64 * 1_000_000_000 / elapsed_us as u64
+}
+
+pub fn duty_millipercent(high_counts: u32, elapsed_us: u32, count_hz: u32) -> u32 {
+    let total = elapsed_us as u64 * count_hz as u64 / 1_000_000;
+    if total == 0 {
+        return 0;
+    }
+    (high_counts as u64 * 100_000 / total).min(100_000) as u32
+}
+
+pub fn write_result(out: &mut impl Write, gpio: u8, result: &FreqResult) -> core::fmt::Result {
+    write!(
+        out,
+        " freq gpio={} hz={}.{:03} duty=",
+        gpio,
+        result.freq_millihz / 1000,
+        result.freq_millihz % 1000
+    )?;
+    write_milli(out, result.duty_millipercent as i32)
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum FreqCommand {
+    Start {
+        gpio: u8,
+        gate_ms: u32,
+        stream: bool,
+    },
+    Read,
+    Stop,
+}
+
+impl FreqCommand {
+    pub fn parse(args: &mut Args) -> Result<Self, CommandError> {
+        match args.next_str()? {
+            "start" => {
+                let gpio = u8::try_from(args.next_u32()?).map_err(|_| CommandError::OutOfRange)?;
+                // 入力はスライスのBチャンネル(奇数GPIO)にしか繋がらない
+                if gpio >= 30 || slice_of(gpio).1 != PwmChannel::B {
+                    return Err(CommandError::OutOfRange);
+                }
+                let mut gate_ms = DEFAULT_GATE_MS;
+                let mut stream = false;
+                while let Some(key) = args.next_opt() {
+                    match key {
+                        "gate" => gate_ms = args.next_u32()?,
+                        "stream" => stream = true,
+                        _ => return Err(CommandError::InvalidArgument),
+                    }
+                }
+                if !(MIN_GATE_MS..=MAX_GATE_MS).contains(&gate_ms) {
+                    return Err(CommandError::OutOfRange);
+                }
+                Ok(FreqCommand::Start {
+                    gpio,
+                    gate_ms,
+                    stream,
+                })
+            }
+            "read" => Ok(FreqCommand::Read),
+            "stop" => Ok(FreqCommand::Stop),
+            _ => Err(CommandError::InvalidArgument),
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum Phase {
+    Edges,
+    High,
+}
+
+pub struct FreqCounter {
+    pub(crate) slice: usize,
+    pub(crate) pin: PwmPin,
+    gate_us: u32,
+    stream: bool,
+    phase: Phase,
+    start_us: u32,
+    // このフェーズでカウンタが一周した回数
+    wraps: u32,
+    freq_millihz: u64,
+    last: Option<FreqResult>,
+}
+
+impl FreqCounter {
+    pub(crate) fn new(slice: usize, pin: PwmPin, gate_ms: u32, stream: bool) -> Self {
+        Self {
+            slice,
+            pin,
+            gate_us: gate_ms * 1000,
+            stream,
+            phase: Phase::Edges,
+            start_us: 0,
+            wraps: 0,
+            freq_millihz: 0,
+            last: None,
+        }
+    }
+
+    pub fn gpio(&self) -> u8 {
+        self.pin.id().num
+    }
+
+    pub fn last(&self) -> Option<FreqResult> {
+        self.last
+    }
+
+    fn begin_phase(&mut self, pwm: &pac::PWM, phase: Phase) {
+        let regs = pwm.ch(self.slice);
+        regs.csr().modify(|_, w| w.en().clear_bit());
+        let div = match phase {
+            Phase::Edges => 1,
+            Phase::High => LEVEL_DIV,
+        };
+        regs.div()
+            .write(|w| unsafe { w.int().bits(div).frac().bits(0) });
+        regs.top().write(|w| unsafe { w.top().bits(0xFFFF) });
+        regs.ctr().write(|w| unsafe { w.ctr().bits(0) });
+        pwm.intr().write(|w| unsafe { w.bits(self.wrap_bit()) });
+        regs.csr().modify(|_, w| {
+            let w = w.ph_correct().clear_bit();
+            match phase {
+                Phase::Edges => w.divmode().rise(),
+                Phase::High => w.divmode().level(),
+            }
+            .en()
+            .set_bit()
+        });
+        self.phase = phase;
+        self.start_us = now_us();
+        self.wraps = 0;
+    }
+
+    fn wrap_bit(&self) -> u32 {
+        1 << self.slice
+    }
+
+    pub(crate) fn start(&mut self, pwm: &pac::PWM) {
+        self.begin_phase(pwm, Phase::Edges);
+        pwm.inte()
+            .modify(|r, w| unsafe { w.bits(r.bits() | self.wrap_bit()) });
+        unsafe { pac::NVIC::unmask(pac::Interrupt::PWM_IRQ_WRAP) };
+    }
+
+    pub(crate) fn stop(&mut self, pwm: &pac::PWM) {
+        let regs = pwm.ch(self.slice);
+        regs.csr().modify(|_, w| w.en().clear_bit().divmode().div());
+        regs.ctr().write(|w| unsafe { w.ctr().bits(0) });
+        pwm.inte()
+            .modify(|r, w| unsafe { w.bits(r.bits() & !self.wrap_bit()) });
+        pwm.intr().write(|w| unsafe { w.bits(self.wrap_bit()) });
+    }
+
+    // 一周していたらフラグを消して数える。PWM_IRQ_WRAPと読み出しの両方から呼ぶ
+    pub(crate) fn take_wrap(&mut self, pwm: &pac::PWM) -> bool {
+        if pwm.ints().read().bits() & self.wrap_bit() == 0 {
+            return false;
+        }
+        pwm.intr().write(|w| unsafe { w.bits(self.wrap_bit()) });
+        self.wraps += 1;
+        true
+    }
+
+    // フェーズの開始からのカウント数
+    fn count(&mut self, pwm: &pac::PWM) -> u32 {
+        let regs = pwm.ch(self.slice);
+        let mut ctr = regs.ctr().read().ctr().bits();
+        // 割り込みを止めている間に一周していたら、読んだ値が一周の前か後か分からないので読み直す
+        if self.take_wrap(pwm) {
+            ctr = regs.ctr().read().ctr().bits();
+        }
+        self.wraps * WRAP_COUNTS + ctr as u32
+    }
+
+    // core0の10ms割り込みから呼ぶ。ストリーム中なら結果の行を返す
+    pub(crate) fn tick(&mut self, pwm: &pac::PWM, sys_hz: u32) -> Option<String<MAX_MESSAGE_SIZE>> {
+        let elapsed = now_us().wrapping_sub(self.start_us);
+        if elapsed < self.gate_us {
+            return None;
+        }
+        let count = self.count(pwm);
+        match self.phase {
+            Phase::Edges => {
+                self.freq_millihz = freq_millihz(count, elapsed);
+                self.begin_phase(pwm, Phase::High);
+                None
+            }
+            Phase::High => {
+                let result = FreqResult {
+                    freq_millihz: self.freq_millihz,
+                    duty_millipercent: duty_millipercent(count, elapsed, sys_hz / LEVEL_DIV as u32),
+                };
+                self.last = Some(result);
+                self.begin_phase(pwm, Phase::Edges);
+                if !self.stream {
+                    return None;
+                }
+                let mut line = String::new();
+                let _ = line.push_str("DATA");
+                let _ = write_result(&mut line, self.gpio(), &result);
+                Some(line)
+            }
+        }
+    }
+}
+
+pub fn handle_command(args: &mut Args) -> Result<Reply, CommandError> {
+    let cmd = FreqCommand::parse(args)?;
+    interrupt::free(|cs| {
+        let mut pwm = PWM.borrow(cs).borrow_mut();
+        let pwm = pwm.as_mut().ok_or(CommandError::NotReady)?;
+        match cmd {
+            FreqCommand::Start {
+                gpio,
+                gate_ms,
+                stream,
+            } => {
+                pwm.start_counter(gpio, gate_ms, stream)?;
+                Ok(ok_reply(|r| {
+                    write!(r, " freq start gpio={} gate_ms={}", gpio, gate_ms)
+                }))
+            }
+            FreqCommand::Read => {
+                let counter = pwm.counter().ok_or(CommandError::NotReady)?;
+                let result = counter.last().ok_or(CommandError::NotReady)?;
+                let gpio = counter.gpio();
+                Ok(ok_reply(|r| write_result(r, gpio, &result)))
+            }
+            FreqCommand::Stop => {
+                pwm.stop_counter()?;
+                Ok(ok_reply(|r| r.write_str(" freq stop")))
+            }
+        }
+    })
+}
+
+// PWM_IRQ_WRAPから呼ぶ
+pub fn handle_wrap_irq() {
+    interrupt::free(|cs| {
+        if let Some(pwm) = PWM.borrow(cs).borrow_mut().as_mut() {
+            pwm.wrap_counter();
+        }
+    });
+}
+
+// core0の10ms割り込みから呼ぶ
+pub fn poll() -> Option<String<MAX_MESSAGE_SIZE>> {
+    interrupt::free(|cs| PWM.borrow(cs).borrow_mut().as_mut()?.tick_counter())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn parse(line: &str) -> Result<FreqCommand, CommandError> {
+        FreqCommand::parse(&mut Args::new(line))
+    }
+
+    #[test]
+    fn frequency_from_edges() {
+        assert_eq!(freq_millihz(1_000, 100_000), 10_000_000);
+        assert_eq!(freq_millihz(7, 1_000_000), 7_000);
+        assert_eq!(freq_millihz(1, 3), 333_333_333);
+        assert_eq!(freq_millihz(5, 0), 0);
+        // 10秒ゲートで62.5MHz (カウンタは何周もしている)
+        assert_eq!(freq_millihz(625_000_000, 10_000_000), 62_500_000_000);
+        assert_eq!(freq_millihz(3 * WRAP_COUNTS + 100, 10_000), 19_670_800_000);
+    }
+
+    #[test]
+    fn duty_from_high_counts() {
+        assert_eq!(duty_millipercent(25_000, 100_000, 500_000), 50_000);
+        assert_eq!(duty_millipercent(1, 100_000, 500_000), 2);
+        assert_eq!(duty_millipercent(0, 100_000, 500_000), 0);
+        // 丸めでゲート時間より多く数えても100%まで
+        assert_eq!(duty_millipercent(50_001, 100_000, 500_000), 100_000);
+        assert_eq!(duty_millipercent(10, 0, 500_000), 0);
+    }
+
+    #[test]
+    fn parses_commands() {
+        assert_eq!(
+            parse("start 7"),
+            Ok(FreqCommand::Start {
+                gpio: 7,
+                gate_ms: DEFAULT_GATE_MS,
+                stream: false
+            })
+        );
+        assert_eq!(
+            parse("start 9 gate 500 stream"),
+            Ok(FreqCommand::Start {
+                gpio: 9,
+                gate_ms: 500,
+                stream: true
+            })
+        );
+        assert_eq!(parse("read"), Ok(FreqCommand::Read));
+        assert_eq!(parse("stop"), Ok(FreqCommand::Stop));
+    }
+
+    #[test]
+    fn rejects_bad_commands() {
+        // Aチャンネルやbank0の外のピン
+        assert_eq!(parse("start 6"), Err(CommandError::OutOfRange));
+        assert_eq!(parse("start 31"), Err(CommandError::OutOfRange));
+        assert_eq!(parse("start 257"), Err(CommandError::OutOfRange));
+        assert_eq!(parse("start 7 gate 9"), Err(CommandError::OutOfRange));
+        assert_eq!(parse("start 7 gate 10001"), Err(CommandError::OutOfRange));
+        assert_eq!(parse("start 7 fast"), Err(CommandError::InvalidArgument));
+        assert_eq!(parse("start"), Err(CommandError::MissingArgument));
+        assert_eq!(parse("reset"), Err(CommandError::InvalidArgument));
+    }
+}
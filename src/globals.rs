@@ -2,19 +2,42 @@ use core::cell::RefCell;
 use cortex_m::interrupt::Mutex;
 use rp_pico as bsp;
 // use sparkfun_pro_micro_rp2040 as bsp;
+use crate::adc::AdcState;
+use crate::bootinfo::BootInfo;
+use crate::capture::CaptureState;
+use crate::config::ConfigState;
+use crate::crashlog::CrashReport;
+use crate::crosscore::CrossCoreShared;
+#[cfg(feature = "hid")]
+use crate::hid::HidKeyboard;
+use crate::i2c::I2cState;
+use crate::kv::FlashRegion;
+use crate::kvstore::KvStore;
+#[cfg(feature = "midi")]
+use crate::midi::{MidiClass, MidiState};
+use crate::pinpool::PinPool;
+use crate::pwm::PwmState;
+use crate::reboot::RebootState;
+use crate::spi::SpiState;
+use crate::spinlock::{Alarm0Lock, Alarm1Lock, Alarm2Lock, Alarm3Lock, LedLock};
+use crate::uartbridge::UartBridgeState;
 use crate::usb::UsbMessageReciver;
+use crate::vendor::VendorClass;
 use bsp::hal::{
     gpio::{bank0::Gpio25, FunctionSio, Pin, PullDown, SioOutput},
     multicore::Stack,
+    pac,
     timer::{Alarm0, Alarm1, Alarm2, Alarm3},
+    watchdog::Watchdog,
 };
 use usb_device::prelude::*;
 use usbd_serial::SerialPort;
 // Sharedは同一コア内での割り込みには安全ですが、異なるコア間での共有はできません
-// 異なるコア間で共有したい場合はrp2040_halのハードウェアspinlockを使います
+// 異なるコア間で共有したい場合はハードウェアspinlockを取るCrossCoreSharedを使います
 pub type Shared<T> = Mutex<RefCell<Option<T>>>;
-pub static LED_PIN: Shared<Pin<Gpio25, FunctionSio<SioOutput>, PullDown>> =
-    Mutex::new(RefCell::new(None));
+// core0で初期化し、core1の100ms割り込みで点滅させる
+pub static LED_PIN: CrossCoreShared<Pin<Gpio25, FunctionSio<SioOutput>, PullDown>, LedLock> =
+    CrossCoreShared::new();
 
 pub static USB_DEV: Shared<UsbDevice<'static, bsp::hal::usb::UsbBus>> =
     Mutex::new(RefCell::new(None));
@@ -22,10 +45,50 @@ pub static USB_DEV: Shared<UsbDevice<'static, bsp::hal::usb::UsbBus>> =
 pub static SERIAL: Shared<SerialPort<'static, bsp::hal::usb::UsbBus>> =
     Mutex::new(RefCell::new(None));
 pub static USB_RECIEVER: Shared<UsbMessageReciver> = Mutex::new(RefCell::new(None));
+// UARTへ中継する2つ目のシリアル
+pub static BRIDGE_SERIAL: Shared<SerialPort<'static, bsp::hal::usb::UsbBus>> =
+    Mutex::new(RefCell::new(None));
+// バイナリ転送用のベンダー固有バルクインターフェース
+pub static VENDOR: Shared<VendorClass<'static, bsp::hal::usb::UsbBus>> =
+    Mutex::new(RefCell::new(None));
+// `*type` で文字列を打ち込むHIDキーボード
+#[cfg(feature = "hid")]
+pub static KEYBOARD: Shared<HidKeyboard<'static, bsp::hal::usb::UsbBus>> =
+    Mutex::new(RefCell::new(None));
+// GPIO/ADCとノート/CCを対応付けるUSB MIDI
+#[cfg(feature = "midi")]
+pub static MIDI_CLASS: Shared<MidiClass<'static, bsp::hal::usb::UsbBus>> =
+    Mutex::new(RefCell::new(None));
+#[cfg(feature = "midi")]
+pub static MIDI: Shared<MidiState> = Mutex::new(RefCell::new(None));
+
+// フラッシュに保存した設定とフラッシュのユニークID
+pub static CONFIG: Shared<ConfigState> = Mutex::new(RefCell::new(None));
+// フラッシュ上のキー・バリューストア。マウントに失敗したらNone
+pub static KV: Shared<KvStore<FlashRegion>> = Mutex::new(RefCell::new(None));
+
+pub static PIN_POOL: Shared<PinPool> = Mutex::new(RefCell::new(None));
+pub static ADC: Shared<AdcState> = Mutex::new(RefCell::new(None));
+pub static CAPTURE: Shared<CaptureState> = Mutex::new(RefCell::new(None));
+pub static PWM: Shared<PwmState> = Mutex::new(RefCell::new(None));
+pub static I2C: Shared<I2cState> = Mutex::new(RefCell::new(None));
+pub static SPI: Shared<SpiState> = Mutex::new(RefCell::new(None));
+pub static UART_BRIDGE: Shared<UartBridgeState> = Mutex::new(RefCell::new(None));
+// 初期化後もコマンドからペリフェラルを起動・停止するのでRESETSを残しておく
+pub static RESETS: Shared<pac::RESETS> = Mutex::new(RefCell::new(None));
+// クロック初期化の後はウォッチドッグによる再起動に使う
+pub static WATCHDOG: Shared<Watchdog> = Mutex::new(RefCell::new(None));
+// `reboot` のトークンと実行予定
+pub static REBOOT: Shared<RebootState> = Mutex::new(RefCell::new(None));
+// 起動時に調べた起動理由と起動回数
+pub static BOOT_INFO: Shared<BootInfo> = Mutex::new(RefCell::new(None));
+// 前回の起動で起きたパニックかHardFault
+pub static LAST_CRASH: Shared<CrashReport> = Mutex::new(RefCell::new(None));
 
-pub static ALARM0: Shared<Alarm0> = Mutex::new(RefCell::new(None));
-pub static ALARM1: Shared<Alarm1> = Mutex::new(RefCell::new(None));
-pub static ALARM2: Shared<Alarm2> = Mutex::new(RefCell::new(None));
-pub static ALARM3: Shared<Alarm3> = Mutex::new(RefCell::new(None));
+// Alarmはcore0で作り、Alarm0/1はcore0、Alarm2/3はcore1の割り込みで使う
+pub static ALARM0: CrossCoreShared<Alarm0, Alarm0Lock> = CrossCoreShared::new();
+pub static ALARM1: CrossCoreShared<Alarm1, Alarm1Lock> = CrossCoreShared::new();
+pub static ALARM2: CrossCoreShared<Alarm2, Alarm2Lock> = CrossCoreShared::new();
+pub static ALARM3: CrossCoreShared<Alarm3, Alarm3Lock> = CrossCoreShared::new();
 pub static mut CORE1_STACK: Stack<4096> = Stack::new();
 pub const MAX_MESSAGE_SIZE: usize = 256; // 最大メッセージサイズ
@@ -0,0 +1,117 @@
+// ヒープと使用量の統計
+// ヒープはmemory.xの.heapセクションに置き、大きさはリンカシンボル _heap_size で変えられる
+// (例: RUSTFLAGS="-C link-arg=--defsym=_heap_size=8192")
+// 確保に失敗したら回数と大きさを記録してログに出す。その後はallocの既定の処理でパニックになり、
+// crashlogに記録されてリセットされる。リセットで統計は消えるので、失敗した大きさはcrashlogにも渡し、
+// 次の起動のPANIC行に oom=<バイト数> として出す
+use crate::command::{ok_reply, Args, CommandError, Reply};
+use core::alloc::{GlobalAlloc, Layout};
+use core::fmt::Write;
+use core::ptr::addr_of;
+use core::sync::atomic::{AtomicUsize, Ordering};
+use cortex_m::interrupt;
+use embedded_alloc::LlffHeap;
+
+extern "C" {
+    static __heap_start: u8;
+    static __heap_end: u8;
+}
+
+pub struct TrackingHeap {
+    heap: LlffHeap,
+    peak: AtomicUsize,
+    failures: AtomicUsize,
+    last_failed_size: AtomicUsize,
+}
+
+// ホストのテストではstdのアロケータを使う
+#[cfg_attr(not(test), global_allocator)]
+static HEAP: TrackingHeap = TrackingHeap {
+    heap: LlffHeap::empty(),
+    peak: AtomicUsize::new(0),
+    failures: AtomicUsize::new(0),
+    last_failed_size: AtomicUsize::new(0),
+};
+
+unsafe impl GlobalAlloc for TrackingHeap {
+    unsafe fn alloc(&self, layout: Layout) -> *mut u8 {
+        let ptr = self.heap.alloc(layout);
+        // thumbv6mにはfetch_maxが無いので割り込みを止めて更新する
+        interrupt::free(|_| {
+            if ptr.is_null() {
+                self.failures
+                    .store(self.failures.load(Ordering::Relaxed) + 1, Ordering::Relaxed);
+                self.last_failed_size
+                    .store(layout.size(), Ordering::Relaxed);
+            } else {
+                let used = self.heap.used();
+                if used > self.peak.load(Ordering::Relaxed) {
+                    self.peak.store(used, Ordering::Relaxed);
+                }
+            }
+        });
+        if ptr.is_null() {
+            crate::crashlog::note_alloc_failure(layout.size());
+            defmt::error!(
+                "heap exhausted: {} bytes requested, {} free",
+                layout.size(),
+                self.heap.free()
+            );
+        }
+        ptr
+    }
+
+    unsafe fn dealloc(&self, ptr: *mut u8, layout: Layout) {
+        self.heap.dealloc(ptr, layout)
+    }
+}
+
+fn region() -> (usize, usize) {
+    let start = addr_of!(__heap_start) as usize;
+    (start, addr_of!(__heap_end) as usize - start)
+}
+
+// main.rsの最初に一度だけ呼ぶ
+pub fn init() {
+    let (start, size) = region();
+    unsafe { HEAP.heap.init(start, size) }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct HeapStats {
+    pub size: usize,
+    pub used: usize,
+    pub free: usize,
+    pub peak: usize,
+    pub failures: usize,
+    pub last_failed_size: usize,
+}
+
+pub fn stats() -> HeapStats {
+    HeapStats {
+        size: region().1,
+        used: HEAP.heap.used(),
+        free: HEAP.heap.free(),
+        peak: HEAP.peak.load(Ordering::Relaxed),
+        failures: HEAP.failures.load(Ordering::Relaxed),
+        last_failed_size: HEAP.last_failed_size.load(Ordering::Relaxed),
+    }
+}
+
+pub fn handle_command(args: &mut Args) -> Result<Reply, CommandError> {
+    if args.next_opt().is_some() {
+        return Err(CommandError::InvalidArgument);
+    }
+    let stats = stats();
+    Ok(ok_reply(|r| {
+        write!(
+            r,
+            " heap size={} used={} free={} peak={} failures={}",
+            stats.size, stats.used, stats.free, stats.peak, stats.failures
+        )?;
+        if stats.failures > 0 {
+            write!(r, " last_failed={}", stats.last_failed_size)?;
+        }
+        Ok(())
+    }))
+}
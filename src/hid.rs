@@ -0,0 +1,285 @@
+// HIDキーボード (ブートプロトコル互換) のUSBクラスと `*type` コマンド
+// cargoのhidフィーチャーを有効にしたときだけインターフェースを追加する
+// 打ち込む内容はkeymapでステップ列にしてqueueに積み、USBポーリングごとに1レポートずつ送る
+use crate::command::{ok_reply, CommandError, Reply};
+use crate::globals::{KEYBOARD, MAX_MESSAGE_SIZE};
+use crate::keymap::{self, key_report, MacroError, MacroStep};
+use crate::timer::now_us;
+use core::fmt::Write;
+use cortex_m::interrupt;
+use heapless::Deque;
+use usb_device::class_prelude::*;
+use usb_device::control::{Recipient, Request, RequestType};
+use usb_device::Result;
+
+const USB_CLASS_HID: u8 = 0x03;
+const HID_SUBCLASS_BOOT: u8 = 0x01;
+const HID_PROTOCOL_KEYBOARD: u8 = 0x01;
+const DESCRIPTOR_TYPE_HID: u8 = 0x21;
+const DESCRIPTOR_TYPE_REPORT: u8 = 0x22;
+const REQ_GET_REPORT: u8 = 0x01;
+const REQ_GET_IDLE: u8 = 0x02;
+const REQ_GET_PROTOCOL: u8 = 0x03;
+const REQ_SET_REPORT: u8 = 0x09;
+const REQ_SET_IDLE: u8 = 0x0A;
+const REQ_SET_PROTOCOL: u8 = 0x0B;
+const REPORT_LEN: u16 = 8;
+// USBポーリング間隔に合わせる
+const POLL_INTERVAL_MS: u8 = 2;
+pub const INTERFACE_NAME: &str = "RP2040 Keyboard";
+
+// HID 1.11 Appendix B.1 のブートキーボード
+const REPORT_DESCRIPTOR: [u8; 63] = [
+    0x05, 0x01, // Usage Page (Generic Desktop)
+    0x09, 0x06, // Usage (Keyboard)
+    0xA1, 0x01, // Collection (Application)
+    0x05, 0x07, //   Usage Page (Key Codes)
+    0x19, 0xE0, //   Usage Minimum (224)
+    0x29, 0xE7, //   Usage Maximum (231)
+    0x15, 0x00, //   Logical Minimum (0)
+    0x25, 0x01, //   Logical Maximum (1)
+    0x75, 0x01, //   Report Size (1)
+    0x95, 0x08, //   Report Count (8)
+    0x81, 0x02, //   Input (Data, Variable, Absolute) 修飾キー
+    0x95, 0x01, //   Report Count (1)
+    0x75, 0x08, //   Report Size (8)
+    0x81, 0x01, //   Input (Constant) 予約
+    0x95, 0x05, //   Report Count (5)
+    0x75, 0x01, //   Report Size (1)
+    0x05, 0x08, //   Usage Page (LEDs)
+    0x19, 0x01, //   Usage Minimum (1)
+    0x29, 0x05, //   Usage Maximum (5)
+    0x91, 0x02, //   Output (Data, Variable, Absolute) LED
+    0x95, 0x01, //   Report Count (1)
+    0x75, 0x03, //   Report Size (3)
+    0x91, 0x01, //   Output (Constant) パディング
+    0x95, 0x06, //   Report Count (6)
+    0x75, 0x08, //   Report Size (8)
+    0x15, 0x00, //   Logical Minimum (0)
+    0x25, 0x65, //   Logical Maximum (101)
+    0x05, 0x07, //   Usage Page (Key Codes)
+    0x19, 0x00, //   Usage Minimum (0)
+    0x29, 0x65, //   Usage Maximum (101)
+    0x81, 0x00, //   Input (Data, Array) キーコード
+    0xC0, // End Collection
+];
+
+const HID_DESCRIPTOR: [u8; 7] = [
+    0x11,
+    0x01, // bcdHID 1.11
+    0x00, // bCountryCode
+    0x01, // bNumDescriptors
+    DESCRIPTOR_TYPE_REPORT,
+    REPORT_DESCRIPTOR.len() as u8,
+    0x00,
+];
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum Phase {
+    Idle,
+    // 押下レポートを送ったので次は離すレポート
+    Pressed,
+    Waiting { since_us: u32, wait_us: u32 },
+}
+
+pub struct HidKeyboard<'a, B: UsbBus> {
+    iface: InterfaceNumber,
+    name: StringIndex,
+    write_ep: EndpointIn<'a, B>,
+    report: [u8; REPORT_LEN as usize],
+    idle: u8,
+    protocol: u8,
+    // ホストから来たNumLock等のLED状態
+    leds: u8,
+    queue: Deque<MacroStep, MAX_MESSAGE_SIZE>,
+    phase: Phase,
+}
+
+impl<'a, B: UsbBus> HidKeyboard<'a, B> {
+    pub fn new(alloc: &'a UsbBusAllocator<B>) -> Self {
+        Self {
+            iface: alloc.interface(),
+            name: alloc.string(),
+            write_ep: alloc.interrupt(REPORT_LEN, POLL_INTERVAL_MS),
+            report: [0; REPORT_LEN as usize],
+            idle: 0,
+            protocol: 1,
+            leds: 0,
+            queue: Deque::new(),
+            phase: Phase::Idle,
+        }
+    }
+
+    pub fn leds(&self) -> u8 {
+        self.leds
+    }
+
+    pub fn pending(&self) -> usize {
+        self.queue.len()
+    }
+
+    pub fn type_text(&mut self, text: &str) -> core::result::Result<usize, MacroError> {
+        keymap::encode(text, &mut self.queue)
+    }
+
+    pub fn cancel(&mut self) {
+        self.queue.clear();
+        self.phase = Phase::Idle;
+        // 押しっぱなしにならないよう離すレポートを送っておく
+        self.send(0, 0);
+    }
+
+    fn send(&mut self, modifiers: u8, keycode: u8) -> bool {
+        let report = key_report(modifiers, keycode);
+        if self.write_ep.write(&report).is_ok() {
+            self.report = report;
+            true
+        } else {
+            false
+        }
+    }
+
+    // USBポーリングの後に呼ぶ。エンドポイントが空いていれば次のレポートを送る
+    pub fn pump(&mut self) {
+        match self.phase {
+            Phase::Idle => match self.queue.front().copied() {
+                Some(MacroStep::Key { modifiers, keycode }) => {
+                    if self.send(modifiers, keycode) {
+                        self.queue.pop_front();
+                        self.phase = Phase::Pressed;
+                    }
+                }
+                Some(MacroStep::Delay { ms }) => {
+                    self.queue.pop_front();
+                    self.phase = Phase::Waiting {
+                        since_us: now_us(),
+                        wait_us: ms * 1000,
+                    };
+                }
+                None => {}
+            },
+            Phase::Pressed => {
+                if self.send(0, 0) {
+                    self.phase = Phase::Idle;
+                }
+            }
+            Phase::Waiting { since_us, wait_us } => {
+                if now_us().wrapping_sub(since_us) >= wait_us {
+                    self.phase = Phase::Idle;
+                }
+            }
+        }
+    }
+
+    fn is_for_me(&self, req: &Request) -> bool {
+        req.recipient == Recipient::Interface && req.index == u8::from(self.iface) as u16
+    }
+}
+
+impl<B: UsbBus> UsbClass<B> for HidKeyboard<'_, B> {
+    fn get_configuration_descriptors(&self, writer: &mut DescriptorWriter) -> Result<()> {
+        writer.interface_alt(
+            self.iface,
+            0,
+            USB_CLASS_HID,
+            HID_SUBCLASS_BOOT,
+            HID_PROTOCOL_KEYBOARD,
+            Some(self.name),
+        )?;
+        writer.write(DESCRIPTOR_TYPE_HID, &HID_DESCRIPTOR)?;
+        writer.endpoint(&self.write_ep)?;
+        Ok(())
+    }
+
+    fn get_string(&self, index: StringIndex, _lang_id: LangID) -> Option<&str> {
+        (index == self.name).then_some(INTERFACE_NAME)
+    }
+
+    fn reset(&mut self) {
+        self.protocol = 1;
+        self.phase = Phase::Idle;
+    }
+
+    fn control_in(&mut self, xfer: ControlIn<B>) {
+        let req = *xfer.request();
+        if !self.is_for_me(&req) {
+            return;
+        }
+        match (req.request_type, req.request) {
+            (RequestType::Standard, Request::GET_DESCRIPTOR) => match (req.value >> 8) as u8 {
+                DESCRIPTOR_TYPE_REPORT => {
+                    let _ = xfer.accept_with_static(&REPORT_DESCRIPTOR);
+                }
+                DESCRIPTOR_TYPE_HID => {
+                    let mut desc = [0u8; 9];
+                    desc[0] = 9;
+                    desc[1] = DESCRIPTOR_TYPE_HID;
+                    desc[2..].copy_from_slice(&HID_DESCRIPTOR);
+                    let _ = xfer.accept_with(&desc);
+                }
+                _ => {}
+            },
+            (RequestType::Class, REQ_GET_REPORT) => {
+                let report = self.report;
+                let _ = xfer.accept_with(&report);
+            }
+            (RequestType::Class, REQ_GET_IDLE) => {
+                let _ = xfer.accept_with(&[self.idle]);
+            }
+            (RequestType::Class, REQ_GET_PROTOCOL) => {
+                let _ = xfer.accept_with(&[self.protocol]);
+            }
+            _ => {}
+        }
+    }
+
+    fn control_out(&mut self, xfer: ControlOut<B>) {
+        let req = *xfer.request();
+        if !self.is_for_me(&req) || req.request_type != RequestType::Class {
+            return;
+        }
+        match req.request {
+            REQ_SET_REPORT => {
+                if let Some(&leds) = xfer.data().first() {
+                    self.leds = leds;
+                }
+                let _ = xfer.accept();
+            }
+            REQ_SET_IDLE => {
+                self.idle = (req.value >> 8) as u8;
+                let _ = xfer.accept();
+            }
+            REQ_SET_PROTOCOL => {
+                self.protocol = (req.value & 0xFF) as u8;
+                let _ = xfer.accept();
+            }
+            _ => {}
+        }
+    }
+}
+
+fn macro_error(err: MacroError) -> CommandError {
+    match err {
+        MacroError::TooLong => CommandError::Busy,
+        MacroError::InvalidDelay => CommandError::OutOfRange,
+        MacroError::UnknownKey | MacroError::Unterminated => CommandError::InvalidArgument,
+    }
+}
+
+// `*type <文字列>` の文字列は空白も含めてそのまま受け取る
+// `*type` だけなら打ち込み中の内容を取り消す
+pub fn handle_type(text: &str) -> core::result::Result<Reply, CommandError> {
+    interrupt::free(|cs| {
+        let mut keyboard = KEYBOARD.borrow(cs).borrow_mut();
+        let keyboard = keyboard.as_mut().ok_or(CommandError::NotReady)?;
+        if text.is_empty() {
+            keyboard.cancel();
+            return Ok(ok_reply(|r| r.write_str(" type cancel")));
+        }
+        let steps = keyboard.type_text(text).map_err(macro_error)?;
+        let pending = keyboard.pending();
+        Ok(ok_reply(|r| {
+            write!(r, " type steps={} pending={}", steps, pending)
+        }))
+    })
+}
@@ -0,0 +1,693 @@
+// I2C0/I2C1をホストから操作するブリッジ
+// SDA/SCLはピンプールから借りる。RP2040ではGPIO番号%4が0/1ならI2C0、2/3ならI2C1のSDA/SCLになる
+// 転送部分はembedded_hal::i2c::I2cに対してジェネリックなので、モックを渡せばホスト上でも動かせる
+use crate::command::{ok_reply, parse_hex_bytes, parse_u32, write_hex, Args, CommandError, Reply};
+use crate::globals::{I2C, RESETS};
+use crate::pinpool::{self, NUM_BANK0_PINS};
+use core::fmt::Write;
+use cortex_m::interrupt;
+use embedded_hal::i2c::{Error as _, ErrorKind, I2c, NoAcknowledgeSource};
+use heapless::Vec;
+use rp_pico::hal::fugit::HertzU32;
+use rp_pico::hal::gpio::{DynPinId, FunctionI2c, Pin, PullUp};
+use rp_pico::hal::i2c::{I2cDevice, ValidatedPinScl, ValidatedPinSda, I2C as HalI2c};
+use rp_pico::hal::pac;
+
+pub const DEFAULT_KHZ: u32 = 100;
+pub const MIN_KHZ: u32 = 10;
+pub const MAX_KHZ: u32 = 1000;
+// 応答行に16進で載せられる長さに合わせる
+pub const MAX_TRANSFER: usize = 64;
+// 0x00-0x07と0x78-0x7Fは予約アドレス
+pub const FIRST_ADDRESS: u8 = 0x08;
+pub const LAST_ADDRESS: u8 = 0x77;
+const NUM_ADDRESSES: usize = (LAST_ADDRESS - FIRST_ADDRESS + 1) as usize;
+// 1回のpollで調べるアドレスの数。100kHzならNACKまで0.1ms程度なので10msの割り込みを長く止めない
+pub const SCAN_STEP: usize = 8;
+
+pub type Data = Vec<u8, MAX_TRANSFER>;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum BusId {
+    I2c0,
+    I2c1,
+}
+
+impl BusId {
+    pub fn parse(s: &str) -> Result<Self, CommandError> {
+        match s {
+            "0" => Ok(BusId::I2c0),
+            "1" => Ok(BusId::I2c1),
+            _ => Err(CommandError::InvalidArgument),
+        }
+    }
+
+    pub fn index(&self) -> u8 {
+        match self {
+            BusId::I2c0 => 0,
+            BusId::I2c1 => 1,
+        }
+    }
+
+    pub fn is_sda(&self, gpio: u8) -> bool {
+        (gpio as usize) < NUM_BANK0_PINS && gpio % 4 == self.index() * 2
+    }
+
+    pub fn is_scl(&self, gpio: u8) -> bool {
+        (gpio as usize) < NUM_BANK0_PINS && gpio % 4 == self.index() * 2 + 1
+    }
+}
+
+fn parse_address(s: &str) -> Result<u8, CommandError> {
+    let addr = parse_u32(s)?;
+    if !(FIRST_ADDRESS as u32..=LAST_ADDRESS as u32).contains(&addr) {
+        return Err(CommandError::OutOfRange);
+    }
+    Ok(addr as u8)
+}
+
+fn parse_len(s: &str) -> Result<usize, CommandError> {
+    let len = parse_u32(s)? as usize;
+    if !(1..=MAX_TRANSFER).contains(&len) {
+        return Err(CommandError::OutOfRange);
+    }
+    Ok(len)
+}
+
+fn parse_data(s: &str) -> Result<Data, CommandError> {
+    let data = parse_hex_bytes::<MAX_TRANSFER>(s)?;
+    // RP2040のI2Cは0バイトの書き込みができない
+    if data.is_empty() {
+        return Err(CommandError::InvalidArgument);
+    }
+    Ok(data)
+}
+
+// バス上の転送。ハードウェアに依存しない
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum I2cOp {
+    Write { addr: u8, data: Data },
+    Read { addr: u8, len: usize },
+    WriteRead { addr: u8, data: Data, len: usize },
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum I2cCommand {
+    Init {
+        bus: BusId,
+        sda: u8,
+        scl: u8,
+        khz: u32,
+    },
+    Deinit {
+        bus: BusId,
+    },
+    // 結果はpollからDATA行で返す
+    Scan {
+        bus: BusId,
+    },
+    Transfer {
+        bus: BusId,
+        op: I2cOp,
+    },
+}
+
+impl I2cCommand {
+    pub fn parse(args: &mut Args) -> Result<Self, CommandError> {
+        let sub = args.next_str()?;
+        let bus = BusId::parse(args.next_str()?)?;
+        match sub {
+            "init" => {
+                let sda = u8::try_from(args.next_u32()?).map_err(|_| CommandError::OutOfRange)?;
+                let scl = u8::try_from(args.next_u32()?).map_err(|_| CommandError::OutOfRange)?;
+                if !bus.is_sda(sda) || !bus.is_scl(scl) {
+                    return Err(CommandError::OutOfRange);
+                }
+                let khz = match args.next_opt() {
+                    Some(s) => parse_u32(s)?,
+                    None => DEFAULT_KHZ,
+                };
+                if !(MIN_KHZ..=MAX_KHZ).contains(&khz) {
+                    return Err(CommandError::OutOfRange);
+                }
+                Ok(I2cCommand::Init { bus, sda, scl, khz })
+            }
+            "deinit" => Ok(I2cCommand::Deinit { bus }),
+            "scan" => Ok(I2cCommand::Scan { bus }),
+            "write" => {
+                let addr = parse_address(args.next_str()?)?;
+                let data = parse_data(args.next_str()?)?;
+                Ok(I2cCommand::Transfer {
+                    bus,
+                    op: I2cOp::Write { addr, data },
+                })
+            }
+            "read" => {
+                let addr = parse_address(args.next_str()?)?;
+                let len = parse_len(args.next_str()?)?;
+                Ok(I2cCommand::Transfer {
+                    bus,
+                    op: I2cOp::Read { addr, len },
+                })
+            }
+            "wr" => {
+                let addr = parse_address(args.next_str()?)?;
+                let data = parse_data(args.next_str()?)?;
+                let len = parse_len(args.next_str()?)?;
+                Ok(I2cCommand::Transfer {
+                    bus,
+                    op: I2cOp::WriteRead { addr, data, len },
+                })
+            }
+            _ => Err(CommandError::InvalidArgument),
+        }
+    }
+}
+
+// NACKとアービトレーション負けはホスト側で区別できるように別のコードにする
+pub fn map_error(kind: ErrorKind) -> CommandError {
+    match kind {
+        ErrorKind::NoAcknowledge(NoAcknowledgeSource::Address) => CommandError::NackAddress,
+        ErrorKind::NoAcknowledge(NoAcknowledgeSource::Data) => CommandError::NackData,
+        ErrorKind::NoAcknowledge(_) => CommandError::Nack,
+        ErrorKind::ArbitrationLoss => CommandError::ArbitrationLoss,
+        _ => CommandError::BusError,
+    }
+}
+
+impl I2cOp {
+    pub fn execute<I: I2c>(&self, i2c: &mut I) -> Result<Reply, CommandError> {
+        match self {
+            I2cOp::Write { addr, data } => {
+                i2c.write(*addr, data).map_err(|e| map_error(e.kind()))?;
+                Ok(ok_reply(|r| write!(r, " i2c write n={}", data.len())))
+            }
+            I2cOp::Read { addr, len } => {
+                let mut buf = [0u8; MAX_TRANSFER];
+                i2c.read(*addr, &mut buf[..*len])
+                    .map_err(|e| map_error(e.kind()))?;
+                Ok(ok_reply(|r| {
+                    r.write_str(" i2c read ")?;
+                    write_hex(r, &buf[..*len])
+                }))
+            }
+            I2cOp::WriteRead { addr, data, len } => {
+                let mut buf = [0u8; MAX_TRANSFER];
+                i2c.write_read(*addr, data, &mut buf[..*len])
+                    .map_err(|e| map_error(e.kind()))?;
+                Ok(ok_reply(|r| {
+                    r.write_str(" i2c wr ")?;
+                    write_hex(r, &buf[..*len])
+                }))
+            }
+        }
+    }
+}
+
+// 割り込みを長く止めないよう、スキャンはpollのたびに少しずつ進める
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Scan {
+    next: u8,
+    found: Vec<u8, NUM_ADDRESSES>,
+}
+
+impl Default for Scan {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl Scan {
+    pub fn new() -> Self {
+        Self {
+            next: FIRST_ADDRESS,
+            found: Vec::new(),
+        }
+    }
+
+    // 最大count個のアドレスを調べる。全て調べ終えたらtrue
+    pub fn step<I: I2c>(&mut self, i2c: &mut I, count: usize) -> Result<bool, CommandError> {
+        for _ in 0..count {
+            if self.next > LAST_ADDRESS {
+                break;
+            }
+            let addr = self.next;
+            self.next += 1;
+            let mut buf = [0u8; 1];
+            match i2c.read(addr, &mut buf).map_err(|e| map_error(e.kind())) {
+                Ok(()) => {
+                    let _ = self.found.push(addr);
+                }
+                // アドレスにNACKが返るのはデバイスがいないだけ
+                Err(CommandError::NackAddress) | Err(CommandError::Nack) => {}
+                Err(e) => return Err(e),
+            }
+        }
+        Ok(self.next > LAST_ADDRESS)
+    }
+
+    pub fn found(&self) -> &[u8] {
+        &self.found
+    }
+}
+
+// 終わったスキャンの結果行
+pub fn write_scan_result(
+    out: &mut impl Write,
+    bus: BusId,
+    result: Result<&[u8], CommandError>,
+) -> core::fmt::Result {
+    write!(out, "DATA i2c scan bus={}", bus.index())?;
+    match result {
+        Ok(found) => {
+            for addr in found {
+                write!(out, " 0x{:02x}", addr)?;
+            }
+            Ok(())
+        }
+        Err(e) => write!(out, " ERR {}", e.code()),
+    }
+}
+
+pub type I2cPin = Pin<DynPinId, FunctionI2c, PullUp>;
+type Bus<D> = HalI2c<D, (ValidatedPinSda<I2cPin, D>, ValidatedPinScl<I2cPin, D>)>;
+
+fn take_pin(num: u8) -> Result<I2cPin, CommandError> {
+    let pin = pinpool::take(num)?
+        .try_into_function::<FunctionI2c>()
+        .map_err(|pin| {
+            pinpool::give(pin);
+            CommandError::InvalidArgument
+        })?;
+    Ok(pin.into_pull_type::<PullUp>())
+}
+
+struct ActiveBus<D: I2cDevice> {
+    bus: Bus<D>,
+    sda: u8,
+    scl: u8,
+}
+
+// 未使用時はPACのペリフェラル、初期化後はHALのI2Cとして持つ
+struct Port<D: I2cDevice> {
+    device: Option<D>,
+    active: Option<ActiveBus<D>>,
+    scan: Option<Scan>,
+}
+
+impl<D: I2cDevice> Port<D> {
+    fn new(device: D) -> Self {
+        Self {
+            device: Some(device),
+            active: None,
+            scan: None,
+        }
+    }
+
+    fn init(
+        &mut self,
+        sda: u8,
+        scl: u8,
+        khz: u32,
+        sys_hz: u32,
+        resets: &mut pac::RESETS,
+    ) -> Result<(), CommandError> {
+        // 初期化し直す場合は先に解放してピンを返す
+        self.deinit(resets);
+        let device = self.device.as_ref().ok_or(CommandError::NotReady)?;
+        let sda_pin = take_pin(sda)?;
+        let scl_pin = match take_pin(scl) {
+            Ok(pin) => pin,
+            Err(e) => {
+                pinpool::give(sda_pin);
+                return Err(e);
+            }
+        };
+        let (sda_pin, scl_pin) = match (
+            ValidatedPinSda::validate(sda_pin, device),
+            ValidatedPinScl::validate(scl_pin, device),
+        ) {
+            (Ok(sda_pin), Ok(scl_pin)) => (sda_pin, scl_pin),
+            // parseでピン番号は検査済みなので通常は起きない
+            pins => {
+                // 番号はここで借りたピンのもの
+                unsafe { pinpool::give_wrapped(pins, &[sda, scl]) };
+                return Err(CommandError::InvalidArgument);
+            }
+        };
+        let device = self.device.take().ok_or(CommandError::NotReady)?;
+        let bus = HalI2c::new_controller(
+            device,
+            sda_pin,
+            scl_pin,
+            HertzU32::kHz(khz),
+            resets,
+            HertzU32::Hz(sys_hz),
+        );
+        self.active = Some(ActiveBus { bus, sda, scl });
+        Ok(())
+    }
+
+    fn deinit(&mut self, resets: &mut pac::RESETS) -> bool {
+        // 途中のスキャンは捨てる
+        self.scan = None;
+        let Some(active) = self.active.take() else {
+            return false;
+        };
+        let (device, pins) = active.bus.free(resets);
+        // 番号はinitで借りたピンのもの
+        unsafe { pinpool::give_wrapped(pins, &[active.sda, active.scl]) };
+        self.device = Some(device);
+        true
+    }
+
+    fn execute(&mut self, op: &I2cOp) -> Result<Reply, CommandError> {
+        let active = self.active.as_mut().ok_or(CommandError::NotReady)?;
+        if self.scan.is_some() {
+            return Err(CommandError::Busy);
+        }
+        op.execute(&mut active.bus)
+    }
+
+    fn start_scan(&mut self) -> Result<(), CommandError> {
+        if self.active.is_none() {
+            return Err(CommandError::NotReady);
+        }
+        if self.scan.is_some() {
+            return Err(CommandError::Busy);
+        }
+        self.scan = Some(Scan::new());
+        Ok(())
+    }
+
+    // スキャン中ならSCAN_STEP個だけ進め、終わったら結果行を返す
+    fn poll_scan(&mut self, bus: BusId) -> Option<Reply> {
+        let active = self.active.as_mut()?;
+        let scan = self.scan.as_mut()?;
+        let result = match scan.step(&mut active.bus, SCAN_STEP) {
+            Ok(false) => return None,
+            Ok(true) => Ok(scan.found()),
+            Err(e) => Err(e),
+        };
+        let mut line = Reply::new();
+        let _ = write_scan_result(&mut line, bus, result);
+        self.scan = None;
+        Some(line)
+    }
+}
+
+pub struct I2cState {
+    i2c0: Port<pac::I2C0>,
+    i2c1: Port<pac::I2C1>,
+    sys_hz: u32,
+}
+
+impl I2cState {
+    pub fn new(i2c0: pac::I2C0, i2c1: pac::I2C1, sys_hz: u32) -> Self {
+        Self {
+            i2c0: Port::new(i2c0),
+            i2c1: Port::new(i2c1),
+            sys_hz,
+        }
+    }
+
+    pub fn execute(
+        &mut self,
+        cmd: &I2cCommand,
+        resets: &mut pac::RESETS,
+    ) -> Result<Reply, CommandError> {
+        match cmd {
+            I2cCommand::Init { bus, sda, scl, khz } => {
+                match bus {
+                    BusId::I2c0 => self.i2c0.init(*sda, *scl, *khz, self.sys_hz, resets)?,
+                    BusId::I2c1 => self.i2c1.init(*sda, *scl, *khz, self.sys_hz, resets)?,
+                }
+                Ok(ok_reply(|r| {
+                    write!(
+                        r,
+                        " i2c init bus={} sda={} scl={} khz={}",
+                        bus.index(),
+                        sda,
+                        scl,
+                        khz
+                    )
+                }))
+            }
+            I2cCommand::Deinit { bus } => {
+                let released = match bus {
+                    BusId::I2c0 => self.i2c0.deinit(resets),
+                    BusId::I2c1 => self.i2c1.deinit(resets),
+                };
+                if !released {
+                    return Err(CommandError::NotReady);
+                }
+                Ok(ok_reply(|r| write!(r, " i2c deinit bus={}", bus.index())))
+            }
+            I2cCommand::Scan { bus } => {
+                match bus {
+                    BusId::I2c0 => self.i2c0.start_scan()?,
+                    BusId::I2c1 => self.i2c1.start_scan()?,
+                }
+                Ok(ok_reply(|r| write!(r, " i2c scan bus={}", bus.index())))
+            }
+            I2cCommand::Transfer { bus, op } => match bus {
+                BusId::I2c0 => self.i2c0.execute(op),
+                BusId::I2c1 => self.i2c1.execute(op),
+            },
+        }
+    }
+
+    // I2C0を先に進め、両方同時に終わった場合のI2C1の行は次のpollで返す
+    fn poll(&mut self) -> Option<Reply> {
+        self.i2c0
+            .poll_scan(BusId::I2c0)
+            .or_else(|| self.i2c1.poll_scan(BusId::I2c1))
+    }
+}
+
+pub fn handle_command(args: &mut Args) -> Result<Reply, CommandError> {
+    let cmd = I2cCommand::parse(args)?;
+    interrupt::free(|cs| {
+        let mut i2c = I2C.borrow(cs).borrow_mut();
+        let i2c = i2c.as_mut().ok_or(CommandError::NotReady)?;
+        let mut resets = RESETS.borrow(cs).borrow_mut();
+        let resets = resets.as_mut().ok_or(CommandError::NotReady)?;
+        i2c.execute(&cmd, resets)
+    })
+}
+
+// core0の10ms割り込みから呼ぶ。スキャンが終わったら結果の行を返す
+pub fn poll() -> Option<Reply> {
+    interrupt::free(|cs| I2C.borrow(cs).borrow_mut().as_mut()?.poll())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use embedded_hal::i2c::{ErrorType, Operation};
+    use std::vec::Vec;
+
+    // レジスタ番号を書いてから読み書きする一般的なデバイス
+    struct Device {
+        addr: u8,
+        regs: [u8; 256],
+        pointer: usize,
+    }
+
+    #[derive(Default)]
+    struct MockBus {
+        devices: Vec<Device>,
+        // このアドレスへの転送はエラーにする
+        fault: Option<(u8, ErrorKind)>,
+        // 転送したアドレスの順
+        log: Vec<u8>,
+    }
+
+    impl MockBus {
+        fn with_devices(addrs: &[u8]) -> Self {
+            let devices = addrs
+                .iter()
+                .map(|&addr| Device {
+                    addr,
+                    regs: core::array::from_fn(|i| i as u8 ^ addr),
+                    pointer: 0,
+                })
+                .collect();
+            Self {
+                devices,
+                ..Default::default()
+            }
+        }
+
+        fn device(&self, addr: u8) -> &Device {
+            self.devices.iter().find(|d| d.addr == addr).unwrap()
+        }
+    }
+
+    impl ErrorType for MockBus {
+        type Error = ErrorKind;
+    }
+
+    impl I2c for MockBus {
+        fn transaction(
+            &mut self,
+            address: u8,
+            operations: &mut [Operation<'_>],
+        ) -> Result<(), Self::Error> {
+            self.log.push(address);
+            if let Some((addr, kind)) = self.fault {
+                if addr == address {
+                    return Err(kind);
+                }
+            }
+            let device = self
+                .devices
+                .iter_mut()
+                .find(|d| d.addr == address)
+                .ok_or(ErrorKind::NoAcknowledge(NoAcknowledgeSource::Address))?;
+            for op in operations {
+                match op {
+                    Operation::Write(data) => {
+                        device.pointer = data[0] as usize;
+                        for &b in &data[1..] {
+                            device.regs[device.pointer] = b;
+                            device.pointer = (device.pointer + 1) % 256;
+                        }
+                    }
+                    Operation::Read(buf) => {
+                        for b in buf.iter_mut() {
+                            *b = device.regs[device.pointer];
+                            device.pointer = (device.pointer + 1) % 256;
+                        }
+                    }
+                }
+            }
+            Ok(())
+        }
+    }
+
+    fn parse(line: &str) -> Result<I2cCommand, CommandError> {
+        I2cCommand::parse(&mut Args::new(line))
+    }
+
+    fn op(line: &str) -> I2cOp {
+        match parse(line) {
+            Ok(I2cCommand::Transfer { op, .. }) => op,
+            other => panic!("{other:?}"),
+        }
+    }
+
+    #[test]
+    fn parses_commands() {
+        assert_eq!(
+            parse("init 1 6 7"),
+            Ok(I2cCommand::Init {
+                bus: BusId::I2c1,
+                sda: 6,
+                scl: 7,
+                khz: DEFAULT_KHZ
+            })
+        );
+        assert_eq!(parse("scan 0"), Ok(I2cCommand::Scan { bus: BusId::I2c0 }));
+        assert_eq!(
+            op("wr 0 0x3c 10 2"),
+            I2cOp::WriteRead {
+                addr: 0x3C,
+                data: Data::from_slice(&[0x10]).unwrap(),
+                len: 2
+            }
+        );
+        // I2C1のピンをI2C0に使う
+        assert_eq!(parse("init 0 6 7"), Err(CommandError::OutOfRange));
+        assert_eq!(parse("init 0 4 5 5"), Err(CommandError::OutOfRange));
+        assert_eq!(parse("read 0 0x78 1"), Err(CommandError::OutOfRange));
+        assert_eq!(parse("read 0 0x3c 65"), Err(CommandError::OutOfRange));
+        assert_eq!(
+            parse("write 0 0x3c 123"),
+            Err(CommandError::InvalidArgument)
+        );
+        assert_eq!(parse("write 0 0x3c"), Err(CommandError::MissingArgument));
+        assert_eq!(parse("scan 2"), Err(CommandError::InvalidArgument));
+    }
+
+    #[test]
+    fn transfers_go_to_the_addressed_device() {
+        let mut bus = MockBus::with_devices(&[0x3C, 0x68]);
+        let reply = op("write 0 0x68 10aabb").execute(&mut bus).unwrap();
+        assert_eq!(reply.as_str(), "OK i2c write n=3");
+        assert_eq!(bus.device(0x68).regs[0x10..0x12], [0xAA, 0xBB]);
+        assert_eq!(bus.device(0x3C).regs[0x10], 0x10 ^ 0x3C);
+
+        let reply = op("wr 0 0x68 0f 3").execute(&mut bus).unwrap();
+        assert_eq!(reply.as_str(), format!("OK i2c wr {:02x}aabb", 0x0F ^ 0x68));
+        // 読み出しは続きのレジスタから
+        let reply = op("read 0 0x68 1").execute(&mut bus).unwrap();
+        assert_eq!(reply.as_str(), format!("OK i2c read {:02x}", 0x12 ^ 0x68));
+    }
+
+    #[test]
+    fn errors_are_mapped_to_distinct_codes() {
+        let mut bus = MockBus::with_devices(&[0x3C]);
+        assert_eq!(
+            op("read 0 0x50 1").execute(&mut bus),
+            Err(CommandError::NackAddress)
+        );
+        for (kind, err) in [
+            (
+                ErrorKind::NoAcknowledge(NoAcknowledgeSource::Data),
+                CommandError::NackData,
+            ),
+            (
+                ErrorKind::NoAcknowledge(NoAcknowledgeSource::Unknown),
+                CommandError::Nack,
+            ),
+            (ErrorKind::ArbitrationLoss, CommandError::ArbitrationLoss),
+            (ErrorKind::Bus, CommandError::BusError),
+            (ErrorKind::Overrun, CommandError::BusError),
+        ] {
+            bus.fault = Some((0x3C, kind));
+            assert_eq!(op("write 0 0x3c 00").execute(&mut bus), Err(err));
+        }
+    }
+
+    #[test]
+    fn scan_advances_a_few_addresses_per_step() {
+        let mut bus = MockBus::with_devices(&[0x08, 0x3C, 0x68, 0x77]);
+        let mut scan = Scan::new();
+        let mut steps = 1;
+        while !scan.step(&mut bus, SCAN_STEP).unwrap() {
+            assert_eq!(bus.log.len(), steps * SCAN_STEP);
+            steps += 1;
+        }
+        assert_eq!(steps, NUM_ADDRESSES.div_ceil(SCAN_STEP));
+        assert_eq!(scan.found(), [0x08, 0x3C, 0x68, 0x77]);
+        // 予約アドレスには触らない
+        assert_eq!(bus.log, (FIRST_ADDRESS..=LAST_ADDRESS).collect::<Vec<_>>());
+        // 終わった後は何もしない
+        assert_eq!(scan.step(&mut bus, SCAN_STEP), Ok(true));
+        assert_eq!(bus.log.len(), NUM_ADDRESSES);
+
+        let mut line = Reply::new();
+        write_scan_result(&mut line, BusId::I2c1, Ok(scan.found())).unwrap();
+        assert_eq!(line.as_str(), "DATA i2c scan bus=1 0x08 0x3c 0x68 0x77");
+    }
+
+    #[test]
+    fn scan_stops_on_bus_errors() {
+        let mut bus = MockBus::with_devices(&[0x3C]);
+        bus.fault = Some((0x20, ErrorKind::ArbitrationLoss));
+        let mut scan = Scan::new();
+        let result = loop {
+            match scan.step(&mut bus, SCAN_STEP) {
+                Ok(false) => {}
+                other => break other,
+            }
+        };
+        assert_eq!(result, Err(CommandError::ArbitrationLoss));
+        assert_eq!(bus.log.last(), Some(&0x20));
+
+        let mut line = Reply::new();
+        write_scan_result(&mut line, BusId::I2c0, result.map(|_| scan.found())).unwrap();
+        assert_eq!(line.as_str(), "DATA i2c scan bus=0 ERR E_ARB_LOST");
+    }
+}
@@ -0,0 +1,318 @@
+// `*type` の文字列をHIDキーボードのキー操作に変換する (USキー配列)
+// ハードウェアに依存しないのでホスト上でも動かせる
+//
+// 文字はそのまま打ち込み、`{...}` は特殊キーや待ち時間として扱う
+//   {ENTER} {TAB} {ESC} {BACKSPACE} {DELETE} {UP} {F5} など名前付きのキー
+//   {CTRL+ALT+DELETE} {GUI+r} {SHIFT+TAB} のように + で修飾キーと組み合わせる
+//   {DELAY 500} でミリ秒単位で待つ
+//   {{ と }} で { と } そのもの
+//   \n でENTER、\t でTAB、\\ で \ そのもの。それ以外の \ はそのまま打ち込む
+use heapless::Deque;
+
+pub const MOD_CTRL: u8 = 0x01;
+pub const MOD_SHIFT: u8 = 0x02;
+pub const MOD_ALT: u8 = 0x04;
+pub const MOD_GUI: u8 = 0x08;
+
+pub const MAX_DELAY_MS: u32 = 60_000;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum MacroStep {
+    // 押して離す
+    Key { modifiers: u8, keycode: u8 },
+    Delay { ms: u32 },
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum MacroError {
+    UnknownKey,
+    Unterminated,
+    InvalidDelay,
+    TooLong,
+}
+
+// 修飾なしで打てる文字とShiftが必要な文字
+pub fn char_to_key(c: char) -> Option<(u8, u8)> {
+    let key = match c {
+        'a'..='z' => (0, 0x04 + (c as u8 - b'a')),
+        'A'..='Z' => (MOD_SHIFT, 0x04 + (c as u8 - b'A')),
+        '1'..='9' => (0, 0x1E + (c as u8 - b'1')),
+        '0' => (0, 0x27),
+        ' ' => (0, 0x2C),
+        '-' => (0, 0x2D),
+        '=' => (0, 0x2E),
+        '[' => (0, 0x2F),
+        ']' => (0, 0x30),
+        '\\' => (0, 0x31),
+        ';' => (0, 0x33),
+        '\'' => (0, 0x34),
+        '`' => (0, 0x35),
+        ',' => (0, 0x36),
+        '.' => (0, 0x37),
+        '/' => (0, 0x38),
+        '!' => (MOD_SHIFT, 0x1E),
+        '@' => (MOD_SHIFT, 0x1F),
+        '#' => (MOD_SHIFT, 0x20),
+        '$' => (MOD_SHIFT, 0x21),
+        '%' => (MOD_SHIFT, 0x22),
+        '^' => (MOD_SHIFT, 0x23),
+        '&' => (MOD_SHIFT, 0x24),
+        '*' => (MOD_SHIFT, 0x25),
+        '(' => (MOD_SHIFT, 0x26),
+        ')' => (MOD_SHIFT, 0x27),
+        '_' => (MOD_SHIFT, 0x2D),
+        '+' => (MOD_SHIFT, 0x2E),
+        '{' => (MOD_SHIFT, 0x2F),
+        '}' => (MOD_SHIFT, 0x30),
+        '|' => (MOD_SHIFT, 0x31),
+        ':' => (MOD_SHIFT, 0x33),
+        '"' => (MOD_SHIFT, 0x34),
+        '~' => (MOD_SHIFT, 0x35),
+        '<' => (MOD_SHIFT, 0x36),
+        '>' => (MOD_SHIFT, 0x37),
+        '?' => (MOD_SHIFT, 0x38),
+        _ => return None,
+    };
+    Some(key)
+}
+
+fn named_key(name: &str) -> Option<u8> {
+    let key = match name {
+        "ENTER" => 0x28,
+        "ESC" => 0x29,
+        "BACKSPACE" => 0x2A,
+        "TAB" => 0x2B,
+        "SPACE" => 0x2C,
+        "CAPSLOCK" => 0x39,
+        "PRINTSCREEN" => 0x46,
+        "INSERT" => 0x49,
+        "HOME" => 0x4A,
+        "PAGEUP" => 0x4B,
+        "DELETE" => 0x4C,
+        "END" => 0x4D,
+        "PAGEDOWN" => 0x4E,
+        "RIGHT" => 0x4F,
+        "LEFT" => 0x50,
+        "DOWN" => 0x51,
+        "UP" => 0x52,
+        _ => {
+            // F1-F12
+            let n: u8 = name.strip_prefix('F')?.parse().ok()?;
+            if !(1..=12).contains(&n) {
+                return None;
+            }
+            0x3A + n - 1
+        }
+    };
+    Some(key)
+}
+
+fn modifier(name: &str) -> Option<u8> {
+    match name {
+        "CTRL" => Some(MOD_CTRL),
+        "SHIFT" => Some(MOD_SHIFT),
+        "ALT" => Some(MOD_ALT),
+        "GUI" | "WIN" => Some(MOD_GUI),
+        _ => None,
+    }
+}
+
+// `{...}` の中身を1ステップにする
+pub fn parse_token(token: &str) -> Result<MacroStep, MacroError> {
+    if let Some(ms) = token.strip_prefix("DELAY ") {
+        let ms: u32 = ms.trim().parse().map_err(|_| MacroError::InvalidDelay)?;
+        if ms > MAX_DELAY_MS {
+            return Err(MacroError::InvalidDelay);
+        }
+        return Ok(MacroStep::Delay { ms });
+    }
+    let mut modifiers = 0;
+    let mut keycode = 0;
+    for part in token.split('+') {
+        if let Some(m) = modifier(part) {
+            modifiers |= m;
+            continue;
+        }
+        // キーは最後の1つだけ
+        if keycode != 0 {
+            return Err(MacroError::UnknownKey);
+        }
+        let mut chars = part.chars();
+        keycode = match (chars.next(), chars.next()) {
+            // `GUI+r` のような1文字のキー。Shiftが要る文字ならShiftも足す
+            (Some(c), None) => {
+                let (m, k) = char_to_key(c).ok_or(MacroError::UnknownKey)?;
+                modifiers |= m;
+                k
+            }
+            _ => named_key(part).ok_or(MacroError::UnknownKey)?,
+        };
+    }
+    Ok(MacroStep::Key { modifiers, keycode })
+}
+
+// 文字列全体をステップ列にしてqueueの後ろへ積む
+// 失敗したときはqueueを変更しない
+pub fn encode<const N: usize>(
+    text: &str,
+    queue: &mut Deque<MacroStep, N>,
+) -> Result<usize, MacroError> {
+    let start = queue.len();
+    let result = encode_into(text, queue);
+    if result.is_err() {
+        while queue.len() > start {
+            queue.pop_back();
+        }
+    }
+    result.map(|_| queue.len() - start)
+}
+
+fn encode_into<const N: usize>(
+    text: &str,
+    queue: &mut Deque<MacroStep, N>,
+) -> Result<(), MacroError> {
+    let mut rest = text;
+    while let Some(c) = rest.chars().next() {
+        let step = if let Some(after) = rest.strip_prefix("{{") {
+            rest = after;
+            key_step('{')?
+        } else if let Some(after) = rest.strip_prefix("}}") {
+            rest = after;
+            key_step('}')?
+        } else if let Some(after) = rest.strip_prefix("\\n") {
+            rest = after;
+            parse_token("ENTER")?
+        } else if let Some(after) = rest.strip_prefix("\\t") {
+            rest = after;
+            parse_token("TAB")?
+        } else if let Some(after) = rest.strip_prefix("\\\\") {
+            rest = after;
+            key_step('\\')?
+        } else if let Some(after) = rest.strip_prefix('{') {
+            let (token, after) = after.split_once('}').ok_or(MacroError::Unterminated)?;
+            rest = after;
+            parse_token(token)?
+        } else {
+            rest = &rest[c.len_utf8()..];
+            key_step(c)?
+        };
+        queue.push_back(step).map_err(|_| MacroError::TooLong)?;
+    }
+    Ok(())
+}
+
+fn key_step(c: char) -> Result<MacroStep, MacroError> {
+    let (modifiers, keycode) = char_to_key(c).ok_or(MacroError::UnknownKey)?;
+    Ok(MacroStep::Key { modifiers, keycode })
+}
+
+// ブートプロトコル互換の8バイトの入力レポート
+pub fn key_report(modifiers: u8, keycode: u8) -> [u8; 8] {
+    [modifiers, 0, keycode, 0, 0, 0, 0, 0]
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::vec::Vec;
+
+    fn steps(text: &str) -> Result<Vec<MacroStep>, MacroError> {
+        let mut queue: Deque<MacroStep, 64> = Deque::new();
+        encode(text, &mut queue)?;
+        Ok(queue.into_iter().collect())
+    }
+
+    fn key(modifiers: u8, keycode: u8) -> MacroStep {
+        MacroStep::Key { modifiers, keycode }
+    }
+
+    #[test]
+    fn plain_text_uses_shift_where_needed() {
+        assert_eq!(
+            steps("aZ1!"),
+            Ok(vec![
+                key(0, 0x04),
+                key(MOD_SHIFT, 0x1D),
+                key(0, 0x1E),
+                key(MOD_SHIFT, 0x1E)
+            ])
+        );
+        assert_eq!(
+            steps("{{}}"),
+            Ok(vec![key(MOD_SHIFT, 0x2F), key(MOD_SHIFT, 0x30)])
+        );
+        assert_eq!(steps("é"), Err(MacroError::UnknownKey));
+    }
+
+    #[test]
+    fn named_keys_and_modifiers() {
+        assert_eq!(
+            steps("{ENTER}{F12}{UP}"),
+            Ok(vec![key(0, 0x28), key(0, 0x45), key(0, 0x52)])
+        );
+        assert_eq!(
+            steps("{CTRL+ALT+DELETE}"),
+            Ok(vec![key(MOD_CTRL | MOD_ALT, 0x4C)])
+        );
+        assert_eq!(steps("{GUI+r}"), Ok(vec![key(MOD_GUI, 0x15)]));
+        // Shiftが要る文字はShiftを足す
+        assert_eq!(steps("{CTRL+?}"), Ok(vec![key(MOD_CTRL | MOD_SHIFT, 0x38)]));
+        // 修飾キーだけ
+        assert_eq!(steps("{SHIFT}"), Ok(vec![key(MOD_SHIFT, 0)]));
+        assert_eq!(steps("{F13}"), Err(MacroError::UnknownKey));
+        assert_eq!(steps("{CTRL+a+b}"), Err(MacroError::UnknownKey));
+        assert_eq!(steps("{ENTER"), Err(MacroError::Unterminated));
+    }
+
+    #[test]
+    fn delays() {
+        assert_eq!(
+            steps("{DELAY 500}x"),
+            Ok(vec![MacroStep::Delay { ms: 500 }, key(0, 0x1B)])
+        );
+        assert_eq!(
+            steps("{DELAY 60000}"),
+            Ok(vec![MacroStep::Delay { ms: MAX_DELAY_MS }])
+        );
+        assert_eq!(steps("{DELAY 60001}"), Err(MacroError::InvalidDelay));
+        assert_eq!(steps("{DELAY soon}"), Err(MacroError::InvalidDelay));
+    }
+
+    #[test]
+    fn failed_encode_leaves_queue_untouched() {
+        let mut queue: Deque<MacroStep, 4> = Deque::new();
+        assert_eq!(encode("ab", &mut queue), Ok(2));
+        assert_eq!(encode("cde", &mut queue), Err(MacroError::TooLong));
+        assert_eq!(encode("c{NOPE}", &mut queue), Err(MacroError::UnknownKey));
+        assert_eq!(queue.len(), 2);
+        assert_eq!(encode("cd", &mut queue), Ok(2));
+    }
+
+    #[test]
+    fn report_layout() {
+        assert_eq!(
+            key_report(MOD_SHIFT, 0x04),
+            [MOD_SHIFT, 0, 0x04, 0, 0, 0, 0, 0]
+        );
+    }
+
+    #[test]
+    fn escapes() {
+        assert_eq!(
+            steps(r"a\nb\tc"),
+            Ok(vec![
+                key(0, 0x04),
+                key(0, 0x28),
+                key(0, 0x05),
+                key(0, 0x2B),
+                key(0, 0x06)
+            ])
+        );
+        assert_eq!(steps(r"\\n"), Ok(vec![key(0, 0x31), key(0, 0x11)]));
+        // 続きが無い \ はそのまま
+        assert_eq!(
+            steps(r"\x\"),
+            Ok(vec![key(0, 0x31), key(0, 0x1B), key(0, 0x31)])
+        );
+    }
+}
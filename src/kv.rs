@@ -0,0 +1,225 @@
+// フラッシュ上のキー・バリューストアとそのコマンド
+// 永続設定セクタのすぐ下の4セクタを使う (memory.xでプログラム領域から外してある)
+use crate::command::{ok_reply, parse_hex_bytes, parse_u32, write_hex, Args, CommandError, Reply};
+use crate::config::CONFIG_OFFSET;
+use crate::flash::{self, SECTOR_SIZE};
+use crate::globals::KV;
+use crate::kvstore::{KvError, KvStore, Storage, Value, MAX_VALUE_LEN};
+use core::fmt::Write;
+use cortex_m::interrupt;
+use heapless::String;
+
+pub const KV_SECTORS: usize = 4;
+pub const KV_OFFSET: u32 = CONFIG_OFFSET - KV_SECTORS as u32 * SECTOR_SIZE;
+// `kv list` 1回で返すキーの数
+const LIST_PAGE: usize = 6;
+
+pub struct FlashRegion;
+
+impl Storage for FlashRegion {
+    fn sector_size(&self) -> usize {
+        SECTOR_SIZE as usize
+    }
+
+    fn sector_count(&self) -> usize {
+        KV_SECTORS
+    }
+
+    fn read(&self, addr: usize, buf: &mut [u8]) {
+        buf.copy_from_slice(flash::read(KV_OFFSET + addr as u32, buf.len()));
+    }
+
+    fn erase(&mut self, sector: usize) -> Result<(), KvError> {
+        if sector >= KV_SECTORS {
+            return Err(KvError::Flash);
+        }
+        flash::erase_sector(KV_OFFSET + sector as u32 * SECTOR_SIZE).map_err(|_| KvError::Flash)
+    }
+
+    fn program(&mut self, addr: usize, data: &[u8]) -> Result<(), KvError> {
+        if addr + data.len() > KV_SECTORS * SECTOR_SIZE as usize {
+            return Err(KvError::Flash);
+        }
+        flash::program(KV_OFFSET + addr as u32, data).map_err(|_| KvError::Flash)
+    }
+}
+
+// 起動直後、core1を起動する前に呼ぶ
+pub fn mount() -> Result<KvStore<FlashRegion>, KvError> {
+    KvStore::mount(FlashRegion)
+}
+
+impl From<KvError> for CommandError {
+    fn from(err: KvError) -> Self {
+        match err {
+            KvError::InvalidKey | KvError::TypeMismatch => CommandError::InvalidArgument,
+            KvError::ValueTooLong => CommandError::OutOfRange,
+            KvError::Full | KvError::Flash => CommandError::Storage,
+        }
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum KvValue {
+    U32(u32),
+    I32(i32),
+    Bool(bool),
+    Str(String<MAX_VALUE_LEN>),
+    Bytes(heapless::Vec<u8, MAX_VALUE_LEN>),
+}
+
+impl KvValue {
+    // `<型> <値>`。strは残りの引数を空白1つで繋ぐ
+    fn parse(args: &mut Args) -> Result<Self, CommandError> {
+        let kind = args.next_str()?;
+        let value = args.next_str()?;
+        match kind {
+            "u32" => Ok(KvValue::U32(parse_u32(value)?)),
+            "i32" => {
+                let parsed = match value.strip_prefix('-') {
+                    Some(abs) => parse_u32(abs).and_then(|v| {
+                        0i32.checked_sub_unsigned(v).ok_or(CommandError::OutOfRange)
+                    })?,
+                    None => {
+                        i32::try_from(parse_u32(value)?).map_err(|_| CommandError::OutOfRange)?
+                    }
+                };
+                Ok(KvValue::I32(parsed))
+            }
+            "bool" => match value {
+                "1" | "true" | "on" => Ok(KvValue::Bool(true)),
+                "0" | "false" | "off" => Ok(KvValue::Bool(false)),
+                _ => Err(CommandError::InvalidArgument),
+            },
+            "str" => {
+                let mut text = String::new();
+                text.push_str(value).map_err(|_| CommandError::OutOfRange)?;
+                while let Some(word) = args.next_opt() {
+                    text.push(' ').map_err(|_| CommandError::OutOfRange)?;
+                    text.push_str(word).map_err(|_| CommandError::OutOfRange)?;
+                }
+                Ok(KvValue::Str(text))
+            }
+            "hex" => Ok(KvValue::Bytes(parse_hex_bytes(value)?)),
+            _ => Err(CommandError::InvalidArgument),
+        }
+    }
+
+    fn as_value(&self) -> Value<'_> {
+        match self {
+            KvValue::U32(v) => Value::U32(*v),
+            KvValue::I32(v) => Value::I32(*v),
+            KvValue::Bool(v) => Value::Bool(*v),
+            KvValue::Str(s) => Value::Str(s.as_str()),
+            KvValue::Bytes(b) => Value::Bytes(b.as_slice()),
+        }
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum KvCommand<'a> {
+    // `kv list [skip]`
+    List(usize),
+    Get(&'a str),
+    Set(&'a str, KvValue),
+    Erase(&'a str),
+    Format,
+    Info,
+}
+
+impl<'a> KvCommand<'a> {
+    pub fn parse(args: &mut Args<'a>) -> Result<Self, CommandError> {
+        match args.next_str()? {
+            "list" => {
+                let skip = args.next_opt().map(parse_u32).transpose()?.unwrap_or(0);
+                Ok(KvCommand::List(skip as usize))
+            }
+            "get" => Ok(KvCommand::Get(args.next_str()?)),
+            "set" => {
+                let key = args.next_str()?;
+                Ok(KvCommand::Set(key, KvValue::parse(args)?))
+            }
+            "erase" => Ok(KvCommand::Erase(args.next_str()?)),
+            "format" => Ok(KvCommand::Format),
+            "info" => Ok(KvCommand::Info),
+            _ => Err(CommandError::InvalidArgument),
+        }
+    }
+}
+
+fn write_value(r: &mut Reply, value: &Value) -> core::fmt::Result {
+    match value {
+        Value::U32(v) => write!(r, "{}", v),
+        Value::I32(v) => write!(r, "{}", v),
+        Value::Bool(v) => write!(r, "{}", *v as u8),
+        Value::Str(s) => write!(r, "\"{}\"", s),
+        Value::Bytes(b) => write_hex(r, b),
+    }
+}
+
+fn execute(store: &mut KvStore<FlashRegion>, cmd: KvCommand) -> Result<Reply, CommandError> {
+    match cmd {
+        KvCommand::List(skip) => {
+            let mut total = 0;
+            let mut reply = ok_reply(|r| r.write_str(" kv"));
+            store.for_each_key(|key, kind| {
+                if total >= skip && total < skip + LIST_PAGE {
+                    let _ = write!(reply, " {}:{}", key, kind.name());
+                }
+                total += 1;
+            });
+            if total > skip + LIST_PAGE {
+                let _ = write!(reply, " next={}", skip + LIST_PAGE);
+            }
+            Ok(reply)
+        }
+        KvCommand::Get(key) => {
+            let mut buf = [0u8; MAX_VALUE_LEN];
+            let value = store
+                .get(key, &mut buf)?
+                .ok_or(CommandError::InvalidArgument)?;
+            Ok(ok_reply(|r| {
+                write!(r, " kv type={} value=", value.value_type().name())?;
+                write_value(r, &value)
+            }))
+        }
+        KvCommand::Set(key, value) => {
+            store.set(key, value.as_value())?;
+            Ok(ok_reply(|r| write!(r, " kv {} saved", key)))
+        }
+        KvCommand::Erase(key) => {
+            if !store.remove(key)? {
+                return Err(CommandError::InvalidArgument);
+            }
+            Ok(ok_reply(|r| write!(r, " kv {} erased", key)))
+        }
+        KvCommand::Format => {
+            store.format()?;
+            Ok(ok_reply(|r| r.write_str(" kv formatted")))
+        }
+        KvCommand::Info => {
+            let stats = store.stats();
+            Ok(ok_reply(|r| {
+                write!(
+                    r,
+                    " kv sector={}/{} gen={} used={}/{} keys={}",
+                    stats.active_sector,
+                    KV_SECTORS,
+                    stats.generation,
+                    stats.used,
+                    stats.capacity,
+                    stats.keys
+                )
+            }))
+        }
+    }
+}
+
+pub fn handle_command(args: &mut Args) -> Result<Reply, CommandError> {
+    let cmd = KvCommand::parse(args)?;
+    interrupt::free(|cs| {
+        let mut kv = KV.borrow(cs).borrow_mut();
+        let store = kv.as_mut().ok_or(CommandError::NotReady)?;
+        execute(store, cmd)
+    })
+}
@@ -0,0 +1,835 @@
+// フラッシュ上のキー・バリューストア
+// ハードウェアに依存しないのでホスト上でもMemFlashで動かせる
+//
+// 数セクタをリングにして、使用中のセクタ1つにレコードを追記していく
+// セクタが一杯になったら最新の値だけをリングの次のセクタへ写して古いセクタを消す (消去回数が均等になる)
+// 書こうとしていたレコードも写す側に書き、セクタヘッダーはその後で最後に書くので、
+// 途中で電源が落ちても古いセクタ (書く前の値) がそのまま使われる
+//
+// セクタ: magic(4) seq(4) crc32(4) reserved(4) レコード...
+// レコード: key_len(1) type(1) value_len(2) key value crc32(4) を4バイト境界に揃える
+// key_lenが0xFF(消去状態)のところが追記位置。CRCが合わないレコードは書き込み途中で落ちたもの
+// 読み書きのたびにセクタを走査しないよう、各キーの最新のレコードの位置をRAMのindexに持つ
+use crate::crc::crc32;
+use heapless::Vec;
+
+pub const MAX_KEY_LEN: usize = 32;
+pub const MAX_VALUE_LEN: usize = 128;
+// RAM上のindexに載せられるキーの数
+pub const MAX_KEYS: usize = 64;
+
+const SECTOR_MAGIC: u32 = 0x3153_564B; // "KVS1"
+const SECTOR_HEADER_LEN: usize = 16;
+const RECORD_HEADER_LEN: usize = 4;
+const CRC_LEN: usize = 4;
+const MAX_RECORD_LEN: usize = align4(RECORD_HEADER_LEN + MAX_KEY_LEN + MAX_VALUE_LEN + CRC_LEN);
+const ERASED: u8 = 0xFF;
+
+const fn align4(len: usize) -> usize {
+    (len + 3) & !3
+}
+
+// NORフラッシュの抽象。programはビットを1から0にしか変えられない
+pub trait Storage {
+    fn sector_size(&self) -> usize;
+    fn sector_count(&self) -> usize;
+    fn read(&self, addr: usize, buf: &mut [u8]);
+    fn erase(&mut self, sector: usize) -> Result<(), KvError>;
+    fn program(&mut self, addr: usize, data: &[u8]) -> Result<(), KvError>;
+}
+
+// ホストで動かすためのRAM上のフラッシュ
+#[derive(Clone)]
+pub struct MemFlash<const SECTOR: usize, const N: usize> {
+    data: [[u8; SECTOR]; N],
+    pub erase_counts: [u32; N],
+}
+
+impl<const SECTOR: usize, const N: usize> Default for MemFlash<SECTOR, N> {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl<const SECTOR: usize, const N: usize> MemFlash<SECTOR, N> {
+    pub fn new() -> Self {
+        Self {
+            data: [[ERASED; SECTOR]; N],
+            erase_counts: [0; N],
+        }
+    }
+}
+
+impl<const SECTOR: usize, const N: usize> Storage for MemFlash<SECTOR, N> {
+    fn sector_size(&self) -> usize {
+        SECTOR
+    }
+
+    fn sector_count(&self) -> usize {
+        N
+    }
+
+    fn read(&self, addr: usize, buf: &mut [u8]) {
+        for (i, b) in buf.iter_mut().enumerate() {
+            let a = addr + i;
+            *b = self.data[a / SECTOR][a % SECTOR];
+        }
+    }
+
+    fn erase(&mut self, sector: usize) -> Result<(), KvError> {
+        self.data
+            .get_mut(sector)
+            .ok_or(KvError::Flash)?
+            .fill(ERASED);
+        self.erase_counts[sector] += 1;
+        Ok(())
+    }
+
+    fn program(&mut self, addr: usize, data: &[u8]) -> Result<(), KvError> {
+        if addr + data.len() > SECTOR * N {
+            return Err(KvError::Flash);
+        }
+        for (i, &b) in data.iter().enumerate() {
+            let a = addr + i;
+            self.data[a / SECTOR][a % SECTOR] &= b;
+        }
+        Ok(())
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum KvError {
+    InvalidKey,
+    ValueTooLong,
+    TypeMismatch,
+    Full,
+    Flash,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ValueType {
+    Tombstone = 0,
+    U32 = 1,
+    I32 = 2,
+    Bool = 3,
+    Str = 4,
+    Bytes = 5,
+}
+
+impl ValueType {
+    fn from_u8(v: u8) -> Option<Self> {
+        match v {
+            0 => Some(ValueType::Tombstone),
+            1 => Some(ValueType::U32),
+            2 => Some(ValueType::I32),
+            3 => Some(ValueType::Bool),
+            4 => Some(ValueType::Str),
+            5 => Some(ValueType::Bytes),
+            _ => None,
+        }
+    }
+
+    pub fn name(&self) -> &'static str {
+        match self {
+            ValueType::Tombstone => "none",
+            ValueType::U32 => "u32",
+            ValueType::I32 => "i32",
+            ValueType::Bool => "bool",
+            ValueType::Str => "str",
+            ValueType::Bytes => "hex",
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Value<'a> {
+    U32(u32),
+    I32(i32),
+    Bool(bool),
+    Str(&'a str),
+    Bytes(&'a [u8]),
+}
+
+impl<'a> Value<'a> {
+    pub fn value_type(&self) -> ValueType {
+        match self {
+            Value::U32(_) => ValueType::U32,
+            Value::I32(_) => ValueType::I32,
+            Value::Bool(_) => ValueType::Bool,
+            Value::Str(_) => ValueType::Str,
+            Value::Bytes(_) => ValueType::Bytes,
+        }
+    }
+
+    // bufに値のバイト列を書いて長さを返す
+    fn encode(&self, buf: &mut [u8; MAX_VALUE_LEN]) -> Result<usize, KvError> {
+        let bytes: &[u8] = match self {
+            Value::U32(v) => {
+                buf[..4].copy_from_slice(&v.to_le_bytes());
+                return Ok(4);
+            }
+            Value::I32(v) => {
+                buf[..4].copy_from_slice(&v.to_le_bytes());
+                return Ok(4);
+            }
+            Value::Bool(v) => {
+                buf[0] = *v as u8;
+                return Ok(1);
+            }
+            Value::Str(s) => s.as_bytes(),
+            Value::Bytes(b) => b,
+        };
+        if bytes.len() > MAX_VALUE_LEN {
+            return Err(KvError::ValueTooLong);
+        }
+        buf[..bytes.len()].copy_from_slice(bytes);
+        Ok(bytes.len())
+    }
+
+    fn decode(kind: ValueType, data: &'a [u8]) -> Option<Self> {
+        match (kind, data) {
+            (ValueType::U32, &[a, b, c, d]) => Some(Value::U32(u32::from_le_bytes([a, b, c, d]))),
+            (ValueType::I32, &[a, b, c, d]) => Some(Value::I32(i32::from_le_bytes([a, b, c, d]))),
+            (ValueType::Bool, &[v]) => Some(Value::Bool(v != 0)),
+            (ValueType::Str, _) => core::str::from_utf8(data).ok().map(Value::Str),
+            (ValueType::Bytes, _) => Some(Value::Bytes(data)),
+            _ => None,
+        }
+    }
+}
+
+// 表示可能なASCIIで空白を含まない
+pub fn validate_key(key: &str) -> Result<(), KvError> {
+    if key.is_empty() || key.len() > MAX_KEY_LEN || !key.bytes().all(|b| b.is_ascii_graphic()) {
+        return Err(KvError::InvalidKey);
+    }
+    Ok(())
+}
+
+#[derive(Debug, Clone, Copy)]
+struct Record {
+    pos: usize,
+    key_len: usize,
+    kind: ValueType,
+    value_len: usize,
+}
+
+impl Record {
+    fn len(&self) -> usize {
+        align4(RECORD_HEADER_LEN + self.key_len + self.value_len + CRC_LEN)
+    }
+}
+
+// 削除されていないキーの最新のレコード。hashはキーのCRCで、一致したときだけキーを読んで確かめる
+#[derive(Debug, Clone, Copy)]
+struct Entry {
+    hash: u32,
+    record: Record,
+}
+
+enum Scan {
+    Record(Record),
+    End,
+    Torn,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Stats {
+    pub active_sector: usize,
+    pub generation: u32,
+    pub used: usize,
+    pub capacity: usize,
+    pub keys: usize,
+}
+
+pub struct KvStore<S: Storage> {
+    storage: S,
+    active: usize,
+    seq: u32,
+    // 使用中セクタ内の追記位置
+    write_pos: usize,
+    // マウント時に一度だけ走査して作り、書くたびに更新する。最後に書いた順
+    index: Vec<Entry, MAX_KEYS>,
+}
+
+impl<S: Storage> KvStore<S> {
+    // 起動時に呼ぶ。使えるセクタが無ければ初期化し、書き込み途中のレコードがあれば整理し直す
+    pub fn mount(storage: S) -> Result<Self, KvError> {
+        let mut store = Self {
+            storage,
+            active: 0,
+            seq: 0,
+            write_pos: SECTOR_HEADER_LEN,
+            index: Vec::new(),
+        };
+        let mut found = None;
+        for sector in 0..store.storage.sector_count() {
+            if let Some(seq) = store.sector_seq(sector) {
+                if found.is_none_or(|(_, best)| seq > best) {
+                    found = Some((sector, seq));
+                }
+            }
+        }
+        let Some((active, seq)) = found else {
+            store.format()?;
+            return Ok(store);
+        };
+        store.active = active;
+        store.seq = seq;
+        // 写し終えた後、古いセクタを消す前に落ちた場合の後始末
+        for sector in 0..store.storage.sector_count() {
+            if sector != active && store.sector_seq(sector).is_some() {
+                store.storage.erase(sector)?;
+            }
+        }
+        let mut pos = SECTOR_HEADER_LEN;
+        loop {
+            match store.scan(pos) {
+                Scan::Record(record) => {
+                    store.index_record(record)?;
+                    pos += record.len();
+                }
+                Scan::End => break,
+                Scan::Torn => {
+                    // 壊れたレコードの手前までを次のセクタへ写す
+                    store.write_pos = pos;
+                    store.compact(None)?;
+                    return Ok(store);
+                }
+            }
+        }
+        store.write_pos = pos;
+        Ok(store)
+    }
+
+    // 全て消して空にする
+    pub fn format(&mut self) -> Result<(), KvError> {
+        for sector in 0..self.storage.sector_count() {
+            self.storage.erase(sector)?;
+        }
+        self.active = 0;
+        self.seq = 1;
+        self.index.clear();
+        self.write_sector_header(0, 1)?;
+        self.write_pos = SECTOR_HEADER_LEN;
+        Ok(())
+    }
+
+    pub fn storage(&self) -> &S {
+        &self.storage
+    }
+
+    fn sector_addr(&self, sector: usize) -> usize {
+        sector * self.storage.sector_size()
+    }
+
+    fn sector_seq(&self, sector: usize) -> Option<u32> {
+        let mut header = [0u8; SECTOR_HEADER_LEN];
+        self.storage.read(self.sector_addr(sector), &mut header);
+        let word =
+            |i: usize| u32::from_le_bytes([header[i], header[i + 1], header[i + 2], header[i + 3]]);
+        (word(0) == SECTOR_MAGIC && word(8) == crc32(&header[..8])).then(|| word(4))
+    }
+
+    fn write_sector_header(&mut self, sector: usize, seq: u32) -> Result<(), KvError> {
+        let mut header = [ERASED; SECTOR_HEADER_LEN];
+        header[0..4].copy_from_slice(&SECTOR_MAGIC.to_le_bytes());
+        header[4..8].copy_from_slice(&seq.to_le_bytes());
+        let crc = crc32(&header[..8]);
+        header[8..12].copy_from_slice(&crc.to_le_bytes());
+        self.storage.program(self.sector_addr(sector), &header)
+    }
+
+    // 使用中セクタのposにあるレコードを読んでCRCを確かめる
+    fn scan(&self, pos: usize) -> Scan {
+        let sector_size = self.storage.sector_size();
+        if pos + RECORD_HEADER_LEN > sector_size {
+            return Scan::End;
+        }
+        let base = self.sector_addr(self.active);
+        let mut buf = [0u8; MAX_RECORD_LEN];
+        self.storage.read(base + pos, &mut buf[..RECORD_HEADER_LEN]);
+        if buf[0] == ERASED {
+            return Scan::End;
+        }
+        let key_len = buf[0] as usize;
+        let value_len = u16::from_le_bytes([buf[2], buf[3]]) as usize;
+        let Some(kind) = ValueType::from_u8(buf[1]) else {
+            return Scan::Torn;
+        };
+        let record = Record {
+            pos,
+            key_len,
+            kind,
+            value_len,
+        };
+        if key_len == 0
+            || key_len > MAX_KEY_LEN
+            || value_len > MAX_VALUE_LEN
+            || pos + record.len() > sector_size
+        {
+            return Scan::Torn;
+        }
+        let body = RECORD_HEADER_LEN + key_len + value_len;
+        self.storage.read(base + pos, &mut buf[..body + CRC_LEN]);
+        let stored = u32::from_le_bytes([buf[body], buf[body + 1], buf[body + 2], buf[body + 3]]);
+        if crc32(&buf[..body]) != stored {
+            return Scan::Torn;
+        }
+        Scan::Record(record)
+    }
+
+    fn read_key<'b>(&self, record: &Record, buf: &'b mut [u8; MAX_KEY_LEN]) -> &'b [u8] {
+        let addr = self.sector_addr(self.active) + record.pos + RECORD_HEADER_LEN;
+        self.storage.read(addr, &mut buf[..record.key_len]);
+        &buf[..record.key_len]
+    }
+
+    fn key_matches(&self, record: &Record, key: &[u8]) -> bool {
+        let mut buf = [0u8; MAX_KEY_LEN];
+        record.key_len == key.len() && self.read_key(record, &mut buf) == key
+    }
+
+    // indexの中でのキーの位置
+    fn lookup(&self, key: &[u8]) -> Option<usize> {
+        let hash = crc32(key);
+        self.index
+            .iter()
+            .position(|entry| entry.hash == hash && self.key_matches(&entry.record, key))
+    }
+
+    // 使用中セクタに書いたレコードをindexに反映する
+    fn index_record(&mut self, record: Record) -> Result<(), KvError> {
+        let mut buf = [0u8; MAX_KEY_LEN];
+        let key = self.read_key(&record, &mut buf);
+        if let Some(i) = self.lookup(key) {
+            self.index.remove(i);
+        }
+        if record.kind == ValueType::Tombstone {
+            return Ok(());
+        }
+        let hash = crc32(key);
+        self.index
+            .push(Entry { hash, record })
+            .map_err(|_| KvError::Full)
+    }
+
+    // キーの最新のレコード
+    fn find(&self, key: &[u8]) -> Option<Record> {
+        self.lookup(key).map(|i| self.index[i].record)
+    }
+
+    pub fn get<'b>(
+        &self,
+        key: &str,
+        buf: &'b mut [u8; MAX_VALUE_LEN],
+    ) -> Result<Option<Value<'b>>, KvError> {
+        validate_key(key)?;
+        let Some(record) = self.find(key.as_bytes()) else {
+            return Ok(None);
+        };
+        let addr = self.sector_addr(self.active) + record.pos + RECORD_HEADER_LEN + record.key_len;
+        let data = &mut buf[..record.value_len];
+        self.storage.read(addr, data);
+        Ok(Value::decode(record.kind, data))
+    }
+
+    pub fn get_u32(&self, key: &str) -> Result<Option<u32>, KvError> {
+        match self.get(key, &mut [0; MAX_VALUE_LEN])? {
+            Some(Value::U32(v)) => Ok(Some(v)),
+            Some(_) => Err(KvError::TypeMismatch),
+            None => Ok(None),
+        }
+    }
+
+    pub fn get_i32(&self, key: &str) -> Result<Option<i32>, KvError> {
+        match self.get(key, &mut [0; MAX_VALUE_LEN])? {
+            Some(Value::I32(v)) => Ok(Some(v)),
+            Some(_) => Err(KvError::TypeMismatch),
+            None => Ok(None),
+        }
+    }
+
+    pub fn get_bool(&self, key: &str) -> Result<Option<bool>, KvError> {
+        match self.get(key, &mut [0; MAX_VALUE_LEN])? {
+            Some(Value::Bool(v)) => Ok(Some(v)),
+            Some(_) => Err(KvError::TypeMismatch),
+            None => Ok(None),
+        }
+    }
+
+    // キーはMAX_KEYS個まで
+    pub fn set(&mut self, key: &str, value: Value) -> Result<(), KvError> {
+        validate_key(key)?;
+        if self.index.is_full() && self.lookup(key.as_bytes()).is_none() {
+            return Err(KvError::Full);
+        }
+        let mut data = [0u8; MAX_VALUE_LEN];
+        let len = value.encode(&mut data)?;
+        self.append(key.as_bytes(), value.value_type(), &data[..len])
+    }
+
+    // 削除の印を追記する。キーが無ければfalse
+    pub fn remove(&mut self, key: &str) -> Result<bool, KvError> {
+        validate_key(key)?;
+        if self.lookup(key.as_bytes()).is_none() {
+            return Ok(false);
+        }
+        self.append(key.as_bytes(), ValueType::Tombstone, &[])?;
+        Ok(true)
+    }
+
+    // 削除されていないキーを書いた順に渡す
+    pub fn for_each_key(&self, mut f: impl FnMut(&str, ValueType)) {
+        for entry in &self.index {
+            let mut buf = [0u8; MAX_KEY_LEN];
+            if let Ok(key) = core::str::from_utf8(self.read_key(&entry.record, &mut buf)) {
+                f(key, entry.record.kind);
+            }
+        }
+    }
+
+    pub fn stats(&self) -> Stats {
+        Stats {
+            active_sector: self.active,
+            generation: self.seq,
+            used: self.write_pos,
+            capacity: self.storage.sector_size(),
+            keys: self.index.len(),
+        }
+    }
+
+    fn append(&mut self, key: &[u8], kind: ValueType, value: &[u8]) -> Result<(), KvError> {
+        let len = align4(RECORD_HEADER_LEN + key.len() + value.len() + CRC_LEN);
+        let mut buf = [ERASED; MAX_RECORD_LEN];
+        buf[0] = key.len() as u8;
+        buf[1] = kind as u8;
+        buf[2..4].copy_from_slice(&(value.len() as u16).to_le_bytes());
+        buf[RECORD_HEADER_LEN..RECORD_HEADER_LEN + key.len()].copy_from_slice(key);
+        let body = RECORD_HEADER_LEN + key.len() + value.len();
+        buf[RECORD_HEADER_LEN + key.len()..body].copy_from_slice(value);
+        let crc = crc32(&buf[..body]);
+        buf[body..body + CRC_LEN].copy_from_slice(&crc.to_le_bytes());
+        if self.write_pos + len > self.storage.sector_size() {
+            return self.compact(Some((key, &buf[..len])));
+        }
+        let addr = self.sector_addr(self.active) + self.write_pos;
+        self.storage.program(addr, &buf[..len])?;
+        let record = Record {
+            pos: self.write_pos,
+            key_len: key.len(),
+            kind,
+            value_len: value.len(),
+        };
+        self.write_pos += len;
+        self.index_record(record)
+    }
+
+    // 最新の値だけを次のセクタへ写す
+    // pendingは書こうとしているレコード (キーとエンコード済みのレコード)。そのキーの古い値は写さず、
+    // 代わりにpendingをヘッダーより先に書く。空きが作れなければ何も変えずにFull
+    fn compact(&mut self, pending: Option<(&[u8], &[u8])>) -> Result<(), KvError> {
+        let skip = pending.and_then(|(key, _)| self.lookup(key));
+        let reserve = pending.map_or(0, |(_, record)| record.len());
+        let needed: usize = self
+            .index
+            .iter()
+            .enumerate()
+            .filter(|&(i, _)| Some(i) != skip)
+            .map(|(_, entry)| entry.record.len())
+            .sum();
+        if SECTOR_HEADER_LEN + needed + reserve > self.storage.sector_size() {
+            return Err(KvError::Full);
+        }
+
+        let next = (self.active + 1) % self.storage.sector_count();
+        self.storage.erase(next)?;
+        // 書き終えるまでは古いセクタのindexを残しておく
+        let mut index = self.index.clone();
+        if let Some(i) = skip {
+            index.remove(i);
+        }
+        let mut dst = SECTOR_HEADER_LEN;
+        let mut buf = [0u8; MAX_RECORD_LEN];
+        for entry in index.iter_mut() {
+            let len = entry.record.len();
+            self.storage.read(
+                self.sector_addr(self.active) + entry.record.pos,
+                &mut buf[..len],
+            );
+            self.storage
+                .program(self.sector_addr(next) + dst, &buf[..len])?;
+            entry.record.pos = dst;
+            dst += len;
+        }
+        let pending_pos = dst;
+        if let Some((_, record)) = pending {
+            self.storage.program(self.sector_addr(next) + dst, record)?;
+            dst += record.len();
+        }
+        // ここで初めて次のセクタが有効になる
+        let seq = self.seq.wrapping_add(1);
+        self.write_sector_header(next, seq)?;
+        let old = self.active;
+        self.active = next;
+        self.seq = seq;
+        self.write_pos = dst;
+        self.index = index;
+        if let Some((key, record)) = pending {
+            let kind = ValueType::from_u8(record[1]).ok_or(KvError::Flash)?;
+            let value_len = u16::from_le_bytes([record[2], record[3]]) as usize;
+            self.index_record(Record {
+                pos: pending_pos,
+                key_len: key.len(),
+                kind,
+                value_len,
+            })?;
+        }
+        self.storage.erase(old)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::vec::Vec;
+
+    const SECTOR: usize = 256;
+    type Flash = MemFlash<SECTOR, 4>;
+
+    fn fresh() -> KvStore<Flash> {
+        KvStore::mount(Flash::new()).unwrap()
+    }
+
+    fn remount(store: KvStore<Flash>) -> KvStore<Flash> {
+        let KvStore { storage, .. } = store;
+        KvStore::mount(storage).unwrap()
+    }
+
+    fn keys(store: &KvStore<Flash>) -> Vec<String> {
+        let mut keys = Vec::new();
+        store.for_each_key(|key, _| keys.push(key.to_string()));
+        keys
+    }
+
+    #[test]
+    fn set_get_remove() {
+        let mut store = fresh();
+        store.set("a", Value::U32(7)).unwrap();
+        store.set("b", Value::I32(-3)).unwrap();
+        store.set("c", Value::Bool(true)).unwrap();
+        store.set("d", Value::Str("hello")).unwrap();
+        store.set("e", Value::Bytes(&[1, 2, 3])).unwrap();
+        assert_eq!(store.get_u32("a"), Ok(Some(7)));
+        assert_eq!(store.get_i32("b"), Ok(Some(-3)));
+        assert_eq!(store.get_bool("c"), Ok(Some(true)));
+        let mut buf = [0; MAX_VALUE_LEN];
+        assert_eq!(store.get("d", &mut buf), Ok(Some(Value::Str("hello"))));
+        assert_eq!(store.get("e", &mut buf), Ok(Some(Value::Bytes(&[1, 2, 3]))));
+        assert_eq!(store.get_i32("a"), Err(KvError::TypeMismatch));
+        assert_eq!(store.get_u32("missing"), Ok(None));
+
+        store.set("a", Value::U32(8)).unwrap();
+        assert_eq!(store.get_u32("a"), Ok(Some(8)));
+        assert_eq!(store.remove("b"), Ok(true));
+        assert_eq!(store.remove("b"), Ok(false));
+        assert_eq!(store.get_i32("b"), Ok(None));
+        assert_eq!(keys(&store), ["c", "d", "e", "a"]);
+
+        let store = remount(store);
+        assert_eq!(store.get_u32("a"), Ok(Some(8)));
+        assert_eq!(store.get_i32("b"), Ok(None));
+        assert_eq!(store.stats().keys, 4);
+    }
+
+    #[test]
+    fn rejects_bad_keys_and_values() {
+        let mut store = fresh();
+        assert_eq!(store.set("", Value::U32(0)), Err(KvError::InvalidKey));
+        assert_eq!(store.set("a b", Value::U32(0)), Err(KvError::InvalidKey));
+        let long_key = "k".repeat(MAX_KEY_LEN + 1);
+        assert_eq!(
+            store.set(&long_key, Value::U32(0)),
+            Err(KvError::InvalidKey)
+        );
+        let long_value = [0u8; MAX_VALUE_LEN + 1];
+        assert_eq!(
+            store.set("a", Value::Bytes(&long_value)),
+            Err(KvError::ValueTooLong)
+        );
+    }
+
+    #[test]
+    fn compaction_keeps_latest_values() {
+        let mut store = fresh();
+        store.set("keep", Value::Str("kept")).unwrap();
+        store.set("gone", Value::U32(1)).unwrap();
+        store.remove("gone").unwrap();
+        for i in 0..100 {
+            store.set("counter", Value::U32(i)).unwrap();
+        }
+        assert!(store.stats().generation > 1);
+        assert_eq!(store.get_u32("counter"), Ok(Some(99)));
+        assert_eq!(store.get_u32("gone"), Ok(None));
+        let mut buf = [0; MAX_VALUE_LEN];
+        assert_eq!(store.get("keep", &mut buf), Ok(Some(Value::Str("kept"))));
+
+        let store = remount(store);
+        assert_eq!(store.get_u32("counter"), Ok(Some(99)));
+        assert_eq!(keys(&store), ["keep", "counter"]);
+    }
+
+    #[test]
+    fn full_when_live_values_do_not_fit() {
+        let mut store = fresh();
+        let value = [0xA5u8; 100];
+        store.set("x", Value::Bytes(&value)).unwrap();
+        store.set("y", Value::Bytes(&value)).unwrap();
+        assert_eq!(store.set("z", Value::Bytes(&value)), Err(KvError::Full));
+        // 失敗しても前の値はそのまま
+        let mut buf = [0; MAX_VALUE_LEN];
+        assert_eq!(store.get("x", &mut buf), Ok(Some(Value::Bytes(&value))));
+        store.remove("y").unwrap();
+        store.set("z", Value::Bytes(&value)).unwrap();
+    }
+
+    #[test]
+    fn key_count_is_limited_by_the_index() {
+        let mut store = KvStore::mount(MemFlash::<4096, 2>::default()).unwrap();
+        for i in 0..MAX_KEYS {
+            store.set(&format!("k{i}"), Value::U32(i as u32)).unwrap();
+        }
+        assert_eq!(store.set("extra", Value::U32(0)), Err(KvError::Full));
+        // 既にあるキーの更新と、消して空いた分は書ける
+        store.set("k0", Value::U32(100)).unwrap();
+        store.remove("k1").unwrap();
+        store.set("extra", Value::U32(1)).unwrap();
+
+        let KvStore { storage, .. } = store;
+        let store = KvStore::mount(storage).unwrap();
+        assert_eq!(store.stats().keys, MAX_KEYS);
+        assert_eq!(store.get_u32("k0"), Ok(Some(100)));
+        assert_eq!(store.get_u32("k1"), Ok(None));
+        assert_eq!(store.get_u32("extra"), Ok(Some(1)));
+    }
+
+    #[test]
+    fn wear_is_spread_over_all_sectors() {
+        let mut store = fresh();
+        for i in 0..2000 {
+            store.set("n", Value::U32(i)).unwrap();
+        }
+        let counts = store.storage().erase_counts;
+        let min = *counts.iter().min().unwrap();
+        let max = *counts.iter().max().unwrap();
+        assert!(min > 0);
+        assert!(max - min <= 2, "{:?}", counts);
+    }
+
+    #[test]
+    fn torn_record_is_dropped_on_mount() {
+        let mut store = fresh();
+        store.set("a", Value::U32(1)).unwrap();
+        store.set("b", Value::U32(2)).unwrap();
+        // 書き込み途中で落ちたレコード: ヘッダーだけ書かれてCRCが無い
+        let addr = store.sector_addr(store.active) + store.write_pos;
+        store.storage.program(addr, &[1, 1, 4, 0, b'c']).unwrap();
+
+        let mut store = remount(store);
+        assert_eq!(store.get_u32("a"), Ok(Some(1)));
+        assert_eq!(store.get_u32("b"), Ok(Some(2)));
+        assert_eq!(store.get_u32("c"), Ok(None));
+        // 壊れた所より後ろにも書ける
+        store.set("c", Value::U32(3)).unwrap();
+        let store = remount(store);
+        assert_eq!(store.get_u32("c"), Ok(Some(3)));
+    }
+
+    // program/eraseをbudget回だけ通し、その後は電源が落ちたように何もしない
+    struct PowerCut {
+        flash: Flash,
+        budget: usize,
+    }
+
+    impl PowerCut {
+        fn spend(&mut self) -> Result<(), KvError> {
+            if self.budget == 0 {
+                return Err(KvError::Flash);
+            }
+            self.budget -= 1;
+            Ok(())
+        }
+    }
+
+    impl Storage for PowerCut {
+        fn sector_size(&self) -> usize {
+            self.flash.sector_size()
+        }
+
+        fn sector_count(&self) -> usize {
+            self.flash.sector_count()
+        }
+
+        fn read(&self, addr: usize, buf: &mut [u8]) {
+            self.flash.read(addr, buf)
+        }
+
+        fn erase(&mut self, sector: usize) -> Result<(), KvError> {
+            self.spend()?;
+            self.flash.erase(sector)
+        }
+
+        fn program(&mut self, addr: usize, data: &[u8]) -> Result<(), KvError> {
+            self.spend()?;
+            self.flash.program(addr, data)
+        }
+    }
+
+    #[test]
+    fn power_loss_during_compaction_keeps_old_or_new_value() {
+        let mut store = fresh();
+        store.set("other", Value::U32(42)).unwrap();
+        let mut n = 0;
+        // 次のsetでcompactionが走るところまで埋める ("n"のu32のレコードは16バイト)
+        while store.write_pos + 16 <= SECTOR {
+            store.set("n", Value::U32(n)).unwrap();
+            n += 1;
+        }
+        let before = store.storage().clone();
+        for budget in 0.. {
+            let mut cut = KvStore::mount(PowerCut {
+                flash: before.clone(),
+                budget,
+            })
+            .unwrap();
+            let done = cut.set("n", Value::U32(1000)).is_ok();
+
+            let KvStore { storage, .. } = cut;
+            let store = KvStore::mount(storage.flash).unwrap();
+            let value = store.get_u32("n").unwrap();
+            assert!(
+                value == Some(n - 1) || value == Some(1000),
+                "budget {}: {:?}",
+                budget,
+                value
+            );
+            assert_eq!(store.get_u32("other"), Ok(Some(42)));
+            if done {
+                assert_eq!(value, Some(1000));
+                break;
+            }
+        }
+    }
+
+    #[test]
+    fn unfinished_compaction_falls_back_to_old_sector() {
+        let mut store = fresh();
+        store.set("a", Value::U32(1)).unwrap();
+        // 次のセクタに写し始めたがヘッダーを書く前に落ちた状態
+        let next = (store.active + 1) % 4;
+        let addr = store.sector_addr(next) + SECTOR_HEADER_LEN;
+        store.storage.program(addr, &[0; 8]).unwrap();
+        let store = remount(store);
+        assert_eq!(store.get_u32("a"), Ok(Some(1)));
+    }
+}
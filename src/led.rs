@@ -1,29 +1,88 @@
 // use crate::LED_PIN;
 use crate::globals::LED_PIN;
-use cortex_m::interrupt;
+use core::sync::atomic::{AtomicU8, Ordering};
 use embedded_hal::digital::{OutputPin, StatefulOutputPin};
 
 #[allow(dead_code)]
 pub fn led_on() {
-    interrupt::free(|cs| {
-        if let Some(pin) = LED_PIN.borrow(cs).borrow_mut().as_mut() {
-            pin.set_high().unwrap();
-        }
-    });
+    LED_PIN.with(|pin| pin.set_high().unwrap());
 }
 #[allow(dead_code)]
 pub fn led_off() {
-    interrupt::free(|cs| {
-        if let Some(pin) = LED_PIN.borrow(cs).borrow_mut().as_mut() {
-            pin.set_low().unwrap();
-        }
-    });
+    LED_PIN.with(|pin| pin.set_low().unwrap());
 }
 #[allow(dead_code)]
 pub fn led_toggle() {
-    interrupt::free(|cs| {
-        if let Some(pin) = LED_PIN.borrow(cs).borrow_mut().as_mut() {
-            pin.toggle().unwrap();
+    LED_PIN.with(|pin| pin.toggle().unwrap());
+}
+
+// core1の100ms割り込みで回すLEDのパターン
+// MIDIなどcore0側から切り替えるのでアトミックで持つ
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum LedPattern {
+    Blink, // 100msごとに反転
+    Slow,  // 500msごとに反転
+    On,
+    Off,
+}
+
+impl LedPattern {
+    pub fn parse(s: &str) -> Option<Self> {
+        match s {
+            "blink" => Some(LedPattern::Blink),
+            "slow" => Some(LedPattern::Slow),
+            "on" => Some(LedPattern::On),
+            "off" => Some(LedPattern::Off),
+            _ => None,
+        }
+    }
+
+    pub fn name(&self) -> &'static str {
+        match self {
+            LedPattern::Blink => "blink",
+            LedPattern::Slow => "slow",
+            LedPattern::On => "on",
+            LedPattern::Off => "off",
+        }
+    }
+
+    fn from_u8(v: u8) -> Self {
+        match v {
+            1 => LedPattern::Slow,
+            2 => LedPattern::On,
+            3 => LedPattern::Off,
+            _ => LedPattern::Blink,
+        }
+    }
+}
+
+const SLOW_TICKS: u8 = 5;
+static PATTERN: AtomicU8 = AtomicU8::new(LedPattern::Blink as u8);
+static TICKS: AtomicU8 = AtomicU8::new(0);
+
+pub fn set_pattern(pattern: LedPattern) {
+    PATTERN.store(pattern as u8, Ordering::Relaxed);
+}
+
+pub fn pattern() -> LedPattern {
+    LedPattern::from_u8(PATTERN.load(Ordering::Relaxed))
+}
+
+// core1のAlarm2割り込みから呼ぶ
+pub fn tick() {
+    match pattern() {
+        LedPattern::Blink => led_toggle(),
+        LedPattern::Slow => {
+            // 書くのはcore1だけなのでload/storeで足りる
+            let ticks = TICKS.load(Ordering::Relaxed) + 1;
+            if ticks >= SLOW_TICKS {
+                led_toggle();
+                TICKS.store(0, Ordering::Relaxed);
+            } else {
+                TICKS.store(ticks, Ordering::Relaxed);
+            }
         }
-    });
+        LedPattern::On => led_on(),
+        LedPattern::Off => led_off(),
+    }
 }
@@ -0,0 +1,119 @@
+// フラッシュ操作中にcore1を止めておく仕組み (pico-sdkのmulticore_lockoutと同じ手順)
+// core0がFIFOにSTARTを送ると、core1はSIO割り込みで割り込みを止めてRAM上のループに入りSTARTを返す
+// core0は操作を終えたらENDを送り、core1はENDを返してから元の処理に戻る
+// core1起動時のFIFOのやり取りが終わってからSIO割り込みを有効にする
+use crate::timer;
+use core::ptr::{read_volatile, write_volatile};
+use core::sync::atomic::{AtomicU8, Ordering};
+use cortex_m::interrupt;
+use rp_pico::hal::pac;
+
+const LOCKOUT_MAGIC_START: u32 = 0x73A8_831E;
+const LOCKOUT_MAGIC_END: u32 = !LOCKOUT_MAGIC_START;
+// core1が長い割り込み禁止区間にいると応答が遅れる
+const HANDSHAKE_TIMEOUT_US: u32 = 10_000;
+
+// RAM上のコードから触るのでPACを通さずに直接読み書きする
+const SIO_FIFO_ST: *mut u32 = 0xD000_0050 as *mut u32;
+const SIO_FIFO_WR: *mut u32 = 0xD000_0054 as *mut u32;
+const SIO_FIFO_RD: *const u32 = 0xD000_0058 as *const u32;
+const FIFO_ST_VLD: u32 = 1 << 0;
+const FIFO_ST_RDY: u32 = 1 << 1;
+// WOF/ROEは書き込みでクリア
+const FIFO_ST_ERRORS: u32 = 0b11 << 2;
+
+const CORE1_STOPPED: u8 = 0;
+const CORE1_STARTING: u8 = 1;
+const CORE1_READY: u8 = 2;
+static CORE1_STATE: AtomicU8 = AtomicU8::new(CORE1_STOPPED);
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct LockoutTimeout;
+
+#[inline(always)]
+unsafe fn fifo_push(value: u32) {
+    while read_volatile(SIO_FIFO_ST) & FIFO_ST_RDY == 0 {}
+    write_volatile(SIO_FIFO_WR, value);
+}
+
+#[inline(always)]
+unsafe fn fifo_pop() -> Option<u32> {
+    if read_volatile(SIO_FIFO_ST) & FIFO_ST_VLD != 0 {
+        Some(read_volatile(SIO_FIFO_RD))
+    } else {
+        None
+    }
+}
+
+// core0がcore1を起動する直前に呼ぶ
+pub fn core1_starting() {
+    CORE1_STATE.store(CORE1_STARTING, Ordering::Release);
+}
+
+// core1側でFIFOのやり取りが済んでから呼ぶ
+pub fn core1_ready() {
+    unsafe {
+        write_volatile(SIO_FIFO_ST, FIFO_ST_ERRORS);
+        pac::NVIC::unmask(pac::Interrupt::SIO_IRQ_PROC1);
+    }
+    CORE1_STATE.store(CORE1_READY, Ordering::Release);
+}
+
+// core1のSIO_IRQ_PROC1から呼ぶ
+pub fn handle_core1_sio_irq() {
+    unsafe {
+        while let Some(value) = fifo_pop() {
+            if value == LOCKOUT_MAGIC_START {
+                interrupt::free(|_| park_in_ram());
+            }
+        }
+        write_volatile(SIO_FIFO_ST, FIFO_ST_ERRORS);
+    }
+}
+
+#[inline(never)]
+#[link_section = ".ram_text"]
+unsafe fn park_in_ram() {
+    fifo_push(LOCKOUT_MAGIC_START);
+    loop {
+        if fifo_pop() == Some(LOCKOUT_MAGIC_END) {
+            break;
+        }
+    }
+    fifo_push(LOCKOUT_MAGIC_END);
+}
+
+fn wait_for(mut done: impl FnMut() -> bool) -> Result<(), LockoutTimeout> {
+    let start = timer::now_us();
+    while !done() {
+        if timer::now_us().wrapping_sub(start) > HANDSHAKE_TIMEOUT_US {
+            return Err(LockoutTimeout);
+        }
+    }
+    Ok(())
+}
+
+// core0から呼ぶ。core1がフラッシュ上のコードを実行しない状態でfを実行する
+// core1が起動前ならそのまま実行する
+pub fn with_core1_parked<R>(f: impl FnOnce() -> R) -> Result<R, LockoutTimeout> {
+    match CORE1_STATE.load(Ordering::Acquire) {
+        CORE1_STOPPED => return Ok(f()),
+        CORE1_STARTING => wait_for(|| CORE1_STATE.load(Ordering::Acquire) == CORE1_READY)?,
+        _ => {}
+    }
+    unsafe {
+        // 前回タイムアウトした時の応答が残っていれば捨てる
+        while fifo_pop().is_some() {}
+        fifo_push(LOCKOUT_MAGIC_START);
+        if wait_for(|| fifo_pop() == Some(LOCKOUT_MAGIC_START)).is_err() {
+            // 後からcore1が止まってもすぐ戻れるようにENDも送っておく
+            fifo_push(LOCKOUT_MAGIC_END);
+            return Err(LockoutTimeout);
+        }
+        let result = f();
+        fifo_push(LOCKOUT_MAGIC_END);
+        // core1はRAM上のループにいるので必ず応答する
+        while fifo_pop() != Some(LOCKOUT_MAGIC_END) {}
+        Ok(result)
+    }
+}
@@ -4,29 +4,25 @@
 #![no_std]
 #![no_main]
 use defmt::*;
-use embedded_alloc::LlffHeap as Heap;
+use pico_test::capture;
 use pico_test::core0;
 use pico_test::core1;
+use pico_test::freqcounter;
 use rp_pico as bsp;
 
 use bsp::{entry, hal::pac::interrupt};
-
-#[global_allocator]
-static HEAP: Heap = Heap::empty();
+use cortex_m_rt::{exception, ExceptionFrame};
+use pico_test::crashlog;
+use pico_test::heap;
+use pico_test::memlayout;
 
 #[entry]
 fn main() -> ! {
     info!("Program start");
+    // SRAM4/SRAM5を0で埋める
+    memlayout::init();
     // set Heap
-    {
-        use core::mem::MaybeUninit;
-        const HEAP_SIZE: usize = 1024;
-        static mut HEAP_MEM: [MaybeUninit<u8>; HEAP_SIZE] = [MaybeUninit::uninit(); HEAP_SIZE];
-        #[allow(static_mut_refs)]
-        unsafe {
-            HEAP.init(HEAP_MEM.as_ptr() as usize, HEAP_SIZE)
-        }
-    }
+    heap::init();
     core0::main();
 }
 
@@ -49,3 +45,27 @@ fn TIMER_IRQ_3() {
     // core1のタイマー割り込み
     core1::handle_timer_irq_3()
 }
+
+#[interrupt]
+fn SIO_IRQ_PROC1() {
+    // core0からのフラッシュ操作の通知
+    core1::handle_sio_irq()
+}
+
+#[interrupt]
+fn DMA_IRQ_0() {
+    // ADCキャプチャのブロック完了
+    capture::handle_dma_irq_0()
+}
+
+#[interrupt]
+fn PWM_IRQ_WRAP() {
+    // 周波数カウンタの16bitカウンタが一周した
+    freqcounter::handle_wrap_irq()
+}
+
+#[exception]
+unsafe fn HardFault(ef: &ExceptionFrame) -> ! {
+    // どちらのコアのHardFaultもここに来る
+    crashlog::record_hard_fault(ef)
+}
@@ -0,0 +1,130 @@
+// メモリ配置の報告と置き場所の指定
+// 各セクションの大きさはmemory.xとcortex-m-rtのリンカスクリプトが定義するシンボルから求める
+// ビルドしたELFはtools/memreport.shで同じ内訳を表示できる
+//
+// 置き場所の指定 (どれも #[link_section] で付ける)
+// - ".ram_text": 割り込みハンドラやキュー操作などよく呼ぶコードをRAMで実行する。
+//   インライン展開されるとフラッシュ側に入るので #[inline(never)] と一緒に使う
+// - ".sram4" / ".sram5": core0 / core1 だけが触る大きなバッファ。
+//   起動時に0で埋めるだけなので、0で初期化するstaticに限る
+use crate::command::{ok_reply, Args, CommandError, Reply};
+use crate::heap;
+use crate::stack;
+use core::fmt::Write;
+use core::ptr::{addr_of, write_bytes};
+
+extern "C" {
+    static __stext: u8;
+    static __etext: u8;
+    static __srodata: u8;
+    static __erodata: u8;
+    static __sdata: u8;
+    static __edata: u8;
+    static __sram_text: u8;
+    static __eram_text: u8;
+    static __sbss: u8;
+    static __ebss: u8;
+    static __suninit: u8;
+    static __euninit: u8;
+    static _ram_start: u8;
+    static _ram_end: u8;
+    static __ssram4: u8;
+    static __esram4: u8;
+    static __ssram5: u8;
+    static __esram5: u8;
+}
+
+pub const SRAM4_SIZE: usize = 4096;
+pub const SRAM5_SIZE: usize = 4096;
+
+fn span(start: *const u8, end: *const u8) -> usize {
+    end as usize - start as usize
+}
+
+// main.rsの最初、SRAM4/SRAM5のstaticを触る前に一度だけ呼ぶ
+pub fn init() {
+    unsafe {
+        write_bytes(
+            addr_of!(__ssram4) as *mut u8,
+            0,
+            span(addr_of!(__ssram4), addr_of!(__esram4)),
+        );
+        write_bytes(
+            addr_of!(__ssram5) as *mut u8,
+            0,
+            span(addr_of!(__ssram5), addr_of!(__esram5)),
+        );
+    }
+}
+
+// 大きさはすべてバイト
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct MemoryLayout {
+    // フラッシュ
+    pub text: usize,
+    pub rodata: usize,
+    // RAM。dataはram_textを含む
+    pub data: usize,
+    pub ram_text: usize,
+    pub bss: usize,
+    pub uninit: usize,
+    pub heap: usize,
+    pub ram: usize,
+    // スタック。core1のスタックは.bssの内数
+    pub core0_stack: usize,
+    pub core1_stack: usize,
+    pub sram4: usize,
+    pub sram5: usize,
+}
+
+impl MemoryLayout {
+    // core0のスタックを除いたRAMの使用量
+    pub fn ram_static(&self) -> usize {
+        self.data + self.bss + self.uninit + self.heap
+    }
+}
+
+pub fn layout() -> MemoryLayout {
+    MemoryLayout {
+        text: span(addr_of!(__stext), addr_of!(__etext)),
+        rodata: span(addr_of!(__srodata), addr_of!(__erodata)),
+        data: span(addr_of!(__sdata), addr_of!(__edata)),
+        ram_text: span(addr_of!(__sram_text), addr_of!(__eram_text)),
+        bss: span(addr_of!(__sbss), addr_of!(__ebss)),
+        uninit: span(addr_of!(__suninit), addr_of!(__euninit)),
+        heap: heap::stats().size,
+        ram: span(addr_of!(_ram_start), addr_of!(_ram_end)),
+        core0_stack: stack::usage_core0().size,
+        core1_stack: stack::usage_core1().size,
+        sram4: span(addr_of!(__ssram4), addr_of!(__esram4)),
+        sram5: span(addr_of!(__ssram5), addr_of!(__esram5)),
+    }
+}
+
+pub fn handle_command(args: &mut Args) -> Result<Reply, CommandError> {
+    if args.next_opt().is_some() {
+        return Err(CommandError::InvalidArgument);
+    }
+    let m = layout();
+    Ok(ok_reply(|r| {
+        write!(
+            r,
+            " mem text={} rodata={} data={} ram_text={} bss={} uninit={} heap={} stack0={} stack1={} ram={}/{} sram4={}/{} sram5={}/{}",
+            m.text,
+            m.rodata,
+            m.data,
+            m.ram_text,
+            m.bss,
+            m.uninit,
+            m.heap,
+            m.core0_stack,
+            m.core1_stack,
+            m.ram_static(),
+            m.ram,
+            m.sram4,
+            SRAM4_SIZE,
+            m.sram5,
+            SRAM5_SIZE
+        )
+    }))
+}
@@ -0,0 +1,552 @@
+// USB MIDI (Audio Class 1.0のMIDIStreaming) のインターフェースとGPIO/ADCとの対応付け
+// cargoのmidiフィーチャーを有効にしたときだけインターフェースを追加する
+//   GPIO入力 (プルアップ、Lowで押下) の押下/離上をノートオン/オフとして送る
+//   ADC0〜ADC3の値をコントロールチェンジとして送る
+//   ホストから来たノートでLEDのパターンやGPIO出力を切り替える
+use crate::adc::{AdcInput, AdcState};
+use crate::command::{ok_reply, Args, CommandError, Reply};
+use crate::globals::{ADC, MIDI};
+use crate::led::{self, LedPattern};
+use crate::pinpool::{self, PoolPin};
+use core::fmt::Write;
+use cortex_m::interrupt;
+use embedded_hal::digital::{InputPin, OutputPin};
+use heapless::{Deque, Vec};
+use rp_pico::hal::adc::AdcPin;
+use rp_pico::hal::gpio::{DynPinId, DynPullType, FunctionSioInput, FunctionSioOutput, Pin};
+use usb_device::class_prelude::*;
+use usb_device::Result;
+
+pub const MAX_PACKET_SIZE: u16 = 64;
+pub const INTERFACE_NAME: &str = "RP2040 MIDI";
+pub const MAX_NOTE_INPUTS: usize = 8;
+pub const MAX_NOTE_ROUTES: usize = 8;
+// 送信待ちのUSB-MIDIイベントパケット数
+const TX_QUEUE_LEN: usize = 64;
+// 12bitのADC値で1段階 (128段階) 以上動いたらCCを送る
+const CC_HYSTERESIS: u16 = 32;
+
+const USB_CLASS_AUDIO: u8 = 0x01;
+const SUBCLASS_AUDIOCONTROL: u8 = 0x01;
+const SUBCLASS_MIDISTREAMING: u8 = 0x03;
+const CS_INTERFACE: u8 = 0x24;
+const CS_ENDPOINT: u8 = 0x25;
+const AC_HEADER: u8 = 0x01;
+const MS_HEADER: u8 = 0x01;
+const MS_GENERAL: u8 = 0x01;
+const MIDI_IN_JACK: u8 = 0x02;
+const MIDI_OUT_JACK: u8 = 0x03;
+const JACK_EMBEDDED: u8 = 0x01;
+const JACK_EXTERNAL: u8 = 0x02;
+// ホスト→デバイスのエンドポイントに繋がる入力ジャック
+const JACK_HOST_OUT: u8 = 1;
+// GPIO/ADCを表す外部入力ジャック
+const JACK_PORT_IN: u8 = 2;
+// デバイス→ホストのエンドポイントに繋がる出力ジャック
+const JACK_HOST_IN: u8 = 3;
+// ヘッダー7 + ジャック6+6+9 + エンドポイント(9+5)*2
+const MS_TOTAL_LEN: u16 = 56;
+
+// コンフィギュレーションディスクリプタは制御転送バッファに収める必要があり、usb-deviceでは256バイトが上限
+// 9 + CDC 66*2 + vendor 23 + HID 25 + MIDI 91 = 280バイトになり、IADや外部ジャックを削っても収まらない
+// CIのclippyは--all-featuresではなくフィーチャーの組み合わせごとに走らせる
+#[cfg(feature = "hid")]
+compile_error!("features `hid` and `midi` cannot be enabled together");
+
+const CIN_NOTE_OFF: u8 = 0x08;
+const CIN_NOTE_ON: u8 = 0x09;
+const CIN_CONTROL_CHANGE: u8 = 0x0B;
+
+// USB-MIDIイベントパケット (ケーブル番号は0固定)
+pub type Packet = [u8; 4];
+
+pub fn note_on(channel: u8, note: u8, velocity: u8) -> Packet {
+    [CIN_NOTE_ON, 0x90 | channel, note, velocity]
+}
+
+pub fn note_off(channel: u8, note: u8) -> Packet {
+    [CIN_NOTE_OFF, 0x80 | channel, note, 0]
+}
+
+pub fn control_change(channel: u8, cc: u8, value: u8) -> Packet {
+    [CIN_CONTROL_CHANGE, 0xB0 | channel, cc, value]
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum NoteEvent {
+    On(u8),
+    Off(u8),
+}
+
+// ノートオン/オフ以外は無視する。ベロシティ0のノートオンはノートオフ扱い
+pub fn decode(packet: &Packet) -> Option<NoteEvent> {
+    match packet[0] & 0x0F {
+        CIN_NOTE_ON if packet[3] != 0 => Some(NoteEvent::On(packet[2])),
+        CIN_NOTE_ON | CIN_NOTE_OFF => Some(NoteEvent::Off(packet[2])),
+        _ => None,
+    }
+}
+
+pub struct MidiClass<'a, B: UsbBus> {
+    ac_iface: InterfaceNumber,
+    ms_iface: InterfaceNumber,
+    name: StringIndex,
+    read_ep: EndpointOut<'a, B>,
+    write_ep: EndpointIn<'a, B>,
+}
+
+impl<'a, B: UsbBus> MidiClass<'a, B> {
+    pub fn new(alloc: &'a UsbBusAllocator<B>) -> Self {
+        Self {
+            ac_iface: alloc.interface(),
+            ms_iface: alloc.interface(),
+            name: alloc.string(),
+            read_ep: alloc.bulk(MAX_PACKET_SIZE),
+            write_ep: alloc.bulk(MAX_PACKET_SIZE),
+        }
+    }
+
+    // イベントパケットをまとめて1パケットで送る
+    pub fn write_packets(&mut self, data: &[u8]) -> Result<usize> {
+        let len = data.len().min(MAX_PACKET_SIZE as usize);
+        self.write_ep.write(&data[..len])
+    }
+
+    pub fn read_packets(&mut self, data: &mut [u8]) -> Result<usize> {
+        self.read_ep.read(data)
+    }
+}
+
+// Audio Class 1.0のエンドポイントはbRefreshとbSynchAddressを含む9バイト
+fn audio_endpoint_extra(buf: &mut [u8]) -> Result<usize> {
+    buf.get_mut(..2).ok_or(UsbError::BufferOverflow)?.fill(0);
+    Ok(2)
+}
+
+impl<B: UsbBus> UsbClass<B> for MidiClass<'_, B> {
+    fn get_configuration_descriptors(&self, writer: &mut DescriptorWriter) -> Result<()> {
+        writer.iad(self.ac_iface, 2, USB_CLASS_AUDIO, 0x00, 0x00, None)?;
+        // MIDIStreamingだけでもAudioControlインターフェースが必要
+        writer.interface(self.ac_iface, USB_CLASS_AUDIO, SUBCLASS_AUDIOCONTROL, 0)?;
+        writer.write(
+            CS_INTERFACE,
+            &[
+                AC_HEADER,
+                0x00,
+                0x01, // bcdADC 1.00
+                0x09,
+                0x00, // wTotalLength
+                0x01, // bInCollection
+                self.ms_iface.into(),
+            ],
+        )?;
+
+        writer.interface_alt(
+            self.ms_iface,
+            0,
+            USB_CLASS_AUDIO,
+            SUBCLASS_MIDISTREAMING,
+            0,
+            Some(self.name),
+        )?;
+        let [total_lo, total_hi] = MS_TOTAL_LEN.to_le_bytes();
+        writer.write(CS_INTERFACE, &[MS_HEADER, 0x00, 0x01, total_lo, total_hi])?;
+        writer.write(
+            CS_INTERFACE,
+            &[MIDI_IN_JACK, JACK_EMBEDDED, JACK_HOST_OUT, 0],
+        )?;
+        writer.write(
+            CS_INTERFACE,
+            &[MIDI_IN_JACK, JACK_EXTERNAL, JACK_PORT_IN, 0],
+        )?;
+        // ディスクリプタの容量を節約するため、ホストからの出力先の外部ジャックは省く
+        writer.write(
+            CS_INTERFACE,
+            &[
+                MIDI_OUT_JACK,
+                JACK_EMBEDDED,
+                JACK_HOST_IN,
+                0x01, // bNrInputPins
+                JACK_PORT_IN,
+                0x01, // baSourcePin
+                0,
+            ],
+        )?;
+        writer.endpoint_ex(&self.read_ep, audio_endpoint_extra)?;
+        writer.write(CS_ENDPOINT, &[MS_GENERAL, 0x01, JACK_HOST_OUT])?;
+        writer.endpoint_ex(&self.write_ep, audio_endpoint_extra)?;
+        writer.write(CS_ENDPOINT, &[MS_GENERAL, 0x01, JACK_HOST_IN])?;
+        Ok(())
+    }
+
+    fn get_string(&self, index: StringIndex, _lang_id: LangID) -> Option<&str> {
+        (index == self.name).then_some(INTERFACE_NAME)
+    }
+}
+
+type InPin = Pin<DynPinId, FunctionSioInput, DynPullType>;
+type OutPin = Pin<DynPinId, FunctionSioOutput, DynPullType>;
+
+struct NoteInput {
+    pin: InPin,
+    channel: u8,
+    note: u8,
+    pressed: bool,
+}
+
+struct CcInput {
+    pin: AdcPin<PoolPin>,
+    channel: u8,
+    cc: u8,
+    last_raw: Option<u16>,
+}
+
+enum NoteAction {
+    Led(LedPattern),
+    Gpio(OutPin),
+}
+
+struct NoteRoute {
+    note: u8,
+    action: NoteAction,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum MidiCommand {
+    // `midi gpio <pin> <ch 1-16> <note>`
+    GpioNote {
+        gpio: u8,
+        channel: u8,
+        note: u8,
+    },
+    // `midi adc <0-3> <ch 1-16> <cc>`
+    AdcCc {
+        input: AdcInput,
+        channel: u8,
+        cc: u8,
+    },
+    // `midi note <note> led <blink|slow|on|off>`
+    NoteLed {
+        note: u8,
+        pattern: LedPattern,
+    },
+    // `midi note <note> gpio <pin>`
+    NoteGpio {
+        note: u8,
+        gpio: u8,
+    },
+    Clear,
+    Status,
+}
+
+fn parse_channel(args: &mut Args) -> core::result::Result<u8, CommandError> {
+    match args.next_u32()? {
+        ch @ 1..=16 => Ok(ch as u8 - 1),
+        _ => Err(CommandError::OutOfRange),
+    }
+}
+
+fn parse_data_byte(args: &mut Args) -> core::result::Result<u8, CommandError> {
+    u8::try_from(args.next_u32()?)
+        .ok()
+        .filter(|&v| v < 0x80)
+        .ok_or(CommandError::OutOfRange)
+}
+
+fn parse_gpio(args: &mut Args) -> core::result::Result<u8, CommandError> {
+    u8::try_from(args.next_u32()?).map_err(|_| CommandError::OutOfRange)
+}
+
+impl MidiCommand {
+    pub fn parse(args: &mut Args) -> core::result::Result<Self, CommandError> {
+        match args.next_str()? {
+            "gpio" => Ok(MidiCommand::GpioNote {
+                gpio: parse_gpio(args)?,
+                channel: parse_channel(args)?,
+                note: parse_data_byte(args)?,
+            }),
+            "adc" => {
+                let input = AdcInput::parse(args.next_str()?)?;
+                // 温度センサーはCCにしない
+                if input == AdcInput::Temp {
+                    return Err(CommandError::InvalidArgument);
+                }
+                Ok(MidiCommand::AdcCc {
+                    input,
+                    channel: parse_channel(args)?,
+                    cc: parse_data_byte(args)?,
+                })
+            }
+            "note" => {
+                let note = parse_data_byte(args)?;
+                match args.next_str()? {
+                    "led" => Ok(MidiCommand::NoteLed {
+                        note,
+                        pattern: LedPattern::parse(args.next_str()?)
+                            .ok_or(CommandError::InvalidArgument)?,
+                    }),
+                    "gpio" => Ok(MidiCommand::NoteGpio {
+                        note,
+                        gpio: parse_gpio(args)?,
+                    }),
+                    _ => Err(CommandError::InvalidArgument),
+                }
+            }
+            "clear" => Ok(MidiCommand::Clear),
+            "status" => Ok(MidiCommand::Status),
+            _ => Err(CommandError::InvalidArgument),
+        }
+    }
+}
+
+pub struct MidiState {
+    inputs: Vec<NoteInput, MAX_NOTE_INPUTS>,
+    ccs: Vec<CcInput, { crate::adc::NUM_ADC_PINS as usize }>,
+    routes: Vec<NoteRoute, MAX_NOTE_ROUTES>,
+    tx: Deque<Packet, TX_QUEUE_LEN>,
+    received: u32,
+    dropped: u32,
+}
+
+impl Default for MidiState {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl MidiState {
+    pub fn new() -> Self {
+        Self {
+            inputs: Vec::new(),
+            ccs: Vec::new(),
+            routes: Vec::new(),
+            tx: Deque::new(),
+            received: 0,
+            dropped: 0,
+        }
+    }
+
+    pub fn execute(&mut self, cmd: MidiCommand) -> core::result::Result<Reply, CommandError> {
+        match cmd {
+            MidiCommand::GpioNote {
+                gpio,
+                channel,
+                note,
+            } => {
+                if self.inputs.is_full() {
+                    return Err(CommandError::Busy);
+                }
+                let mut pin = pinpool::take(gpio)?
+                    .try_into_function::<FunctionSioInput>()
+                    .map_err(|pin| {
+                        pinpool::give(pin);
+                        CommandError::InvalidArgument
+                    })?;
+                pin.set_pull_type(DynPullType::Up);
+                let _ = self.inputs.push(NoteInput {
+                    pin,
+                    channel,
+                    note,
+                    pressed: false,
+                });
+                Ok(ok_reply(|r| {
+                    write!(r, " midi gpio={} ch={} note={}", gpio, channel + 1, note)
+                }))
+            }
+            MidiCommand::AdcCc { input, channel, cc } => {
+                // ADCピンは4本なので容量が足りなくなることはない
+                let Some(pin) = AdcState::claim_pin(input)? else {
+                    return Err(CommandError::InvalidArgument);
+                };
+                let _ = self.ccs.push(CcInput {
+                    pin,
+                    channel,
+                    cc,
+                    last_raw: None,
+                });
+                Ok(ok_reply(|r| {
+                    write!(r, " midi adc={} ch={} cc={}", input, channel + 1, cc)
+                }))
+            }
+            MidiCommand::NoteLed { note, pattern } => {
+                self.add_route(note, NoteAction::Led(pattern))?;
+                Ok(ok_reply(|r| {
+                    write!(r, " midi note={} led={}", note, pattern.name())
+                }))
+            }
+            MidiCommand::NoteGpio { note, gpio } => {
+                let pin = pinpool::take(gpio)?
+                    .try_into_function::<FunctionSioOutput>()
+                    .map_err(|pin| {
+                        pinpool::give(pin);
+                        CommandError::InvalidArgument
+                    })?;
+                self.add_route(note, NoteAction::Gpio(pin))?;
+                Ok(ok_reply(|r| write!(r, " midi note={} gpio={}", note, gpio)))
+            }
+            MidiCommand::Clear => {
+                self.clear();
+                Ok(ok_reply(|r| r.write_str(" midi clear")))
+            }
+            MidiCommand::Status => Ok(ok_reply(|r| {
+                write!(
+                    r,
+                    " midi inputs={} ccs={} routes={} rx={} dropped={}",
+                    self.inputs.len(),
+                    self.ccs.len(),
+                    self.routes.len(),
+                    self.received,
+                    self.dropped
+                )
+            })),
+        }
+    }
+
+    fn add_route(
+        &mut self,
+        note: u8,
+        action: NoteAction,
+    ) -> core::result::Result<(), CommandError> {
+        if self.routes.is_full() || self.routes.iter().any(|r| r.note == note) {
+            if let NoteAction::Gpio(pin) = action {
+                pinpool::give(pin);
+            }
+            return Err(CommandError::Busy);
+        }
+        let _ = self.routes.push(NoteRoute { note, action });
+        Ok(())
+    }
+
+    fn clear(&mut self) {
+        while let Some(input) = self.inputs.pop() {
+            pinpool::give(input.pin);
+        }
+        while let Some(cc) = self.ccs.pop() {
+            AdcState::release_pin(Some(cc.pin));
+        }
+        while let Some(route) = self.routes.pop() {
+            match route.action {
+                NoteAction::Led(_) => led::set_pattern(LedPattern::Blink),
+                NoteAction::Gpio(pin) => pinpool::give(pin),
+            }
+        }
+        self.tx.clear();
+    }
+
+    fn send(&mut self, packet: Packet) {
+        if self.tx.push_back(packet).is_err() {
+            self.dropped = self.dropped.wrapping_add(1);
+        }
+    }
+
+    // core0の10ms割り込みでGPIOとADCを見て変化をイベントにする
+    fn poll(&mut self, mut adc: Option<&mut AdcState>) {
+        let mut events: Vec<Packet, { MAX_NOTE_INPUTS + crate::adc::NUM_ADC_PINS as usize }> =
+            Vec::new();
+        for input in self.inputs.iter_mut() {
+            let pressed = input.pin.is_low().unwrap_or(false);
+            if pressed != input.pressed {
+                input.pressed = pressed;
+                let packet = if pressed {
+                    note_on(input.channel, input.note, 0x7F)
+                } else {
+                    note_off(input.channel, input.note)
+                };
+                let _ = events.push(packet);
+            }
+        }
+        if let Some(adc) = adc.as_mut() {
+            for cc in self.ccs.iter_mut() {
+                let Some(raw) = adc.read_pin(&mut cc.pin) else {
+                    continue;
+                };
+                if cc
+                    .last_raw
+                    .is_some_and(|last| last.abs_diff(raw) < CC_HYSTERESIS)
+                {
+                    continue;
+                }
+                cc.last_raw = Some(raw);
+                let value = (raw >> (crate::adc::ADC_BITS - 7)) as u8;
+                let _ = events.push(control_change(cc.channel, cc.cc, value));
+            }
+        }
+        events.into_iter().for_each(|packet| self.send(packet));
+    }
+
+    fn handle_packet(&mut self, packet: &Packet) {
+        let Some(event) = decode(packet) else {
+            return;
+        };
+        self.received = self.received.wrapping_add(1);
+        let (note, on) = match event {
+            NoteEvent::On(note) => (note, true),
+            NoteEvent::Off(note) => (note, false),
+        };
+        for route in self.routes.iter_mut().filter(|r| r.note == note) {
+            match &mut route.action {
+                NoteAction::Led(pattern) => {
+                    led::set_pattern(if on { *pattern } else { LedPattern::Blink })
+                }
+                NoteAction::Gpio(pin) => {
+                    let _ = if on { pin.set_high() } else { pin.set_low() };
+                }
+            }
+        }
+    }
+
+    fn pump<B: UsbBus>(&mut self, class: &mut MidiClass<'_, B>) {
+        let mut buf = [0u8; MAX_PACKET_SIZE as usize];
+        if let Ok(n) = class.read_packets(&mut buf) {
+            buf[..n]
+                .as_chunks::<4>()
+                .0
+                .iter()
+                .for_each(|p| self.handle_packet(p));
+        }
+
+        if self.tx.is_empty() {
+            return;
+        }
+        let mut len = 0;
+        for packet in self.tx.iter().take(buf.len() / 4) {
+            buf[len..len + 4].copy_from_slice(packet);
+            len += 4;
+        }
+        // エンドポイントが空いていなければ次のポーリングで送る
+        if class.write_packets(&buf[..len]).is_ok() {
+            (0..len / 4).for_each(|_| {
+                self.tx.pop_front();
+            });
+        }
+    }
+}
+
+pub fn handle_command(args: &mut Args) -> core::result::Result<Reply, CommandError> {
+    let cmd = MidiCommand::parse(args)?;
+    interrupt::free(|cs| {
+        MIDI.borrow(cs)
+            .borrow_mut()
+            .as_mut()
+            .ok_or(CommandError::NotReady)?
+            .execute(cmd)
+    })
+}
+
+// core0の10ms割り込みから呼ぶ
+pub fn poll() {
+    interrupt::free(|cs| {
+        if let Some(midi) = MIDI.borrow(cs).borrow_mut().as_mut() {
+            midi.poll(ADC.borrow(cs).borrow_mut().as_mut());
+        }
+    });
+}
+
+// USBポーリングの直後に呼ぶ
+pub fn pump<B: UsbBus>(class: &mut MidiClass<'_, B>) {
+    interrupt::free(|cs| {
+        if let Some(midi) = MIDI.borrow(cs).borrow_mut().as_mut() {
+            midi.pump(class);
+        }
+    });
+}
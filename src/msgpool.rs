@@ -0,0 +1,237 @@
+// コア間で受け渡すメッセージのブロックのプール
+// キューにはブロックを指すハンドルだけを入れ、本文はプールに置く
+// 行の多くは短いので、64バイトの小ブロックを主にして、収まらない行だけ256バイトの大ブロックに置く
+// 向きごとにプールを分け、片方の向きが溢れてももう片方の行は捨てられないようにする
+// 空きブロックはビットマップで管理し、両コアから触るのでMessagePoolLockで守る
+// ハンドルには種類を持たせ、ユーザーの行と内部のメッセージを本文で見分けなくて済むようにする
+// ブロックの中身はハンドルを持っている側だけが触る
+use crate::command::{ok_reply, Args, CommandError, Reply};
+use crate::globals::MAX_MESSAGE_SIZE;
+use crate::spinlock::{self, MessagePoolLock};
+use core::cell::UnsafeCell;
+use core::fmt::Write;
+use core::ops::Deref;
+use cortex_m::interrupt;
+use heapless::String;
+
+pub const SMALL_BLOCK_SIZE: usize = 64;
+pub const SMALL_BLOCKS: usize = 24;
+pub const LARGE_BLOCKS: usize = 4;
+// 1つのプールに入るメッセージの数
+pub const POOL_BLOCKS: usize = SMALL_BLOCKS + LARGE_BLOCKS;
+// 空きのビットマップがu32なので32個まで
+const _: () = assert!(SMALL_BLOCKS <= 32 && LARGE_BLOCKS <= 32);
+
+type SmallBlock = String<SMALL_BLOCK_SIZE>;
+type LargeBlock = String<MAX_MESSAGE_SIZE>;
+
+const fn all_free(blocks: usize) -> u32 {
+    u32::MAX >> (32 - blocks)
+}
+
+// メッセージの種類
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum MessageKind {
+    // ホストから来た行や、ホストへ返す行
+    Text,
+    // core0でサンプリングしたADCの生の値 (adc.rs)
+    AdcSample,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum SizeClass {
+    Small,
+    Large,
+}
+
+struct PoolMeta {
+    // 1が空き
+    free_small: u32,
+    free_large: u32,
+    min_free_small: u32,
+    min_free_large: u32,
+    // 空きが無くて捨てたメッセージ数
+    drops: u32,
+}
+
+impl PoolMeta {
+    fn take(&mut self, class: SizeClass) -> Option<u8> {
+        let (free, min_free) = match class {
+            SizeClass::Small => (&mut self.free_small, &mut self.min_free_small),
+            SizeClass::Large => (&mut self.free_large, &mut self.min_free_large),
+        };
+        if *free == 0 {
+            return None;
+        }
+        let index = free.trailing_zeros();
+        *free &= !(1 << index);
+        *min_free = (*min_free).min(free.count_ones());
+        Some(index as u8)
+    }
+}
+
+pub struct MessagePool {
+    name: &'static str,
+    small: UnsafeCell<[SmallBlock; SMALL_BLOCKS]>,
+    large: UnsafeCell<[LargeBlock; LARGE_BLOCKS]>,
+    meta: UnsafeCell<PoolMeta>,
+}
+
+unsafe impl Sync for MessagePool {}
+
+impl MessagePool {
+    const fn new(name: &'static str) -> Self {
+        Self {
+            name,
+            small: UnsafeCell::new([const { SmallBlock::new() }; SMALL_BLOCKS]),
+            large: UnsafeCell::new([const { LargeBlock::new() }; LARGE_BLOCKS]),
+            meta: UnsafeCell::new(PoolMeta {
+                free_small: all_free(SMALL_BLOCKS),
+                free_large: all_free(LARGE_BLOCKS),
+                min_free_small: SMALL_BLOCKS as u32,
+                min_free_large: LARGE_BLOCKS as u32,
+                drops: 0,
+            }),
+        }
+    }
+
+    fn with_meta<R>(&self, f: impl FnOnce(&mut PoolMeta) -> R) -> R {
+        interrupt::free(|_| {
+            let _guard = spinlock::claim::<MessagePoolLock>();
+            f(unsafe { &mut *self.meta.get() })
+        })
+    }
+
+    fn small_block(&self, index: u8) -> *mut SmallBlock {
+        unsafe { (self.small.get() as *mut SmallBlock).add(index as usize) }
+    }
+
+    fn large_block(&self, index: u8) -> *mut LargeBlock {
+        unsafe { (self.large.get() as *mut LargeBlock).add(index as usize) }
+    }
+}
+
+pub static CORE0_TO_CORE1_POOL: MessagePool = MessagePool::new("c0to1");
+pub static CORE1_TO_CORE0_POOL: MessagePool = MessagePool::new("c1to0");
+
+// プールのブロックを指すハンドル。捨てるとブロックはプールに戻る
+pub struct MessageBox {
+    pool: &'static MessagePool,
+    kind: MessageKind,
+    class: SizeClass,
+    index: u8,
+}
+
+impl MessageBox {
+    // 小ブロックに収まれば小ブロック、埋まっていれば大ブロックに置く
+    // 空きが無ければNone (捨てた数として数える)
+    #[inline(never)]
+    #[link_section = ".ram_text"]
+    pub fn new(pool: &'static MessagePool, kind: MessageKind, msg: &str) -> Option<Self> {
+        let fits_small = msg.len() <= SMALL_BLOCK_SIZE;
+        let (class, index) = pool.with_meta(|meta| {
+            let taken = fits_small
+                .then(|| meta.take(SizeClass::Small).map(|i| (SizeClass::Small, i)))
+                .flatten()
+                .or_else(|| meta.take(SizeClass::Large).map(|i| (SizeClass::Large, i)));
+            if taken.is_none() {
+                meta.drops += 1;
+            }
+            taken
+        })?;
+        // 長さは確かめてあるので溢れない
+        unsafe {
+            match class {
+                SizeClass::Small => {
+                    let block = &mut *pool.small_block(index);
+                    block.clear();
+                    let _ = block.push_str(msg);
+                }
+                SizeClass::Large => {
+                    let block = &mut *pool.large_block(index);
+                    block.clear();
+                    let _ = block.push_str(msg);
+                }
+            }
+        }
+        Some(Self {
+            pool,
+            kind,
+            class,
+            index,
+        })
+    }
+
+    pub fn kind(&self) -> MessageKind {
+        self.kind
+    }
+}
+
+impl Deref for MessageBox {
+    type Target = str;
+
+    fn deref(&self) -> &str {
+        unsafe {
+            match self.class {
+                SizeClass::Small => (*self.pool.small_block(self.index)).as_str(),
+                SizeClass::Large => (*self.pool.large_block(self.index)).as_str(),
+            }
+        }
+    }
+}
+
+impl Drop for MessageBox {
+    #[inline(never)]
+    #[link_section = ".ram_text"]
+    fn drop(&mut self) {
+        let (class, index) = (self.class, self.index);
+        self.pool.with_meta(|meta| match class {
+            SizeClass::Small => meta.free_small |= 1 << index,
+            SizeClass::Large => meta.free_large |= 1 << index,
+        });
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct PoolStats {
+    pub name: &'static str,
+    pub free_small: usize,
+    pub min_free_small: usize,
+    pub free_large: usize,
+    pub min_free_large: usize,
+    pub drops: u32,
+}
+
+pub fn stats(pool: &MessagePool) -> PoolStats {
+    pool.with_meta(|meta| PoolStats {
+        name: pool.name,
+        free_small: meta.free_small.count_ones() as usize,
+        min_free_small: meta.min_free_small as usize,
+        free_large: meta.free_large.count_ones() as usize,
+        min_free_large: meta.min_free_large as usize,
+        drops: meta.drops,
+    })
+}
+
+pub fn handle_command(args: &mut Args) -> Result<Reply, CommandError> {
+    if args.next_opt().is_some() {
+        return Err(CommandError::InvalidArgument);
+    }
+    let pools = [stats(&CORE0_TO_CORE1_POOL), stats(&CORE1_TO_CORE0_POOL)];
+    Ok(ok_reply(|r| {
+        write!(
+            r,
+            " pool small={}x{} large={}x{}",
+            SMALL_BLOCKS, SMALL_BLOCK_SIZE, LARGE_BLOCKS, MAX_MESSAGE_SIZE
+        )?;
+        // 向き=小の空き/最小,大の空き/最小,捨てた数
+        for s in &pools {
+            write!(
+                r,
+                " {}={}/{},{}/{},{}",
+                s.name, s.free_small, s.min_free_small, s.free_large, s.min_free_large, s.drops
+            )?;
+        }
+        Ok(())
+    }))
+}
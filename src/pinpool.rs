@@ -0,0 +1,97 @@
+// コマンドから実行時に選ばれるGPIOを貸し出すためのピンプール
+// 各機能はピンを借りてtry_into_functionで機能を切り替え、使い終わったら返却する
+// 返却時に機能はNull、プルはプルダウンに戻す
+use crate::command::CommandError;
+use crate::globals::PIN_POOL;
+use cortex_m::interrupt;
+use rp_pico::hal::gpio::{
+    self, DynBankId, DynFunction, DynPinId, DynPullType, Function, FunctionNull, Pin, PullDown,
+    PullType,
+};
+
+// DynPinIdのピンはDynFunctionでしか型検査を通らないので、プールでは全て動的型で持つ
+pub type PoolPin = Pin<DynPinId, DynFunction, DynPullType>;
+// BSPから受け取った直後のピン
+pub type BootPin = Pin<DynPinId, FunctionNull, PullDown>;
+
+pub const NUM_BANK0_PINS: usize = 30;
+
+pub struct PinPool {
+    pins: [Option<PoolPin>; NUM_BANK0_PINS],
+}
+
+impl PinPool {
+    pub fn new(pins: impl IntoIterator<Item = BootPin>) -> Self {
+        let mut pool = Self {
+            pins: [const { None }; NUM_BANK0_PINS],
+        };
+        for pin in pins {
+            pool.give(pin);
+        }
+        pool
+    }
+
+    pub fn take(&mut self, num: u8) -> Result<PoolPin, CommandError> {
+        self.pins
+            .get_mut(num as usize)
+            .ok_or(CommandError::OutOfRange)?
+            .take()
+            .ok_or(CommandError::PinBusy)
+    }
+
+    pub fn give<F: Function, P: PullType>(&mut self, pin: Pin<DynPinId, F, P>) {
+        let mut pin = into_pool_pin(pin);
+        let _ = pin.try_set_function(DynFunction::Null);
+        pin.set_pull_type(DynPullType::Down);
+        let num = pin.id().num as usize;
+        self.pins[num] = Some(pin);
+    }
+
+    pub fn is_free(&self, num: u8) -> bool {
+        matches!(self.pins.get(num as usize), Some(Some(_)))
+    }
+}
+
+pub fn take(num: u8) -> Result<PoolPin, CommandError> {
+    interrupt::free(|cs| {
+        PIN_POOL
+            .borrow(cs)
+            .borrow_mut()
+            .as_mut()
+            .ok_or(CommandError::NotReady)?
+            .take(num)
+    })
+}
+
+// 機能やプルの型が何であっても動的型に戻す
+pub fn into_pool_pin<F: Function, P: PullType>(pin: Pin<DynPinId, F, P>) -> PoolPin {
+    match pin.try_into_function::<DynFunction>() {
+        Ok(pin) => pin.into_pull_type::<DynPullType>(),
+        // DynFunctionへの変換は常に有効なピン機能なので失敗しない
+        Err(_) => unreachable!(),
+    }
+}
+
+pub fn give<F: Function, P: PullType>(pin: Pin<DynPinId, F, P>) {
+    interrupt::free(|cs| {
+        if let Some(pool) = PIN_POOL.borrow(cs).borrow_mut().as_mut() {
+            pool.give(pin);
+        }
+    });
+}
+
+// HALのValidatedPinのように包まれて元のピンを取り出せないとき、包みごと捨ててから番号でピンを作り直して返す
+// 呼ぶ側はnumsがwrappedの持っていたピンの番号で、他にそのピンを持っているところが無いことを保証する
+pub(crate) unsafe fn give_wrapped<T>(wrapped: T, nums: &[u8]) {
+    drop(wrapped);
+    for &num in nums {
+        // 元のピンは捨てたので同じピンが二重に存在することはない
+        let pin = unsafe {
+            gpio::new_pin(DynPinId {
+                bank: DynBankId::Bank0,
+                num,
+            })
+        };
+        give(pin);
+    }
+}
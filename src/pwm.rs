@@ -0,0 +1,562 @@
+// PWM出力
+// GPIOnはスライス(n/2)%8のチャンネルA(偶数)/B(奇数)に繋がる
+// 周波数と位相補正モードはスライス単位の設定なので、同じスライスのA/Bは同じ周波数になる
+use crate::command::{ok_reply, parse_milli, parse_u32, write_milli, Args, CommandError, Reply};
+use crate::freqcounter::FreqCounter;
+use crate::globals::{MAX_MESSAGE_SIZE, PWM};
+use crate::pinpool::{self, NUM_BANK0_PINS};
+use core::fmt::Write;
+use cortex_m::interrupt;
+use heapless::String;
+use rp_pico::hal::gpio::{DynPinId, DynPullType, FunctionPwm, Pin};
+use rp_pico::hal::pac;
+
+pub const NUM_SLICES: usize = 8;
+pub const MAX_TOP: u32 = 0xFFFF;
+// 分周比は8.4固定小数点 (1.0〜255+15/16)
+const DIV_FRAC_SCALE: u64 = 16;
+const MIN_DIV: u64 = DIV_FRAC_SCALE;
+const MAX_DIV: u64 = 255 * DIV_FRAC_SCALE + 15;
+pub const SERVO_DEFAULT_PERIOD_US: u32 = 20_000;
+
+pub type PwmPin = Pin<DynPinId, FunctionPwm, DynPullType>;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum PwmChannel {
+    A,
+    B,
+}
+
+pub fn slice_of(gpio: u8) -> (usize, PwmChannel) {
+    let slice = (gpio as usize / 2) % NUM_SLICES;
+    let channel = if gpio.is_multiple_of(2) {
+        PwmChannel::A
+    } else {
+        PwmChannel::B
+    };
+    (slice, channel)
+}
+
+// スライスの分周とTOPの組み合わせ
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct PwmConfig {
+    pub div_int: u8,
+    pub div_frac: u8,
+    pub top: u16,
+    pub phase_correct: bool,
+}
+
+impl PwmConfig {
+    // 目標周波数に対して、分解能(TOP)が最大になる最小の分周比を選ぶ
+    pub fn for_frequency(
+        sys_hz: u32,
+        freq_millihz: u64,
+        phase_correct: bool,
+    ) -> Result<Self, CommandError> {
+        if freq_millihz == 0 {
+            return Err(CommandError::OutOfRange);
+        }
+        let mode = if phase_correct { 2 } else { 1 };
+        // 1周期あたりのシステムクロック数 (分周の小数部の分だけ16倍)
+        let scaled_cycles = sys_hz as u64 * 1000 * DIV_FRAC_SCALE / (freq_millihz * mode);
+        let div = scaled_cycles.div_ceil(MAX_TOP as u64 + 1).max(MIN_DIV);
+        if div > MAX_DIV {
+            return Err(CommandError::OutOfRange);
+        }
+        let wrap = (scaled_cycles + div / 2) / div;
+        // TOPが1未満だとデューティーが表現できない
+        if wrap < 2 {
+            return Err(CommandError::OutOfRange);
+        }
+        Ok(Self {
+            div_int: (div / DIV_FRAC_SCALE) as u8,
+            div_frac: (div % DIV_FRAC_SCALE) as u8,
+            top: (wrap.min(MAX_TOP as u64 + 1) - 1) as u16,
+            phase_correct,
+        })
+    }
+
+    pub fn period_ticks(&self) -> u32 {
+        self.top as u32 + 1
+    }
+
+    // 実際に得られる周波数 (mHz)
+    pub fn actual_millihz(&self, sys_hz: u32) -> u64 {
+        let div = self.div_int as u64 * DIV_FRAC_SCALE + self.div_frac as u64;
+        let mode = if self.phase_correct { 2 } else { 1 };
+        sys_hz as u64 * 1000 * DIV_FRAC_SCALE / (div * self.period_ticks() as u64 * mode)
+    }
+
+    // デューティー比(ミリ%)からコンペア値を求める
+    pub fn level_for_duty(&self, duty_millipercent: u32) -> u16 {
+        let level = self.period_ticks() as u64 * duty_millipercent as u64 / 100_000;
+        self.clamp_level(level)
+    }
+
+    // サーボ用: 周期に対するパルス幅からコンペア値を求める
+    pub fn level_for_pulse(&self, pulse_us: u32, period_us: u32) -> u16 {
+        let level = self.period_ticks() as u64 * pulse_us as u64 / period_us as u64;
+        self.clamp_level(level)
+    }
+
+    // TOP=0xFFFFだと1周期が65536カウントでu16に入らないので、100%は0xFFFFで止める
+    fn clamp_level(&self, level: u64) -> u16 {
+        level.min(self.period_ticks() as u64).min(u16::MAX as u64) as u16
+    }
+}
+
+// `1000` や `50.5` をmHzにする
+pub fn parse_millihz(s: &str) -> Result<u64, CommandError> {
+    if s.contains('.') {
+        parse_milli(s).map(|v| v as u64)
+    } else {
+        parse_u32(s).map(|v| v as u64 * 1000)
+    }
+}
+
+pub fn write_config(out: &mut impl Write, config: &PwmConfig, sys_hz: u32) -> core::fmt::Result {
+    let millihz = config.actual_millihz(sys_hz);
+    write!(
+        out,
+        " div={}+{}/16 top={} pc={} freq_hz={}.{:03}",
+        config.div_int,
+        config.div_frac,
+        config.top,
+        config.phase_correct as u8,
+        millihz / 1000,
+        millihz % 1000
+    )
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum PwmCommand {
+    Set {
+        gpio: u8,
+        freq_millihz: u64,
+        duty_millipercent: u32,
+        phase_correct: bool,
+    },
+    Duty {
+        gpio: u8,
+        duty_millipercent: u32,
+    },
+    Servo {
+        gpio: u8,
+        pulse_us: u32,
+        period_us: u32,
+    },
+    Off {
+        gpio: u8,
+    },
+    Calc {
+        freq_millihz: u64,
+        phase_correct: bool,
+    },
+}
+
+fn parse_gpio(args: &mut Args) -> Result<u8, CommandError> {
+    let gpio = args.next_u32()?;
+    if gpio as usize >= NUM_BANK0_PINS {
+        return Err(CommandError::OutOfRange);
+    }
+    Ok(gpio as u8)
+}
+
+fn parse_duty(args: &mut Args) -> Result<u32, CommandError> {
+    let duty = args.next_milli()?;
+    if duty > 100_000 {
+        return Err(CommandError::OutOfRange);
+    }
+    Ok(duty)
+}
+
+fn parse_phase_correct(args: &mut Args) -> Result<bool, CommandError> {
+    match args.next_opt() {
+        None => Ok(false),
+        Some("pc") => Ok(true),
+        Some(_) => Err(CommandError::InvalidArgument),
+    }
+}
+
+impl PwmCommand {
+    pub fn parse(args: &mut Args) -> Result<Self, CommandError> {
+        match args.next_str()? {
+            "set" => Ok(PwmCommand::Set {
+                gpio: parse_gpio(args)?,
+                freq_millihz: parse_millihz(args.next_str()?)?,
+                duty_millipercent: parse_duty(args)?,
+                phase_correct: parse_phase_correct(args)?,
+            }),
+            "duty" => Ok(PwmCommand::Duty {
+                gpio: parse_gpio(args)?,
+                duty_millipercent: parse_duty(args)?,
+            }),
+            "servo" => {
+                let gpio = parse_gpio(args)?;
+                let pulse_us = args.next_u32()?;
+                let period_us = match args.next_opt() {
+                    Some(s) => parse_u32(s)?,
+                    None => SERVO_DEFAULT_PERIOD_US,
+                };
+                if period_us == 0 || pulse_us > period_us {
+                    return Err(CommandError::OutOfRange);
+                }
+                Ok(PwmCommand::Servo {
+                    gpio,
+                    pulse_us,
+                    period_us,
+                })
+            }
+            "off" => Ok(PwmCommand::Off {
+                gpio: parse_gpio(args)?,
+            }),
+            "calc" => Ok(PwmCommand::Calc {
+                freq_millihz: parse_millihz(args.next_str()?)?,
+                phase_correct: parse_phase_correct(args)?,
+            }),
+            _ => Err(CommandError::InvalidArgument),
+        }
+    }
+}
+
+#[derive(Default)]
+struct SliceState {
+    config: Option<PwmConfig>,
+    pins: [Option<PwmPin>; 2],
+}
+
+impl SliceState {
+    fn pin(&mut self, channel: PwmChannel) -> &mut Option<PwmPin> {
+        &mut self.pins[channel as usize]
+    }
+
+    fn is_idle(&self) -> bool {
+        self.pins.iter().all(|p| p.is_none())
+    }
+}
+
+pub struct PwmState {
+    pwm: pac::PWM,
+    sys_hz: u32,
+    slices: [SliceState; NUM_SLICES],
+    // 周波数カウンタに使っているスライス
+    counter: Option<FreqCounter>,
+}
+
+impl PwmState {
+    pub fn new(pwm: pac::PWM, sys_hz: u32) -> Self {
+        Self {
+            pwm,
+            sys_hz,
+            slices: Default::default(),
+            counter: None,
+        }
+    }
+
+    pub fn sys_hz(&self) -> u32 {
+        self.sys_hz
+    }
+
+    pub fn execute(&mut self, cmd: PwmCommand) -> Result<Reply, CommandError> {
+        match cmd {
+            PwmCommand::Set {
+                gpio,
+                freq_millihz,
+                duty_millipercent,
+                phase_correct,
+            } => {
+                let config = PwmConfig::for_frequency(self.sys_hz, freq_millihz, phase_correct)?;
+                self.output(gpio, config, config.level_for_duty(duty_millipercent))?;
+                let sys_hz = self.sys_hz;
+                Ok(ok_reply(|r| {
+                    write!(r, " pwm set gpio={}", gpio)?;
+                    write_config(r, &config, sys_hz)
+                }))
+            }
+            PwmCommand::Duty {
+                gpio,
+                duty_millipercent,
+            } => {
+                let (slice, channel) = slice_of(gpio);
+                let state = &mut self.slices[slice];
+                if state.pin(channel).as_ref().map(|p| p.id().num) != Some(gpio) {
+                    return Err(CommandError::NotReady);
+                }
+                let config = state.config.ok_or(CommandError::NotReady)?;
+                self.set_level(slice, channel, config.level_for_duty(duty_millipercent));
+                Ok(ok_reply(|r| {
+                    r.write_str(" pwm duty=")?;
+                    write_milli(r, duty_millipercent as i32)
+                }))
+            }
+            PwmCommand::Servo {
+                gpio,
+                pulse_us,
+                period_us,
+            } => {
+                let freq_millihz = 1_000_000_000 / period_us as u64;
+                let config = PwmConfig::for_frequency(self.sys_hz, freq_millihz, false)?;
+                self.output(gpio, config, config.level_for_pulse(pulse_us, period_us))?;
+                let sys_hz = self.sys_hz;
+                Ok(ok_reply(|r| {
+                    write!(r, " pwm servo gpio={} pulse_us={}", gpio, pulse_us)?;
+                    write_config(r, &config, sys_hz)
+                }))
+            }
+            PwmCommand::Off { gpio } => {
+                self.release(gpio)?;
+                Ok(ok_reply(|r| write!(r, " pwm off gpio={}", gpio)))
+            }
+            PwmCommand::Calc {
+                freq_millihz,
+                phase_correct,
+            } => {
+                let config = PwmConfig::for_frequency(self.sys_hz, freq_millihz, phase_correct)?;
+                let sys_hz = self.sys_hz;
+                Ok(ok_reply(|r| {
+                    r.write_str(" pwm calc")?;
+                    write_config(r, &config, sys_hz)
+                }))
+            }
+        }
+    }
+
+    fn output(&mut self, gpio: u8, config: PwmConfig, level: u16) -> Result<(), CommandError> {
+        let (slice, channel) = slice_of(gpio);
+        let other = match channel {
+            PwmChannel::A => PwmChannel::B,
+            PwmChannel::B => PwmChannel::A,
+        };
+        if self.counter.as_ref().map(|c| c.slice) == Some(slice) {
+            return Err(CommandError::Busy);
+        }
+        let state = &mut self.slices[slice];
+        // 相方のチャンネルが別の周波数で動いているスライスは変更できない
+        if state.pin(other).is_some() && state.config != Some(config) {
+            return Err(CommandError::Busy);
+        }
+        let owned = state.pin(channel).as_ref().map(|p| p.id().num) == Some(gpio);
+        if !owned {
+            // 同じチャンネルの別ピン(GPIOnとn+16)が使っていれば返却する
+            if let Some(pin) = state.pin(channel).take() {
+                pinpool::give(pin);
+            }
+            let pin = pinpool::take(gpio)?
+                .try_into_function::<FunctionPwm>()
+                .map_err(|pin| {
+                    pinpool::give(pin);
+                    CommandError::InvalidArgument
+                })?;
+            *state.pin(channel) = Some(pin);
+        }
+        if state.config != Some(config) {
+            state.config = Some(config);
+            self.apply_config(slice, &config);
+        }
+        self.set_level(slice, channel, level);
+        Ok(())
+    }
+
+    fn apply_config(&mut self, slice: usize, config: &PwmConfig) {
+        let regs = self.pwm.ch(slice);
+        regs.csr().modify(|_, w| w.en().clear_bit());
+        regs.div()
+            .write(|w| unsafe { w.int().bits(config.div_int).frac().bits(config.div_frac) });
+        regs.top().write(|w| unsafe { w.top().bits(config.top) });
+        regs.ctr().write(|w| unsafe { w.ctr().bits(0) });
+        regs.csr().modify(|_, w| {
+            w.ph_correct()
+                .bit(config.phase_correct)
+                .divmode()
+                .div()
+                .en()
+                .set_bit()
+        });
+    }
+
+    fn set_level(&mut self, slice: usize, channel: PwmChannel, level: u16) {
+        let cc = self.pwm.ch(slice).cc();
+        match channel {
+            PwmChannel::A => cc.modify(|_, w| unsafe { w.a().bits(level) }),
+            PwmChannel::B => cc.modify(|_, w| unsafe { w.b().bits(level) }),
+        }
+    }
+
+    fn release(&mut self, gpio: u8) -> Result<(), CommandError> {
+        let (slice, channel) = slice_of(gpio);
+        let state = &mut self.slices[slice];
+        if state.pin(channel).as_ref().map(|p| p.id().num) != Some(gpio) {
+            return Err(CommandError::NotReady);
+        }
+        if let Some(pin) = state.pin(channel).take() {
+            pinpool::give(pin);
+        }
+        self.set_level(slice, channel, 0);
+        let state = &mut self.slices[slice];
+        if state.is_idle() {
+            state.config = None;
+            self.pwm.ch(slice).csr().modify(|_, w| w.en().clear_bit());
+        }
+        Ok(())
+    }
+
+    pub fn counter(&self) -> Option<&FreqCounter> {
+        self.counter.as_ref()
+    }
+
+    // 出力に使っていないスライスだけカウンタにできる
+    pub fn start_counter(
+        &mut self,
+        gpio: u8,
+        gate_ms: u32,
+        stream: bool,
+    ) -> Result<(), CommandError> {
+        let (slice, _) = slice_of(gpio);
+        if self.counter.is_some() || !self.slices[slice].is_idle() {
+            return Err(CommandError::Busy);
+        }
+        let pin = pinpool::take(gpio)?
+            .try_into_function::<FunctionPwm>()
+            .map_err(|pin| {
+                pinpool::give(pin);
+                CommandError::InvalidArgument
+            })?;
+        let mut counter = FreqCounter::new(slice, pin, gate_ms, stream);
+        counter.start(&self.pwm);
+        self.counter = Some(counter);
+        Ok(())
+    }
+
+    pub fn stop_counter(&mut self) -> Result<(), CommandError> {
+        let mut counter = self.counter.take().ok_or(CommandError::NotReady)?;
+        counter.stop(&self.pwm);
+        pinpool::give(counter.pin);
+        Ok(())
+    }
+
+    pub fn wrap_counter(&mut self) {
+        if let Some(counter) = self.counter.as_mut() {
+            counter.take_wrap(&self.pwm);
+        }
+    }
+
+    pub fn tick_counter(&mut self) -> Option<String<MAX_MESSAGE_SIZE>> {
+        let sys_hz = self.sys_hz;
+        self.counter.as_mut()?.tick(&self.pwm, sys_hz)
+    }
+}
+
+pub fn handle_command(args: &mut Args) -> Result<Reply, CommandError> {
+    let cmd = PwmCommand::parse(args)?;
+    interrupt::free(|cs| {
+        PWM.borrow(cs)
+            .borrow_mut()
+            .as_mut()
+            .ok_or(CommandError::NotReady)?
+            .execute(cmd)
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const SYS_HZ: u32 = 125_000_000;
+
+    #[test]
+    fn divider_is_one_when_top_fits() {
+        let config = PwmConfig::for_frequency(SYS_HZ, 25_000_000, false).unwrap();
+        assert_eq!((config.div_int, config.div_frac, config.top), (1, 0, 4999));
+        assert_eq!(config.actual_millihz(SYS_HZ), 25_000_000);
+
+        // 位相補正では1周期で上下するので同じ周波数でTOPが半分
+        let config = PwmConfig::for_frequency(SYS_HZ, 25_000_000, true).unwrap();
+        assert_eq!((config.div_int, config.div_frac, config.top), (1, 0, 2499));
+        assert_eq!(config.actual_millihz(SYS_HZ), 25_000_000);
+    }
+
+    #[test]
+    fn fractional_divider_keeps_top_near_max() {
+        let config = PwmConfig::for_frequency(SYS_HZ, 1_000_000, false).unwrap();
+        assert_eq!(
+            (config.div_int, config.div_frac, config.top),
+            (1, 15, 64515)
+        );
+        assert_eq!(config.actual_millihz(SYS_HZ), 1_000_002);
+    }
+
+    #[test]
+    fn smallest_divider_with_actual_frequency_close_to_target() {
+        for hz in [8, 50, 440, 1_000, 20_000, 100_000, 1_000_000, 10_000_000] {
+            for phase_correct in [false, true] {
+                let target = hz as u64 * 1000;
+                let config = PwmConfig::for_frequency(SYS_HZ, target, phase_correct).unwrap();
+                let actual = config.actual_millihz(SYS_HZ);
+                // 誤差はTOPの丸めの1カウント分と、mHzへの切り捨ての分まで
+                let tolerance = target / config.period_ticks() as u64 + 1;
+                assert!(actual.abs_diff(target) <= tolerance, "{hz} Hz -> {actual}");
+                // 分周比を1/16下げるとTOPに収まらない
+                let div = config.div_int as u64 * DIV_FRAC_SCALE + config.div_frac as u64;
+                if div > MIN_DIV {
+                    let mode = if phase_correct { 2 } else { 1 };
+                    let cycles = SYS_HZ as u64 * 1000 * DIV_FRAC_SCALE / (target * mode);
+                    assert!(cycles.div_ceil(div - 1) > MAX_TOP as u64 + 1, "{hz} Hz");
+                }
+            }
+        }
+    }
+
+    #[test]
+    fn out_of_range_frequencies() {
+        assert_eq!(
+            PwmConfig::for_frequency(SYS_HZ, 0, false),
+            Err(CommandError::OutOfRange)
+        );
+        // 最大の分周比でもTOPに収まらない
+        assert_eq!(
+            PwmConfig::for_frequency(SYS_HZ, 7_000, false),
+            Err(CommandError::OutOfRange)
+        );
+        assert!(PwmConfig::for_frequency(SYS_HZ, 8_000, false).is_ok());
+        // TOPが1未満になりデューティーが表現できない
+        assert_eq!(
+            PwmConfig::for_frequency(SYS_HZ, 100_000_000_000, false),
+            Err(CommandError::OutOfRange)
+        );
+        let config = PwmConfig::for_frequency(SYS_HZ, 62_500_000_000, false).unwrap();
+        assert_eq!(config.top, 1);
+    }
+
+    #[test]
+    fn levels_are_clamped_to_period() {
+        let config = PwmConfig::for_frequency(SYS_HZ, 25_000_000, false).unwrap();
+        assert_eq!(config.level_for_duty(0), 0);
+        assert_eq!(config.level_for_duty(50_000), 2500);
+        assert_eq!(config.level_for_duty(100_000), 5000);
+        assert_eq!(config.level_for_duty(150_000), 5000);
+        assert_eq!(config.level_for_pulse(1_500, 20_000), 375);
+        assert_eq!(config.level_for_pulse(30_000, 20_000), 5000);
+    }
+
+    #[test]
+    fn full_duty_with_max_top_does_not_wrap() {
+        let config = PwmConfig::for_frequency(SYS_HZ, 1_907_349, false).unwrap();
+        assert_eq!(
+            (config.div_int, config.div_frac, config.top),
+            (1, 0, 0xFFFF)
+        );
+        assert_eq!(config.level_for_duty(100_000), u16::MAX);
+        assert_eq!(config.level_for_duty(150_000), u16::MAX);
+        assert_eq!(config.level_for_duty(50_000), 32768);
+        assert_eq!(config.level_for_pulse(20_000, 20_000), u16::MAX);
+    }
+
+    #[test]
+    fn parses_frequencies() {
+        assert_eq!(parse_millihz("1000"), Ok(1_000_000));
+        assert_eq!(parse_millihz("50.5"), Ok(50_500));
+        assert_eq!(parse_millihz("0x10"), Ok(16_000));
+        assert_eq!(parse_millihz("1.2345"), Err(CommandError::InvalidArgument));
+        assert_eq!(parse_millihz("fast"), Err(CommandError::InvalidArgument));
+    }
+}
@@ -0,0 +1,182 @@
+// ウォッチドッグによる再起動と、ROMのUSBブートローダー(BOOTSEL)への再起動
+// 誤操作を避けるため、`reboot [bootsel]` でトークンを受け取り、期限内に
+// `reboot [bootsel] <token>` と送ったときだけ実行する
+// 応答がホストに届くように、実行はcore0の10ms割り込みで少し待ってから行う
+use crate::command::{ok_reply, Args, CommandError, Reply};
+use crate::globals::{REBOOT, WATCHDOG};
+use crate::supervisor;
+use crate::timer;
+use core::fmt::Write;
+use cortex_m::interrupt;
+use rp_pico::hal::{fugit::MicrosDurationU32, rom_data};
+
+pub const TOKEN_TIMEOUT_US: u32 = 10_000_000;
+pub const REBOOT_DELAY_US: u32 = 100_000;
+// BOOTSEL中にアクセスランプとして使うピン (オンボードLED)
+const BOOTSEL_ACTIVITY_PIN: u32 = 25;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum RebootMode {
+    Normal,
+    Bootsel,
+}
+
+impl RebootMode {
+    pub fn name(&self) -> &'static str {
+        match self {
+            RebootMode::Normal => "normal",
+            RebootMode::Bootsel => "bootsel",
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum RebootCommand {
+    // トークンの発行
+    Request(RebootMode),
+    Confirm(RebootMode, u16),
+}
+
+impl RebootCommand {
+    pub fn parse(args: &mut Args) -> Result<Self, CommandError> {
+        let mut mode = RebootMode::Normal;
+        let mut token = args.next_opt();
+        if token == Some("bootsel") {
+            mode = RebootMode::Bootsel;
+            token = args.next_opt();
+        }
+        match token {
+            None => Ok(RebootCommand::Request(mode)),
+            Some(s) => {
+                let token =
+                    u16::from_str_radix(s, 16).map_err(|_| CommandError::InvalidArgument)?;
+                Ok(RebootCommand::Confirm(mode, token))
+            }
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+struct Pending {
+    mode: RebootMode,
+    token: u16,
+    issued_us: u32,
+}
+
+#[derive(Debug, Default)]
+pub struct RebootState {
+    pending: Option<Pending>,
+    scheduled: Option<(RebootMode, u32)>,
+}
+
+// 推測されにくい必要はないので、タイマーの値を混ぜて0以外にする
+fn make_token(now_us: u32) -> u16 {
+    let mixed = (now_us ^ (now_us >> 16)).wrapping_mul(0x9E37_79B1);
+    ((mixed >> 16) as u16).max(1)
+}
+
+impl RebootState {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    pub fn execute(&mut self, cmd: RebootCommand, now_us: u32) -> Result<Reply, CommandError> {
+        match cmd {
+            RebootCommand::Request(mode) => {
+                let token = make_token(now_us);
+                self.pending = Some(Pending {
+                    mode,
+                    token,
+                    issued_us: now_us,
+                });
+                Ok(ok_reply(|r| {
+                    write!(
+                        r,
+                        " reboot {} token={:04x} expires_ms={}",
+                        mode.name(),
+                        token,
+                        TOKEN_TIMEOUT_US / 1000
+                    )
+                }))
+            }
+            RebootCommand::Confirm(mode, token) => {
+                // 間違えたトークンでも使い捨てにする
+                let pending = self.pending.take().ok_or(CommandError::NotReady)?;
+                if pending.mode != mode
+                    || pending.token != token
+                    || now_us.wrapping_sub(pending.issued_us) > TOKEN_TIMEOUT_US
+                {
+                    return Err(CommandError::InvalidArgument);
+                }
+                self.scheduled = Some((mode, now_us));
+                Ok(ok_reply(|r| {
+                    write!(
+                        r,
+                        " reboot {} in_ms={}",
+                        mode.name(),
+                        REBOOT_DELAY_US / 1000
+                    )
+                }))
+            }
+        }
+    }
+
+    // 実行する時刻になっていればモードを返す
+    pub fn due(&self, now_us: u32) -> Option<RebootMode> {
+        match self.scheduled {
+            Some((mode, at)) if now_us.wrapping_sub(at) >= REBOOT_DELAY_US => Some(mode),
+            _ => None,
+        }
+    }
+}
+
+pub fn handle_command(args: &mut Args) -> Result<Reply, CommandError> {
+    let cmd = RebootCommand::parse(args)?;
+    let now = timer::now_us();
+    interrupt::free(|cs| {
+        REBOOT
+            .borrow(cs)
+            .borrow_mut()
+            .as_mut()
+            .ok_or(CommandError::NotReady)?
+            .execute(cmd, now)
+    })
+}
+
+// core0の10ms割り込みから呼ぶ
+pub fn poll() {
+    let now = timer::now_us();
+    let due = interrupt::free(|cs| REBOOT.borrow(cs).borrow().as_ref()?.due(now));
+    if let Some(mode) = due {
+        reboot(mode);
+    }
+}
+
+pub fn reboot(mode: RebootMode) -> ! {
+    let started = interrupt::free(|cs| {
+        let mut watchdog = WATCHDOG.borrow(cs).borrow_mut();
+        let watchdog = watchdog.as_mut()?;
+        supervisor::mark_requested_reset(watchdog);
+        if mode == RebootMode::Normal {
+            watchdog.start(MicrosDurationU32::micros(1));
+        }
+        Some(())
+    });
+    match mode {
+        RebootMode::Normal => {
+            if started.is_some() {
+                loop {
+                    cortex_m::asm::nop();
+                }
+            }
+            // ウォッチドッグを取れなかったときはCPUだけでもリセットする
+            cortex_m::peripheral::SCB::sys_reset();
+        }
+        RebootMode::Bootsel => {
+            rom_data::reset_to_usb_boot(1 << BOOTSEL_ACTIVITY_PIN, 0);
+            loop {
+                cortex_m::asm::wfi();
+            }
+        }
+    }
+}
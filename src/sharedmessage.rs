@@ -1,54 +1,72 @@
 extern crate alloc;
 // use alloc::string::String;
 use crate::globals::MAX_MESSAGE_SIZE;
+use crate::msgpool::{
+    MessageBox, MessageKind, MessagePool, CORE0_TO_CORE1_POOL, CORE1_TO_CORE0_POOL, POOL_BLOCKS,
+};
+use crate::spinlock::{self, MessageQueueLock};
 use core::cell::UnsafeCell;
 use cortex_m::interrupt::Mutex;
 use heapless::Deque;
 use heapless::String;
 use heapless::Vec;
-use rp_pico::hal::sio::Spinlock0;
 
-// 増やしすぎると正常に動作しなくなる たぶん.bssが溢れている
-const MAX_BUFFER_SIZE: usize = 8;
-const MAX_QUEUE_SIZE: usize = 8;
+// 本文は向きごとのmsgpoolに置きキューにはハンドルだけを入れるので、プール全体を溜められる深さにしておく
+const MAX_BUFFER_SIZE: usize = POOL_BLOCKS;
+const MAX_QUEUE_SIZE: usize = POOL_BLOCKS;
 
 pub static SHARED_MESSAGE_CORE0_TO_CORE1: Mutex<LockedSharedMessage> =
-    Mutex::new(LockedSharedMessage::new());
+    Mutex::new(LockedSharedMessage::new(&CORE0_TO_CORE1_POOL));
+// core1で処理した結果をcore0経由でUSBへ返す
+pub static SHARED_MESSAGE_CORE1_TO_CORE0: Mutex<LockedSharedMessage> =
+    Mutex::new(LockedSharedMessage::new(&CORE1_TO_CORE0_POOL));
 
 pub struct LockedSharedMessage {
+    pool: &'static MessagePool,
     data: UnsafeCell<SharedString>,
 }
 
 unsafe impl Sync for LockedSharedMessage {}
 
-impl Default for LockedSharedMessage {
-    fn default() -> Self {
-        Self::new()
-    }
-}
-
 impl LockedSharedMessage {
-    pub const fn new() -> Self {
+    pub const fn new(pool: &'static MessagePool) -> Self {
         Self {
+            pool,
             data: UnsafeCell::new(SharedString::new()),
         }
     }
 
     pub fn write(&self, msg: String<MAX_MESSAGE_SIZE>) {
+        self.write_kind(MessageKind::Text, msg.as_str());
+    }
+
+    // プールに空きが無ければ捨てる
+    #[inline(never)]
+    #[link_section = ".ram_text"]
+    pub fn write_kind(&self, kind: MessageKind, msg: &str) {
+        let Some(msg) = MessageBox::new(self.pool, kind, msg) else {
+            return;
+        };
         let buffer = unsafe { &mut *self.data.get() };
         buffer.push_message(msg);
     }
 
+    #[inline(never)]
+    #[link_section = ".ram_text"]
     pub fn flush(&self) {
         let buffer = unsafe { &mut *self.data.get() };
         buffer.flush_queue();
     }
 
-    pub fn pop(&self) -> Option<String<MAX_MESSAGE_SIZE>> {
+    #[inline(never)]
+    #[link_section = ".ram_text"]
+    pub fn pop(&self) -> Option<MessageBox> {
         let buffer = unsafe { &mut *self.data.get() };
         buffer.queue_pop()
     }
-    pub fn drain_all(&self) -> Vec<String<MAX_MESSAGE_SIZE>, MAX_QUEUE_SIZE> {
+    #[inline(never)]
+    #[link_section = ".ram_text"]
+    pub fn drain_all(&self) -> Vec<MessageBox, MAX_QUEUE_SIZE> {
         let buffer = unsafe { &mut *self.data.get() };
         buffer.drain_all()
     }
@@ -56,8 +74,8 @@ impl LockedSharedMessage {
 
 // 実バッファ構造体
 pub struct SharedString {
-    buffer: Deque<String<MAX_MESSAGE_SIZE>, MAX_BUFFER_SIZE>, // 一時バッファ
-    queue: Deque<String<MAX_MESSAGE_SIZE>, MAX_QUEUE_SIZE>,   // core1に渡るログキュー
+    buffer: Deque<MessageBox, MAX_BUFFER_SIZE>, // 一時バッファ
+    queue: Deque<MessageBox, MAX_QUEUE_SIZE>,   // core1に渡るログキュー
 }
 
 impl Default for SharedString {
@@ -69,13 +87,13 @@ impl Default for SharedString {
 impl SharedString {
     pub const fn new() -> Self {
         Self {
-            buffer: Deque::<String<MAX_MESSAGE_SIZE>, MAX_BUFFER_SIZE>::new(),
-            queue: Deque::<String<MAX_MESSAGE_SIZE>, MAX_QUEUE_SIZE>::new(),
+            buffer: Deque::<MessageBox, MAX_BUFFER_SIZE>::new(),
+            queue: Deque::<MessageBox, MAX_QUEUE_SIZE>::new(),
         }
     }
 
-    pub fn push_message(&mut self, msg: String<MAX_MESSAGE_SIZE>) {
-        if let Some(_guard) = Spinlock0::try_claim() {
+    pub fn push_message(&mut self, msg: MessageBox) {
+        if let Some(_guard) = spinlock::try_claim::<MessageQueueLock>() {
             self.rotate_buffer();
             self.push_queue(msg);
         } else {
@@ -89,14 +107,14 @@ impl SharedString {
         }
     }
 
-    fn push_buffer(&mut self, msg: String<MAX_MESSAGE_SIZE>) {
+    fn push_buffer(&mut self, msg: MessageBox) {
         if self.buffer.len() >= MAX_BUFFER_SIZE {
             self.buffer.pop_front();
         }
         let _ = self.buffer.push_back(msg);
     }
 
-    fn push_queue(&mut self, msg: String<MAX_MESSAGE_SIZE>) {
+    fn push_queue(&mut self, msg: MessageBox) {
         if self.queue.len() >= MAX_QUEUE_SIZE {
             self.queue.pop_front();
         }
@@ -104,21 +122,24 @@ impl SharedString {
     }
 
     pub fn flush_queue(&mut self) {
-        if let Some(_guard) = Spinlock0::try_claim() {
+        if let Some(_guard) = spinlock::try_claim::<MessageQueueLock>() {
             self.rotate_buffer();
         }
     }
 
-    pub fn queue_pop(&mut self) -> Option<String<MAX_MESSAGE_SIZE>> {
+    // queueは相手のコアがロックを取って積むので、取り出す側もロックを取る
+    // 壊れたDequeからハンドルが二重に出るとブロックを二重に返してしまう
+    pub fn queue_pop(&mut self) -> Option<MessageBox> {
+        let _guard = spinlock::claim::<MessageQueueLock>();
         self.queue.pop_front()
     }
-    pub fn drain_all(&mut self) -> Vec<String<MAX_MESSAGE_SIZE>, MAX_QUEUE_SIZE> {
-        let msgs = self
-            .queue
-            .iter()
-            .cloned()
-            .collect::<Vec<String<MAX_MESSAGE_SIZE>, MAX_QUEUE_SIZE>>();
-        self.queue.clear();
+    pub fn drain_all(&mut self) -> Vec<MessageBox, MAX_QUEUE_SIZE> {
+        let _guard = spinlock::claim::<MessageQueueLock>();
+        let mut msgs = Vec::new();
+        while let Some(msg) = self.queue.pop_front() {
+            // 容量は同じなので溢れない
+            let _ = msgs.push(msg);
+        }
         msgs
     }
 }
@@ -0,0 +1,702 @@
+// SPI0/SPI1をホストから操作するブリッジ
+// SCK/MOSI/MISOはピンプールから借りる。RP2040ではGPIO番号%4が0:RX 1:CS 2:SCK 3:TXで、
+// (GPIO番号/8)%2がSPIの番号になる。CSはハードウェアに任せずファームウェアが任意のGPIOで制御する
+// 転送部分はembedded_hal::spi::SpiDeviceに対してジェネリックなので、モックを渡せばホスト上でも動かせる
+use crate::command::{ok_reply, parse_hex_bytes, write_hex, Args, CommandError, Reply};
+use crate::globals::{RESETS, SPI};
+use crate::pinpool::{self, NUM_BANK0_PINS};
+use core::fmt::Write;
+use cortex_m::interrupt;
+use embedded_hal::digital::OutputPin;
+use embedded_hal::spi::{
+    self, ErrorKind, ErrorType, Mode, Operation, SpiBus, SpiDevice, MODE_0, MODE_1, MODE_2, MODE_3,
+};
+use heapless::Vec;
+use rp_pico::hal::fugit::HertzU32;
+use rp_pico::hal::gpio::{DynPinId, DynPullType, FunctionSioOutput, FunctionSpi, Pin};
+use rp_pico::hal::pac;
+use rp_pico::hal::spi::{
+    Enabled, Spi, SpiDevice as HalSpiDevice, ValidatedPinRx, ValidatedPinSck, ValidatedPinTx,
+};
+
+pub const DEFAULT_KHZ: u32 = 1000;
+pub const MIN_KHZ: u32 = 10;
+// 周辺クロック125MHzの半分まで
+pub const MAX_KHZ: u32 = 62_500;
+// 応答行に16進で載せられる長さに合わせる
+pub const MAX_TRANSFER: usize = 64;
+
+pub type Data = Vec<u8, MAX_TRANSFER>;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum BusId {
+    Spi0,
+    Spi1,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum PinRole {
+    Rx,
+    Cs,
+    Sck,
+    Tx,
+}
+
+impl BusId {
+    pub fn parse(s: &str) -> Result<Self, CommandError> {
+        match s {
+            "0" => Ok(BusId::Spi0),
+            "1" => Ok(BusId::Spi1),
+            _ => Err(CommandError::InvalidArgument),
+        }
+    }
+
+    pub fn index(&self) -> u8 {
+        match self {
+            BusId::Spi0 => 0,
+            BusId::Spi1 => 1,
+        }
+    }
+
+    pub fn accepts(&self, gpio: u8, role: PinRole) -> bool {
+        let pin_role = match gpio % 4 {
+            0 => PinRole::Rx,
+            1 => PinRole::Cs,
+            2 => PinRole::Sck,
+            _ => PinRole::Tx,
+        };
+        (gpio as usize) < NUM_BANK0_PINS && (gpio / 8) % 2 == self.index() && pin_role == role
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum BitOrder {
+    MsbFirst,
+    LsbFirst,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct SpiConfig {
+    pub khz: u32,
+    pub mode: u8,
+    pub bit_order: BitOrder,
+}
+
+impl SpiConfig {
+    pub fn hal_mode(&self) -> Mode {
+        match self.mode {
+            0 => MODE_0,
+            1 => MODE_1,
+            2 => MODE_2,
+            _ => MODE_3,
+        }
+    }
+}
+
+impl Default for SpiConfig {
+    fn default() -> Self {
+        Self {
+            khz: DEFAULT_KHZ,
+            mode: 0,
+            bit_order: BitOrder::MsbFirst,
+        }
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum SpiCommand {
+    Init {
+        bus: BusId,
+        sck: u8,
+        tx: u8,
+        rx: u8,
+        cs: u8,
+        config: SpiConfig,
+    },
+    Deinit {
+        bus: BusId,
+    },
+    Transfer {
+        bus: BusId,
+        data: Data,
+    },
+}
+
+fn parse_gpio(args: &mut Args) -> Result<u8, CommandError> {
+    u8::try_from(args.next_u32()?).map_err(|_| CommandError::OutOfRange)
+}
+
+impl SpiCommand {
+    pub fn parse(args: &mut Args) -> Result<Self, CommandError> {
+        let sub = args.next_str()?;
+        let bus = BusId::parse(args.next_str()?)?;
+        match sub {
+            "init" => {
+                let sck = parse_gpio(args)?;
+                let tx = parse_gpio(args)?;
+                let rx = parse_gpio(args)?;
+                let cs = parse_gpio(args)?;
+                if !bus.accepts(sck, PinRole::Sck)
+                    || !bus.accepts(tx, PinRole::Tx)
+                    || !bus.accepts(rx, PinRole::Rx)
+                    || cs as usize >= NUM_BANK0_PINS
+                {
+                    return Err(CommandError::OutOfRange);
+                }
+                let mut config = SpiConfig::default();
+                while let Some(key) = args.next_opt() {
+                    match key {
+                        "khz" => config.khz = args.next_u32()?,
+                        "mode" => {
+                            config.mode = u8::try_from(args.next_u32()?)
+                                .map_err(|_| CommandError::OutOfRange)?
+                        }
+                        "msb" => config.bit_order = BitOrder::MsbFirst,
+                        "lsb" => config.bit_order = BitOrder::LsbFirst,
+                        _ => return Err(CommandError::InvalidArgument),
+                    }
+                }
+                if !(MIN_KHZ..=MAX_KHZ).contains(&config.khz) || config.mode > 3 {
+                    return Err(CommandError::OutOfRange);
+                }
+                Ok(SpiCommand::Init {
+                    bus,
+                    sck,
+                    tx,
+                    rx,
+                    cs,
+                    config,
+                })
+            }
+            "deinit" => Ok(SpiCommand::Deinit { bus }),
+            "xfer" => {
+                let data = parse_hex_bytes::<MAX_TRANSFER>(args.next_str()?)?;
+                if data.is_empty() {
+                    return Err(CommandError::InvalidArgument);
+                }
+                Ok(SpiCommand::Transfer { bus, data })
+            }
+            _ => Err(CommandError::InvalidArgument),
+        }
+    }
+}
+
+// 全二重転送。SPIペリフェラルはMSBファーストしか送れないのでLSBファーストはビットを反転して送受信する
+pub fn transfer<S: SpiDevice>(
+    dev: &mut S,
+    data: &[u8],
+    bit_order: BitOrder,
+) -> Result<Reply, CommandError> {
+    let mut buf = [0u8; MAX_TRANSFER];
+    let buf = &mut buf[..data.len()];
+    buf.copy_from_slice(data);
+    if bit_order == BitOrder::LsbFirst {
+        buf.iter_mut().for_each(|b| *b = b.reverse_bits());
+    }
+    // CSの失敗もバス上の転送の失敗として返す
+    dev.transfer_in_place(buf)
+        .map_err(|_| CommandError::BusError)?;
+    if bit_order == BitOrder::LsbFirst {
+        buf.iter_mut().for_each(|b| *b = b.reverse_bits());
+    }
+    Ok(ok_reply(|r| {
+        r.write_str(" spi xfer ")?;
+        write_hex(r, buf)
+    }))
+}
+
+#[derive(Debug)]
+pub enum DeviceError<E> {
+    Bus(E),
+    ChipSelect,
+}
+
+impl<E: spi::Error> spi::Error for DeviceError<E> {
+    fn kind(&self) -> ErrorKind {
+        match self {
+            DeviceError::Bus(e) => e.kind(),
+            DeviceError::ChipSelect => ErrorKind::ChipSelectFault,
+        }
+    }
+}
+
+// SpiBusとCSピンをまとめてSpiDeviceにする。CSはアクティブLow
+pub struct ChipSelectDevice<B, P> {
+    bus: B,
+    cs: P,
+}
+
+impl<B: SpiBus, P: OutputPin> ChipSelectDevice<B, P> {
+    pub fn new(bus: B, mut cs: P) -> Self {
+        let _ = cs.set_high();
+        Self { bus, cs }
+    }
+
+    pub fn free(self) -> (B, P) {
+        (self.bus, self.cs)
+    }
+}
+
+impl<B: SpiBus, P: OutputPin> ErrorType for ChipSelectDevice<B, P> {
+    type Error = DeviceError<B::Error>;
+}
+
+impl<B: SpiBus, P: OutputPin> SpiDevice for ChipSelectDevice<B, P> {
+    fn transaction(&mut self, operations: &mut [Operation<'_, u8>]) -> Result<(), Self::Error> {
+        self.cs.set_low().map_err(|_| DeviceError::ChipSelect)?;
+        let result = operations
+            .iter_mut()
+            .try_for_each(|op| match op {
+                Operation::Read(buf) => self.bus.read(buf),
+                Operation::Write(buf) => self.bus.write(buf),
+                Operation::Transfer(read, write) => self.bus.transfer(read, write),
+                Operation::TransferInPlace(buf) => self.bus.transfer_in_place(buf),
+                // 送信を終えてから待つ。125MHzなら1サイクル8nsなので大まかに換算する
+                Operation::DelayNs(ns) => {
+                    self.bus.flush()?;
+                    cortex_m::asm::delay(*ns / 8 + 1);
+                    Ok(())
+                }
+            })
+            .and_then(|_| self.bus.flush())
+            .map_err(DeviceError::Bus);
+        self.cs.set_high().map_err(|_| DeviceError::ChipSelect)?;
+        result
+    }
+}
+
+pub type SpiPin = Pin<DynPinId, FunctionSpi, DynPullType>;
+pub type CsPin = Pin<DynPinId, FunctionSioOutput, DynPullType>;
+type Bus<D> = Spi<
+    Enabled,
+    D,
+    (
+        ValidatedPinTx<SpiPin, D>,
+        ValidatedPinRx<SpiPin, D>,
+        ValidatedPinSck<SpiPin, D>,
+    ),
+>;
+
+fn take_pin(num: u8) -> Result<SpiPin, CommandError> {
+    pinpool::take(num)?
+        .try_into_function::<FunctionSpi>()
+        .map_err(|pin| {
+            pinpool::give(pin);
+            CommandError::InvalidArgument
+        })
+}
+
+fn take_cs_pin(num: u8) -> Result<CsPin, CommandError> {
+    let pin = pinpool::take(num)?;
+    // SIOの出力値はLowのままなので、先にHighにしておかないと出力にした瞬間CSがアクティブになる
+    let sio = unsafe { &*pac::SIO::ptr() };
+    sio.gpio_out_set().write(|w| unsafe { w.bits(1 << num) });
+    pin.try_into_function::<FunctionSioOutput>().map_err(|pin| {
+        pinpool::give(pin);
+        CommandError::InvalidArgument
+    })
+}
+
+struct ActiveBus<D: HalSpiDevice> {
+    device: ChipSelectDevice<Bus<D>, CsPin>,
+    sck: u8,
+    tx: u8,
+    rx: u8,
+    config: SpiConfig,
+}
+
+// 未使用時はPACのペリフェラル、初期化後はCS付きのSpiDeviceとして持つ
+struct Port<D: HalSpiDevice> {
+    device: Option<D>,
+    active: Option<ActiveBus<D>>,
+}
+
+impl<D: HalSpiDevice> Port<D> {
+    fn new(device: D) -> Self {
+        Self {
+            device: Some(device),
+            active: None,
+        }
+    }
+
+    #[allow(clippy::too_many_arguments)]
+    fn init(
+        &mut self,
+        sck: u8,
+        tx: u8,
+        rx: u8,
+        cs: u8,
+        config: SpiConfig,
+        peri_hz: u32,
+        resets: &mut pac::RESETS,
+    ) -> Result<u32, CommandError> {
+        // 初期化し直す場合は先に解放してピンを返す
+        self.deinit();
+        let device = self.device.as_ref().ok_or(CommandError::NotReady)?;
+        // 途中で失敗したら借りたピンを全部返す
+        let mut taken: Vec<SpiPin, 3> = Vec::new();
+        for num in [tx, rx, sck] {
+            match take_pin(num) {
+                Ok(pin) => {
+                    let _ = taken.push(pin);
+                }
+                Err(e) => {
+                    taken.into_iter().for_each(pinpool::give);
+                    return Err(e);
+                }
+            }
+        }
+        let cs_pin = match take_cs_pin(cs) {
+            Ok(pin) => pin,
+            Err(e) => {
+                taken.into_iter().for_each(pinpool::give);
+                return Err(e);
+            }
+        };
+        let mut taken = taken.into_iter();
+        let (Some(tx_pin), Some(rx_pin), Some(sck_pin)) =
+            (taken.next(), taken.next(), taken.next())
+        else {
+            unreachable!()
+        };
+        let pins = match (
+            ValidatedPinTx::validate(tx_pin, device),
+            ValidatedPinRx::validate(rx_pin, device),
+            ValidatedPinSck::validate(sck_pin, device),
+        ) {
+            (Ok(tx_pin), Ok(rx_pin), Ok(sck_pin)) => (tx_pin, rx_pin, sck_pin),
+            // parseでピン番号は検査済みなので通常は起きない
+            pins => {
+                // 番号はここで借りたピンのもの
+                unsafe { pinpool::give_wrapped(pins, &[tx, rx, sck]) };
+                pinpool::give(cs_pin);
+                return Err(CommandError::InvalidArgument);
+            }
+        };
+        let device = self.device.take().ok_or(CommandError::NotReady)?;
+        let mut bus = Spi::<_, _, _, 8>::new(device, pins).init(
+            resets,
+            HertzU32::Hz(peri_hz),
+            HertzU32::kHz(config.khz),
+            config.hal_mode(),
+        );
+        // initは実際の周波数を返さないので設定し直して受け取る
+        let actual_hz = bus
+            .set_baudrate(HertzU32::Hz(peri_hz), HertzU32::kHz(config.khz))
+            .to_Hz();
+        self.active = Some(ActiveBus {
+            device: ChipSelectDevice::new(bus, cs_pin),
+            sck,
+            tx,
+            rx,
+            config,
+        });
+        Ok(actual_hz)
+    }
+
+    fn deinit(&mut self) -> bool {
+        let Some(active) = self.active.take() else {
+            return false;
+        };
+        let (bus, cs_pin) = active.device.free();
+        let (device, pins) = bus.free();
+        device.sspcr1().modify(|_, w| w.sse().clear_bit());
+        self.device = Some(device);
+        // 番号はinitで借りたピンのもの
+        unsafe { pinpool::give_wrapped(pins, &[active.tx, active.rx, active.sck]) };
+        pinpool::give(cs_pin);
+        true
+    }
+
+    fn transfer(&mut self, data: &[u8]) -> Result<Reply, CommandError> {
+        let active = self.active.as_mut().ok_or(CommandError::NotReady)?;
+        transfer(&mut active.device, data, active.config.bit_order)
+    }
+}
+
+pub struct SpiState {
+    spi0: Port<pac::SPI0>,
+    spi1: Port<pac::SPI1>,
+    peri_hz: u32,
+}
+
+impl SpiState {
+    pub fn new(spi0: pac::SPI0, spi1: pac::SPI1, peri_hz: u32) -> Self {
+        Self {
+            spi0: Port::new(spi0),
+            spi1: Port::new(spi1),
+            peri_hz,
+        }
+    }
+
+    pub fn execute(
+        &mut self,
+        cmd: &SpiCommand,
+        resets: &mut pac::RESETS,
+    ) -> Result<Reply, CommandError> {
+        match cmd {
+            SpiCommand::Init {
+                bus,
+                sck,
+                tx,
+                rx,
+                cs,
+                config,
+            } => {
+                let actual_hz = match bus {
+                    BusId::Spi0 => {
+                        self.spi0
+                            .init(*sck, *tx, *rx, *cs, *config, self.peri_hz, resets)?
+                    }
+                    BusId::Spi1 => {
+                        self.spi1
+                            .init(*sck, *tx, *rx, *cs, *config, self.peri_hz, resets)?
+                    }
+                };
+                Ok(ok_reply(|r| {
+                    write!(
+                        r,
+                        " spi init bus={} mode={} order={} hz={}",
+                        bus.index(),
+                        config.mode,
+                        match config.bit_order {
+                            BitOrder::MsbFirst => "msb",
+                            BitOrder::LsbFirst => "lsb",
+                        },
+                        actual_hz
+                    )
+                }))
+            }
+            SpiCommand::Deinit { bus } => {
+                let released = match bus {
+                    BusId::Spi0 => self.spi0.deinit(),
+                    BusId::Spi1 => self.spi1.deinit(),
+                };
+                if !released {
+                    return Err(CommandError::NotReady);
+                }
+                Ok(ok_reply(|r| write!(r, " spi deinit bus={}", bus.index())))
+            }
+            SpiCommand::Transfer { bus, data } => match bus {
+                BusId::Spi0 => self.spi0.transfer(data),
+                BusId::Spi1 => self.spi1.transfer(data),
+            },
+        }
+    }
+}
+
+pub fn handle_command(args: &mut Args) -> Result<Reply, CommandError> {
+    let cmd = SpiCommand::parse(args)?;
+    interrupt::free(|cs| {
+        let mut spi = SPI.borrow(cs).borrow_mut();
+        let spi = spi.as_mut().ok_or(CommandError::NotReady)?;
+        let mut resets = RESETS.borrow(cs).borrow_mut();
+        let resets = resets.as_mut().ok_or(CommandError::NotReady)?;
+        spi.execute(&cmd, resets)
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use core::cell::RefCell;
+    use embedded_hal::digital;
+    use std::vec::Vec;
+
+    // 起きたことを順に記録する。バスとCSピンで共有する
+    #[derive(Debug, Clone, PartialEq, Eq)]
+    enum Event {
+        Cs(bool),
+        Byte(u8),
+        Flush,
+    }
+
+    type Log = RefCell<Vec<Event>>;
+
+    // 送った値を1ビット左に回して返すデバイスが繋がったバス
+    struct MockBus<'a> {
+        log: &'a Log,
+        fail: bool,
+    }
+
+    impl MockBus<'_> {
+        fn exchange(&mut self, byte: u8) -> Result<u8, ErrorKind> {
+            if self.fail {
+                return Err(ErrorKind::Overrun);
+            }
+            self.log.borrow_mut().push(Event::Byte(byte));
+            Ok(byte.rotate_left(1))
+        }
+    }
+
+    impl ErrorType for MockBus<'_> {
+        type Error = ErrorKind;
+    }
+
+    impl SpiBus for MockBus<'_> {
+        fn read(&mut self, words: &mut [u8]) -> Result<(), Self::Error> {
+            words
+                .iter_mut()
+                .try_for_each(|w| self.exchange(0).map(|b| *w = b))
+        }
+
+        fn write(&mut self, words: &[u8]) -> Result<(), Self::Error> {
+            words.iter().try_for_each(|&w| self.exchange(w).map(|_| ()))
+        }
+
+        fn transfer(&mut self, read: &mut [u8], write: &[u8]) -> Result<(), Self::Error> {
+            for i in 0..read.len().max(write.len()) {
+                let b = self.exchange(write.get(i).copied().unwrap_or(0))?;
+                if let Some(r) = read.get_mut(i) {
+                    *r = b;
+                }
+            }
+            Ok(())
+        }
+
+        fn transfer_in_place(&mut self, words: &mut [u8]) -> Result<(), Self::Error> {
+            words
+                .iter_mut()
+                .try_for_each(|w| self.exchange(*w).map(|b| *w = b))
+        }
+
+        fn flush(&mut self) -> Result<(), Self::Error> {
+            self.log.borrow_mut().push(Event::Flush);
+            Ok(())
+        }
+    }
+
+    struct MockCs<'a> {
+        log: &'a Log,
+        fail: bool,
+    }
+
+    impl digital::ErrorType for MockCs<'_> {
+        type Error = digital::ErrorKind;
+    }
+
+    impl OutputPin for MockCs<'_> {
+        fn set_low(&mut self) -> Result<(), Self::Error> {
+            if self.fail {
+                return Err(digital::ErrorKind::Other);
+            }
+            self.log.borrow_mut().push(Event::Cs(false));
+            Ok(())
+        }
+
+        fn set_high(&mut self) -> Result<(), Self::Error> {
+            self.log.borrow_mut().push(Event::Cs(true));
+            Ok(())
+        }
+    }
+
+    fn device(log: &Log) -> ChipSelectDevice<MockBus<'_>, MockCs<'_>> {
+        ChipSelectDevice::new(MockBus { log, fail: false }, MockCs { log, fail: false })
+    }
+
+    fn parse(line: &str) -> Result<SpiCommand, CommandError> {
+        SpiCommand::parse(&mut Args::new(line))
+    }
+
+    #[test]
+    fn parses_commands() {
+        assert_eq!(
+            parse("init 1 10 11 8 13 khz 500 mode 3 lsb"),
+            Ok(SpiCommand::Init {
+                bus: BusId::Spi1,
+                sck: 10,
+                tx: 11,
+                rx: 8,
+                cs: 13,
+                config: SpiConfig {
+                    khz: 500,
+                    mode: 3,
+                    bit_order: BitOrder::LsbFirst
+                }
+            })
+        );
+        // SPI1のピンをSPI0に使う
+        assert_eq!(parse("init 0 10 11 8 13"), Err(CommandError::OutOfRange));
+        // SCKとTXが入れ替わっている
+        assert_eq!(parse("init 0 3 2 0 1"), Err(CommandError::OutOfRange));
+        assert_eq!(parse("init 0 2 3 0 30"), Err(CommandError::OutOfRange));
+        assert_eq!(
+            parse("init 0 2 3 0 1 mode 4"),
+            Err(CommandError::OutOfRange)
+        );
+        assert_eq!(parse("init 0 2 3 0 1 khz 5"), Err(CommandError::OutOfRange));
+        assert_eq!(
+            parse("init 0 2 3 0 1 fast"),
+            Err(CommandError::InvalidArgument)
+        );
+        assert_eq!(parse("xfer 0 "), Err(CommandError::MissingArgument));
+        assert_eq!(parse("xfer 0 abc"), Err(CommandError::InvalidArgument));
+    }
+
+    #[test]
+    fn cs_is_asserted_only_around_the_transfer() {
+        let log = Log::default();
+        let mut dev = device(&log);
+        let reply = transfer(&mut dev, &[0x81, 0x02], BitOrder::MsbFirst).unwrap();
+        assert_eq!(reply.as_str(), "OK spi xfer 0304");
+        assert_eq!(
+            *log.borrow(),
+            [
+                Event::Cs(true),
+                Event::Cs(false),
+                Event::Byte(0x81),
+                Event::Byte(0x02),
+                Event::Flush,
+                Event::Cs(true),
+            ]
+        );
+    }
+
+    #[test]
+    fn lsb_first_reverses_bits_on_the_wire() {
+        let log = Log::default();
+        let mut dev = device(&log);
+        let reply = transfer(&mut dev, &[0x01], BitOrder::LsbFirst).unwrap();
+        // 0x01 -> 線上0x80 -> デバイスが0x01を返す -> 0x80
+        assert_eq!(log.borrow()[2], Event::Byte(0x80));
+        assert_eq!(reply.as_str(), "OK spi xfer 80");
+    }
+
+    #[test]
+    fn failures_release_cs_and_report_bus_errors() {
+        let log = Log::default();
+        let mut dev = ChipSelectDevice::new(
+            MockBus {
+                log: &log,
+                fail: true,
+            },
+            MockCs {
+                log: &log,
+                fail: false,
+            },
+        );
+        assert_eq!(
+            transfer(&mut dev, &[0x00], BitOrder::MsbFirst),
+            Err(CommandError::BusError)
+        );
+        assert_eq!(log.borrow().last(), Some(&Event::Cs(true)));
+
+        let mut dev = ChipSelectDevice::new(
+            MockBus {
+                log: &log,
+                fail: false,
+            },
+            MockCs {
+                log: &log,
+                fail: true,
+            },
+        );
+        let mut buf = [0u8; 1];
+        let err = dev.transfer_in_place(&mut buf).unwrap_err();
+        assert_eq!(spi::Error::kind(&err), ErrorKind::ChipSelectFault);
+        assert_eq!(
+            transfer(&mut dev, &[0x00], BitOrder::MsbFirst),
+            Err(CommandError::BusError)
+        );
+    }
+}
@@ -0,0 +1,166 @@
+// ハードウェアspinlockの割り当て表
+// 使う側は番号ではなくここで定義した型 (トークン) でロックを指定する
+// 番号は下のspinlocks!の一か所でだけ決め、重なっていたり31を使っていたりするとコンパイルが通らない
+// ロックごとに取得回数と、すぐに取れず待った (try_claimなら諦めた) 回数を数えて `locks` コマンドで出す
+use crate::command::{ok_reply, Args, CommandError, Reply};
+use core::fmt::Write;
+use core::marker::PhantomData;
+use core::sync::atomic::{compiler_fence, AtomicU32, Ordering};
+use rp_pico::hal::pac;
+
+pub const NUM_SPINLOCKS: usize = 32;
+// rp2040-halのcritical-sectionの実装が使う
+pub const HAL_CRITICAL_SECTION_LOCK: usize = 31;
+
+mod sealed {
+    pub trait Sealed {}
+}
+
+// spinlocks!で定義した型だけが実装できる
+pub trait Lock: sealed::Sealed {
+    const NUM: usize;
+    const NAME: &'static str;
+}
+
+const fn check_assignments(nums: &[usize]) {
+    let mut i = 0;
+    while i < nums.len() {
+        assert!(
+            nums[i] < HAL_CRITICAL_SECTION_LOCK,
+            "spinlock 31 is reserved for critical-section"
+        );
+        let mut j = i + 1;
+        while j < nums.len() {
+            assert!(nums[i] != nums[j], "spinlock assigned twice");
+            j += 1;
+        }
+        i += 1;
+    }
+}
+
+macro_rules! spinlocks {
+    ($($(#[$attr:meta])* $token:ident = $num:literal, $name:literal;)*) => {
+        $(
+            $(#[$attr])*
+            pub struct $token {
+                _private: (),
+            }
+
+            impl sealed::Sealed for $token {}
+
+            impl Lock for $token {
+                const NUM: usize = $num;
+                const NAME: &'static str = $name;
+            }
+        )*
+
+        const _: () = check_assignments(&[$($num),*]);
+
+        // 定義順
+        const ASSIGNED: &[(usize, &str)] = &[$(($num, $name)),*];
+    };
+}
+
+spinlocks! {
+    // sharedmessageのコア間キュー
+    MessageQueueLock = 0, "msgq";
+    // capture のリングバッファのブロック状態
+    CaptureLock = 1, "capture";
+    // msgpool の空きブロック
+    MessagePoolLock = 2, "msgpool";
+    // globalsのCrossCoreShared
+    Alarm0Lock = 3, "alarm0";
+    Alarm1Lock = 4, "alarm1";
+    Alarm2Lock = 5, "alarm2";
+    Alarm3Lock = 6, "alarm3";
+    LedLock = 7, "led";
+}
+
+static ACQUIRED: [AtomicU32; NUM_SPINLOCKS] = [const { AtomicU32::new(0) }; NUM_SPINLOCKS];
+static CONTENDED: [AtomicU32; NUM_SPINLOCKS] = [const { AtomicU32::new(0) }; NUM_SPINLOCKS];
+
+// thumbv6mにはfetch_addが無いので読んで書く
+// ACQUIREDはロックを持っている側、CONTENDEDは待っている側 (その間もう片方がロックを持っている)
+// だけが書くので、両コアが同時に同じカウンタを書くことはない
+fn bump(counter: &AtomicU32) {
+    counter.store(
+        counter.load(Ordering::Relaxed).wrapping_add(1),
+        Ordering::Relaxed,
+    );
+}
+
+// 読むとロックを取り、取れたら0以外が返る
+fn try_lock(num: usize) -> bool {
+    let sio = unsafe { &*pac::SIO::ptr() };
+    sio.spinlock(num).read().bits() != 0
+}
+
+// ロックを持っている間だけ生きているガード。捨てると解放する
+pub struct SpinlockGuard<L: Lock> {
+    _lock: PhantomData<L>,
+}
+
+impl<L: Lock> SpinlockGuard<L> {
+    fn acquired() -> Self {
+        compiler_fence(Ordering::Acquire);
+        bump(&ACQUIRED[L::NUM]);
+        Self { _lock: PhantomData }
+    }
+}
+
+impl<L: Lock> Drop for SpinlockGuard<L> {
+    fn drop(&mut self) {
+        compiler_fence(Ordering::Release);
+        let sio = unsafe { &*pac::SIO::ptr() };
+        sio.spinlock(L::NUM).write(|w| unsafe { w.bits(1) });
+    }
+}
+
+// 同じコアで既に持っているロックを取るとそのまま止まるので、割り込みを止めた中で使う
+pub fn claim<L: Lock>() -> SpinlockGuard<L> {
+    if !try_lock(L::NUM) {
+        bump(&CONTENDED[L::NUM]);
+        while !try_lock(L::NUM) {}
+    }
+    SpinlockGuard::acquired()
+}
+
+pub fn try_claim<L: Lock>() -> Option<SpinlockGuard<L>> {
+    if try_lock(L::NUM) {
+        Some(SpinlockGuard::acquired())
+    } else {
+        bump(&CONTENDED[L::NUM]);
+        None
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct LockStats {
+    pub num: usize,
+    pub name: &'static str,
+    pub acquired: u32,
+    pub contended: u32,
+}
+
+pub fn stats() -> impl Iterator<Item = LockStats> {
+    ASSIGNED.iter().map(|&(num, name)| LockStats {
+        num,
+        name,
+        acquired: ACQUIRED[num].load(Ordering::Relaxed),
+        contended: CONTENDED[num].load(Ordering::Relaxed),
+    })
+}
+
+pub fn handle_command(args: &mut Args) -> Result<Reply, CommandError> {
+    if args.next_opt().is_some() {
+        return Err(CommandError::InvalidArgument);
+    }
+    Ok(ok_reply(|r| {
+        r.write_str(" locks")?;
+        // 名前=取得回数/待った回数
+        for lock in stats() {
+            write!(r, " {}={}/{}", lock.name, lock.acquired, lock.contended)?;
+        }
+        Ok(())
+    }))
+}
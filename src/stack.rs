@@ -0,0 +1,151 @@
+// 両コアのスタック使用量の計測
+// 起動時にスタックの空き部分を決まった値で塗っておき、塗ったままの部分を数えて最大使用量を出す
+// core0のスタックは_stack_startから下へRAMの先頭に向かって伸び、core1のスタックはCORE1_STACK
+// flip-linkでリンクするので、静的データ(.data/.bss/.uninit/.heap)はRAMの上端に寄り、core0のスタックはその下に来る
+//
+// stack-guardフィーチャーではcore1のスタックの底256バイトをMPUでアクセス禁止にし、
+// 溢れた書き込みがその下のメモリを壊さないようにする (その分core1のスタックは狭くなる)
+// Cortex-M0+は例外フレームも溢れたスタックに積もうとするので、ガードに触れるとHardFaultハンドラに
+// 入れずcore1はロックアップする。crashlogには残らないが、core1が止まるとsupervisorがウォッチドッグで
+// リセットし、次の起動で `hang=core1` と報告される (watchdog_msが0なら止まったまま)
+use crate::command::{ok_reply, Args, CommandError, Reply};
+use crate::globals::CORE1_STACK;
+use core::fmt::Write;
+use core::mem::size_of_val;
+use core::ptr::{addr_of, read_volatile, write_volatile};
+
+const PAINT: u32 = 0xC5AC_CE55;
+// 塗る関数自身のフレームを避ける分 (paintはインライン展開して呼び出しのフレームを作らない)
+const PAINT_MARGIN: usize = 64;
+// memory.xのRAMの先頭。flip-linkで溢れたcore0のスタックはここで止まる
+const RAM_START: usize = 0x2000_0000;
+#[cfg(feature = "stack-guard")]
+const GUARD_SIZE: usize = 256;
+
+extern "C" {
+    // cortex-m-rtのリンカスクリプトが定義し、flip-linkが_stack_startを静的データの下に付け替える
+    // _stack_endはcortex-m-rtの値(.heapの後ろ)のままだとスタックより上になる
+    static _stack_start: u32;
+    static _stack_end: u32;
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct StackUsage {
+    pub used: usize,
+    pub size: usize,
+}
+
+#[inline(always)]
+unsafe fn paint(start: usize, end: usize) {
+    let mut p = start as *mut u32;
+    while (p as usize) < end {
+        write_volatile(p, PAINT);
+        p = p.add(1);
+    }
+}
+
+// 底から塗ったままの部分を数えて使用量にする
+unsafe fn measure(bottom: usize, top: usize) -> StackUsage {
+    let mut p = bottom as *const u32;
+    while (p as usize) < top && read_volatile(p) == PAINT {
+        p = p.add(1);
+    }
+    StackUsage {
+        used: top.saturating_sub(p as usize),
+        size: top.saturating_sub(bottom),
+    }
+}
+
+fn core0_bounds() -> (usize, usize) {
+    let top = addr_of!(_stack_start) as usize;
+    let end = addr_of!(_stack_end) as usize;
+    // _stack_endがスタックより上ならflip-linkの配置なので、RAMの先頭までがスタック
+    let bottom = if end <= top { end } else { RAM_START };
+    (bottom, top)
+}
+
+fn core1_region() -> (usize, usize) {
+    let start = addr_of!(CORE1_STACK) as usize;
+    let len = unsafe { size_of_val(&*addr_of!(CORE1_STACK)) };
+    (start, start + len)
+}
+
+// ガードより上が使える範囲
+fn core1_bounds() -> (usize, usize) {
+    let (start, end) = core1_region();
+    #[cfg(feature = "stack-guard")]
+    let start = guard_base(start) + GUARD_SIZE;
+    (start, end)
+}
+
+// core0::mainの最初の方で呼ぶ。今のSPより下を塗る
+#[inline(never)]
+pub fn paint_core0() {
+    let sp = cortex_m::register::msp::read() as usize;
+    let (bottom, _) = core0_bounds();
+    unsafe { paint(bottom, sp - PAINT_MARGIN) };
+}
+
+// core1を起動する前に呼ぶ
+pub fn paint_core1() {
+    let (start, end) = core1_region();
+    unsafe { paint(start, end) };
+}
+
+pub fn usage_core0() -> StackUsage {
+    let (bottom, top) = core0_bounds();
+    unsafe { measure(bottom, top) }
+}
+
+pub fn usage_core1() -> StackUsage {
+    let (bottom, top) = core1_bounds();
+    unsafe { measure(bottom, top) }
+}
+
+// MPUの領域は大きさに揃えた位置にしか置けない
+#[cfg(feature = "stack-guard")]
+fn guard_base(start: usize) -> usize {
+    (start + GUARD_SIZE - 1) & !(GUARD_SIZE - 1)
+}
+
+// core1の最初で呼ぶ (MPUはコアごと)
+#[cfg(feature = "stack-guard")]
+pub fn install_core1_guard() {
+    // RASR: XN(28) AP=000(アクセス禁止) SIZE=log2(256)-1 ENABLE
+    const RASR_XN: u32 = 1 << 28;
+    const RASR_SIZE_256: u32 = 7 << 1;
+    const RASR_ENABLE: u32 = 1;
+    // CTRL: 領域外は従来通り(PRIVDEFENA)で有効化
+    const CTRL_PRIVDEFENA: u32 = 1 << 2;
+    const CTRL_ENABLE: u32 = 1;
+    let (start, _) = core1_region();
+    let mpu = unsafe { &*cortex_m::peripheral::MPU::PTR };
+    unsafe {
+        mpu.rnr.write(0);
+        mpu.rbar.write(guard_base(start) as u32);
+        mpu.rasr.write(RASR_XN | RASR_SIZE_256 | RASR_ENABLE);
+        mpu.ctrl.write(CTRL_PRIVDEFENA | CTRL_ENABLE);
+    }
+    cortex_m::asm::dsb();
+    cortex_m::asm::isb();
+}
+
+pub fn handle_command(args: &mut Args) -> Result<Reply, CommandError> {
+    if args.next_opt().is_some() {
+        return Err(CommandError::InvalidArgument);
+    }
+    let core0 = usage_core0();
+    let core1 = usage_core1();
+    let guard = if cfg!(feature = "stack-guard") {
+        "on"
+    } else {
+        "off"
+    };
+    Ok(ok_reply(|r| {
+        write!(
+            r,
+            " stack core0={}/{} core1={}/{} guard={}",
+            core0.used, core0.size, core1.used, core1.size, guard
+        )
+    }))
+}
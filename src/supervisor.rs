@@ -0,0 +1,183 @@
+// ウォッチドッグによる両コアの見張り
+// core0はUSBポーリング割り込み、core1は5ms割り込みでチェックインし、
+// core0の10ms割り込みで両方のチェックインが揃っていたときだけウォッチドッグに餌をやる
+// 揃わなかったコアは毎回スクラッチレジスタに書いておくので、ウォッチドッグで落ちた後の起動で
+// どちらが止まっていたか分かる (スクラッチはウォッチドッグリセットでは消えない)
+//
+// タイムアウトはKVストアの `watchdog_ms` (u32) で変えられ、0なら見張らない。起動時に読む
+use crate::globals::{KV, WATCHDOG};
+use core::sync::atomic::{AtomicBool, AtomicU8, Ordering};
+use cortex_m::interrupt;
+use defmt::{info, warn};
+use rp_pico::hal::{
+    fugit::MicrosDurationU32,
+    pac,
+    watchdog::{ScratchRegister, Watchdog},
+};
+
+pub const TIMEOUT_KEY: &str = "watchdog_ms";
+pub const DEFAULT_TIMEOUT_MS: u32 = 2000;
+// フラッシュのセクタ消去(数十ms)を何回か挟んでも落ちない長さにする
+pub const MIN_TIMEOUT_MS: u32 = 500;
+// ウォッチドッグのカウンタは24bitで、RP2040-E1により1usに2減る
+pub const MAX_TIMEOUT_MS: u32 = 0xFF_FFFF / 2 / 1000;
+
+// スクラッチ4〜7はブートROMが使うので0を使う
+const CAUSE_REGISTER: ScratchRegister = ScratchRegister::Scratch0;
+const CAUSE_MAGIC: u32 = 0x5744_0000; // "WD"
+const CAUSE_MAGIC_MASK: u32 = 0xFFFF_0000;
+const MISSING_CORE0: u32 = 1 << 0;
+const MISSING_CORE1: u32 = 1 << 1;
+// `reboot` による意図した再起動
+const CAUSE_REQUESTED: u32 = 1 << 8;
+
+static CORE0_ALIVE: AtomicBool = AtomicBool::new(false);
+static CORE1_ALIVE: AtomicBool = AtomicBool::new(false);
+static RUNNING: AtomicBool = AtomicBool::new(false);
+static LAST_HANG: AtomicU8 = AtomicU8::new(HangCause::None as u8);
+static LAST_REQUESTED: AtomicBool = AtomicBool::new(false);
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum HangCause {
+    None = 0,
+    Core0 = 1,
+    Core1 = 2,
+    Both = 3,
+    // ウォッチドッグで落ちたが記録が無い
+    Unknown = 4,
+}
+
+impl HangCause {
+    fn from_u8(v: u8) -> Self {
+        match v {
+            0 => HangCause::None,
+            1 => HangCause::Core0,
+            2 => HangCause::Core1,
+            3 => HangCause::Both,
+            _ => HangCause::Unknown,
+        }
+    }
+
+    pub fn name(&self) -> &'static str {
+        match self {
+            HangCause::None => "none",
+            HangCause::Core0 => "core0",
+            HangCause::Core1 => "core1",
+            HangCause::Both => "both",
+            HangCause::Unknown => "unknown",
+        }
+    }
+}
+
+// 前回の記録を読んで消す。クロック初期化の後、WATCHDOGを置いてから呼ぶ
+pub fn take_last_hang() -> HangCause {
+    let timer_reset = unsafe { (*pac::WATCHDOG::ptr()).reason().read().timer().bit_is_set() };
+    let recorded = interrupt::free(|cs| {
+        let mut watchdog = WATCHDOG.borrow(cs).borrow_mut();
+        let watchdog = watchdog.as_mut()?;
+        let value = watchdog.read_scratch(CAUSE_REGISTER);
+        watchdog.write_scratch(CAUSE_REGISTER, 0);
+        Some(value)
+    });
+    let cause = match recorded {
+        _ if !timer_reset => HangCause::None,
+        Some(v) if v & CAUSE_MAGIC_MASK == CAUSE_MAGIC => {
+            if v & CAUSE_REQUESTED != 0 {
+                LAST_REQUESTED.store(true, Ordering::Relaxed);
+                HangCause::None
+            } else {
+                // 揃っていたのに餌が来なかったなら、見張り役のcore0の割り込みが止まっていた
+                match v & (MISSING_CORE0 | MISSING_CORE1) {
+                    0 | MISSING_CORE0 => HangCause::Core0,
+                    MISSING_CORE1 => HangCause::Core1,
+                    _ => HangCause::Both,
+                }
+            }
+        }
+        _ => HangCause::Unknown,
+    };
+    LAST_HANG.store(cause as u8, Ordering::Relaxed);
+    if cause != HangCause::None {
+        warn!("watchdog reset: {} stopped", cause.name());
+    }
+    cause
+}
+
+pub fn last_hang() -> HangCause {
+    HangCause::from_u8(LAST_HANG.load(Ordering::Relaxed))
+}
+
+// 前回のウォッチドッグリセットが `reboot` によるものだったか
+pub fn last_reset_requested() -> bool {
+    LAST_REQUESTED.load(Ordering::Relaxed)
+}
+
+fn configured_timeout_ms() -> u32 {
+    let stored = interrupt::free(|cs| {
+        KV.borrow(cs)
+            .borrow()
+            .as_ref()
+            .and_then(|kv| kv.get_u32(TIMEOUT_KEY).ok().flatten())
+    });
+    match stored {
+        Some(0) => 0,
+        Some(ms) => ms.clamp(MIN_TIMEOUT_MS, MAX_TIMEOUT_MS),
+        None => DEFAULT_TIMEOUT_MS,
+    }
+}
+
+// core1が動き出してから呼ぶ
+pub fn start() {
+    let timeout_ms = configured_timeout_ms();
+    if timeout_ms == 0 {
+        info!("watchdog disabled");
+        return;
+    }
+    interrupt::free(|cs| {
+        if let Some(watchdog) = WATCHDOG.borrow(cs).borrow_mut().as_mut() {
+            // デバッガで止めている間は数えない
+            watchdog.pause_on_debug(true);
+            watchdog.write_scratch(CAUSE_REGISTER, CAUSE_MAGIC);
+            watchdog.start(MicrosDurationU32::millis(timeout_ms));
+            RUNNING.store(true, Ordering::Relaxed);
+        }
+    });
+    info!("watchdog started: {} ms", timeout_ms);
+}
+
+pub fn checkin_core0() {
+    CORE0_ALIVE.store(true, Ordering::Relaxed);
+}
+
+pub fn checkin_core1() {
+    CORE1_ALIVE.store(true, Ordering::Relaxed);
+}
+
+// core0の10ms割り込みから呼ぶ
+pub fn poll() {
+    if !RUNNING.load(Ordering::Relaxed) {
+        return;
+    }
+    let mut missing = 0;
+    if !CORE0_ALIVE.load(Ordering::Relaxed) {
+        missing |= MISSING_CORE0;
+    }
+    if !CORE1_ALIVE.load(Ordering::Relaxed) {
+        missing |= MISSING_CORE1;
+    }
+    interrupt::free(|cs| {
+        if let Some(watchdog) = WATCHDOG.borrow(cs).borrow_mut().as_mut() {
+            if missing == 0 {
+                watchdog.feed();
+                CORE0_ALIVE.store(false, Ordering::Relaxed);
+                CORE1_ALIVE.store(false, Ordering::Relaxed);
+            }
+            watchdog.write_scratch(CAUSE_REGISTER, CAUSE_MAGIC | missing);
+        }
+    });
+}
+
+// 意図した再起動の前に呼ぶ。次の起動でハングとして報告しない
+pub fn mark_requested_reset(watchdog: &mut Watchdog) {
+    watchdog.write_scratch(CAUSE_REGISTER, CAUSE_MAGIC | CAUSE_REQUESTED);
+}
@@ -0,0 +1,21 @@
+// 1MHzで進むTIMERの読み出し
+// TIMERはcore0::mainで起動済み。どのモジュールからもここを通して時刻を読む
+use rp_pico::hal::pac;
+
+// 下位32bitだけ使う。約71分で一周するので差分はwrapping_subで取る
+pub fn now_us() -> u32 {
+    unsafe { (*pac::TIMER::ptr()).timerawl().read().bits() }
+}
+
+// 起動からの64bitの時刻
+pub fn uptime_us() -> u64 {
+    let timer = unsafe { &*pac::TIMER::ptr() };
+    // 上位を読み直して、下位の桁上がりと重ならなかった値を使う
+    loop {
+        let hi = timer.timerawh().read().bits();
+        let lo = timer.timerawl().read().bits();
+        if timer.timerawh().read().bits() == hi {
+            return ((hi as u64) << 32) | lo as u64;
+        }
+    }
+}
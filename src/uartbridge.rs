@@ -0,0 +1,438 @@
+// 2つ目のCDCシリアルをUART0/UART1へ素通しするUSB-UART変換
+// 1つ目のシリアルはコマンド用のまま残し、ボーレート・パリティ・ストップビットはホストが設定したライン設定に追従する
+// TX/RXはピンプールから借りる。RP2040ではGPIO番号%4が0ならTX、1ならRXで、((GPIO番号+4)/8)%2がUARTの番号になる
+use crate::command::{ok_reply, Args, CommandError, Reply};
+use crate::globals::{RESETS, UART_BRIDGE};
+use crate::pinpool::{self, NUM_BANK0_PINS};
+use core::fmt::Write;
+use cortex_m::interrupt;
+use defmt::warn;
+use heapless::Vec;
+use rp_pico::hal::fugit::HertzU32;
+use rp_pico::hal::gpio::{DynPinId, DynPullType, FunctionUart, Pin};
+use rp_pico::hal::pac;
+use rp_pico::hal::uart::{
+    DataBits, Enabled, Parity, StopBits, UartConfig, UartDevice, UartPeripheral, ValidatedPinRx,
+    ValidatedPinTx,
+};
+use usb_device::bus::UsbBus;
+use usbd_serial::{LineCoding, ParityType, SerialPort};
+
+pub const MIN_BAUD: u32 = 300;
+// RXはUSBの2msポーリングでしか吸い出さないので、その間に32バイトのFIFOが溢れない速さまで
+// 115200bpsでも2msで約23バイト届く
+pub const MAX_BAUD: u32 = 115_200;
+// USBの1パケット分ずつ中継する
+const CHUNK: usize = 64;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum BusId {
+    Uart0,
+    Uart1,
+}
+
+impl BusId {
+    pub fn parse(s: &str) -> Result<Self, CommandError> {
+        match s {
+            "0" => Ok(BusId::Uart0),
+            "1" => Ok(BusId::Uart1),
+            _ => Err(CommandError::InvalidArgument),
+        }
+    }
+
+    pub fn index(&self) -> u8 {
+        match self {
+            BusId::Uart0 => 0,
+            BusId::Uart1 => 1,
+        }
+    }
+
+    fn accepts(&self, gpio: u8, role: u8) -> bool {
+        (gpio as usize) < NUM_BANK0_PINS && gpio % 4 == role && ((gpio + 4) / 8) % 2 == self.index()
+    }
+
+    pub fn is_tx(&self, gpio: u8) -> bool {
+        self.accepts(gpio, 0)
+    }
+
+    pub fn is_rx(&self, gpio: u8) -> bool {
+        self.accepts(gpio, 1)
+    }
+}
+
+// UARTに設定できる形にしたライン設定
+#[derive(Clone, Copy, PartialEq, Eq)]
+pub struct LineSettings {
+    pub baud: u32,
+    pub data_bits: u8,
+    pub parity: ParityType,
+    pub two_stop_bits: bool,
+}
+
+impl LineSettings {
+    // UARTで扱えない設定 (16bitデータ、マーク/スペースパリティ、範囲外のボーレート) はNone
+    pub fn from_line_coding(coding: &LineCoding) -> Option<Self> {
+        if !(5..=8).contains(&coding.data_bits())
+            || !(MIN_BAUD..=MAX_BAUD).contains(&coding.data_rate())
+            || matches!(coding.parity_type(), ParityType::Mark | ParityType::Space)
+        {
+            return None;
+        }
+        Some(Self {
+            baud: coding.data_rate(),
+            data_bits: coding.data_bits(),
+            parity: coding.parity_type(),
+            // 1.5ストップビットは無いので2ビットにする
+            two_stop_bits: coding.stop_bits() != usbd_serial::StopBits::One,
+        })
+    }
+
+    pub fn parity_str(&self) -> &'static str {
+        match self.parity {
+            ParityType::Odd => "odd",
+            ParityType::Even => "even",
+            _ => "none",
+        }
+    }
+
+    fn to_config(self) -> UartConfig {
+        let data_bits = match self.data_bits {
+            5 => DataBits::Five,
+            6 => DataBits::Six,
+            7 => DataBits::Seven,
+            _ => DataBits::Eight,
+        };
+        let parity = match self.parity {
+            ParityType::Odd => Some(Parity::Odd),
+            ParityType::Even => Some(Parity::Even),
+            _ => None,
+        };
+        let stop_bits = if self.two_stop_bits {
+            StopBits::Two
+        } else {
+            StopBits::One
+        };
+        UartConfig::new(HertzU32::Hz(self.baud), data_bits, parity, stop_bits)
+    }
+}
+
+impl Default for LineSettings {
+    fn default() -> Self {
+        Self {
+            baud: 115_200,
+            data_bits: 8,
+            parity: ParityType::None,
+            two_stop_bits: false,
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum UartCommand {
+    Init { bus: BusId, tx: u8, rx: u8 },
+    Deinit,
+    Status,
+}
+
+impl UartCommand {
+    pub fn parse(args: &mut Args) -> Result<Self, CommandError> {
+        match args.next_str()? {
+            "init" => {
+                let bus = BusId::parse(args.next_str()?)?;
+                let tx = u8::try_from(args.next_u32()?).map_err(|_| CommandError::OutOfRange)?;
+                let rx = u8::try_from(args.next_u32()?).map_err(|_| CommandError::OutOfRange)?;
+                if !bus.is_tx(tx) || !bus.is_rx(rx) {
+                    return Err(CommandError::OutOfRange);
+                }
+                Ok(UartCommand::Init { bus, tx, rx })
+            }
+            "deinit" => Ok(UartCommand::Deinit),
+            "status" => Ok(UartCommand::Status),
+            _ => Err(CommandError::InvalidArgument),
+        }
+    }
+}
+
+pub type UartPin = Pin<DynPinId, FunctionUart, DynPullType>;
+type Uart<D> = UartPeripheral<Enabled, D, (ValidatedPinTx<UartPin, D>, ValidatedPinRx<UartPin, D>)>;
+
+fn take_pin(num: u8) -> Result<UartPin, CommandError> {
+    pinpool::take(num)?
+        .try_into_function::<FunctionUart>()
+        .map_err(|pin| {
+            pinpool::give(pin);
+            CommandError::InvalidArgument
+        })
+}
+
+struct ActiveUart<D: UartDevice> {
+    uart: Uart<D>,
+    tx: u8,
+    rx: u8,
+    settings: LineSettings,
+}
+
+// 未使用時はPACのペリフェラル、初期化後はHALのUARTとして持つ
+struct Port<D: UartDevice> {
+    device: Option<D>,
+    active: Option<ActiveUart<D>>,
+}
+
+impl<D: UartDevice> Port<D> {
+    fn new(device: D) -> Self {
+        Self {
+            device: Some(device),
+            active: None,
+        }
+    }
+
+    fn init(
+        &mut self,
+        tx: u8,
+        rx: u8,
+        settings: LineSettings,
+        peri_hz: u32,
+        resets: &mut pac::RESETS,
+    ) -> Result<(), CommandError> {
+        self.deinit();
+        let device = self.device.as_ref().ok_or(CommandError::NotReady)?;
+        let tx_pin = take_pin(tx)?;
+        let rx_pin = match take_pin(rx) {
+            Ok(pin) => pin,
+            Err(e) => {
+                pinpool::give(tx_pin);
+                return Err(e);
+            }
+        };
+        let pins = match (
+            ValidatedPinTx::validate(tx_pin, device),
+            ValidatedPinRx::validate(rx_pin, device),
+        ) {
+            (Ok(tx_pin), Ok(rx_pin)) => (tx_pin, rx_pin),
+            // parseでピン番号は検査済みなので通常は起きない
+            pins => {
+                // 番号はここで借りたピンのもの
+                unsafe { pinpool::give_wrapped(pins, &[tx, rx]) };
+                return Err(CommandError::InvalidArgument);
+            }
+        };
+        let device = self.device.take().ok_or(CommandError::NotReady)?;
+        // ボーレートは範囲を検査済みなのでenableは失敗しない
+        let uart = UartPeripheral::new(device, pins, resets)
+            .enable(settings.to_config(), HertzU32::Hz(peri_hz))
+            .map_err(|_| CommandError::InvalidArgument)?;
+        self.active = Some(ActiveUart {
+            uart,
+            tx,
+            rx,
+            settings,
+        });
+        Ok(())
+    }
+
+    fn deinit(&mut self) -> bool {
+        let Some(active) = self.active.take() else {
+            return false;
+        };
+        let (device, pins) = active.uart.disable().free();
+        self.device = Some(device);
+        // 番号はinitで借りたピンのもの
+        unsafe { pinpool::give_wrapped(pins, &[active.tx, active.rx]) };
+        true
+    }
+
+    fn reconfigure(&mut self, settings: LineSettings, peri_hz: u32) {
+        let Some(active) = self.active.take() else {
+            return;
+        };
+        match active
+            .uart
+            .disable()
+            .enable(settings.to_config(), HertzU32::Hz(peri_hz))
+        {
+            Ok(uart) => {
+                self.active = Some(ActiveUart {
+                    uart,
+                    settings,
+                    ..active
+                })
+            }
+            // 範囲外の設定はfrom_line_codingで弾いているので起きない
+            Err(_) => warn!("UART reconfigure failed"),
+        }
+    }
+
+    fn status(&self) -> Option<(u8, u8, LineSettings)> {
+        self.active.as_ref().map(|a| (a.tx, a.rx, a.settings))
+    }
+
+    // USBから受けた分をUARTへ送り、UARTで受けた分をUSBへ返す
+    fn pump<B: UsbBus>(
+        &mut self,
+        serial: &mut SerialPort<'_, B>,
+        to_uart: &mut Vec<u8, CHUNK>,
+        to_host: &mut Vec<u8, CHUNK>,
+        peri_hz: u32,
+    ) {
+        // 扱えないライン設定が来たら今の設定のまま中継を続ける
+        if let Some(settings) = LineSettings::from_line_coding(serial.line_coding()) {
+            if self.active.as_ref().is_some_and(|a| a.settings != settings) {
+                self.reconfigure(settings, peri_hz);
+            }
+        }
+        let Some(active) = self.active.as_mut() else {
+            return;
+        };
+
+        if to_uart.is_empty() {
+            let mut buf = [0u8; CHUNK];
+            if let Ok(n) = serial.read(&mut buf) {
+                let _ = to_uart.extend_from_slice(&buf[..n]);
+            }
+        }
+        if !to_uart.is_empty() {
+            // FIFOに入りきらなかった分は次のポーリングで送る
+            let rest = match active.uart.write_raw(to_uart) {
+                Ok(rest) => rest.len(),
+                Err(_) => to_uart.len(),
+            };
+            let sent = to_uart.len() - rest;
+            to_uart.rotate_left(sent);
+            to_uart.truncate(rest);
+        }
+
+        if to_host.is_empty() {
+            let mut buf = [0u8; CHUNK];
+            if let Ok(n) = active.uart.read_raw(&mut buf) {
+                let _ = to_host.extend_from_slice(&buf[..n]);
+            }
+        }
+        if !to_host.is_empty() {
+            if let Ok(n) = serial.write(to_host) {
+                to_host.rotate_left(n);
+                to_host.truncate(to_host.len() - n);
+            }
+        }
+    }
+}
+
+pub struct UartBridgeState {
+    uart0: Port<pac::UART0>,
+    uart1: Port<pac::UART1>,
+    bus: Option<BusId>,
+    peri_hz: u32,
+    to_uart: Vec<u8, CHUNK>,
+    to_host: Vec<u8, CHUNK>,
+}
+
+impl UartBridgeState {
+    pub fn new(uart0: pac::UART0, uart1: pac::UART1, peri_hz: u32) -> Self {
+        Self {
+            uart0: Port::new(uart0),
+            uart1: Port::new(uart1),
+            bus: None,
+            peri_hz,
+            to_uart: Vec::new(),
+            to_host: Vec::new(),
+        }
+    }
+
+    fn deinit(&mut self) -> bool {
+        self.to_uart.clear();
+        self.to_host.clear();
+        match self.bus.take() {
+            Some(BusId::Uart0) => self.uart0.deinit(),
+            Some(BusId::Uart1) => self.uart1.deinit(),
+            None => false,
+        }
+    }
+
+    pub fn execute(
+        &mut self,
+        cmd: &UartCommand,
+        resets: &mut pac::RESETS,
+    ) -> Result<Reply, CommandError> {
+        match *cmd {
+            UartCommand::Init { bus, tx, rx } => {
+                // 中継先のシリアルは1つなので、使えるUARTも1つだけ
+                self.deinit();
+                let settings = LineSettings::default();
+                match bus {
+                    BusId::Uart0 => self.uart0.init(tx, rx, settings, self.peri_hz, resets)?,
+                    BusId::Uart1 => self.uart1.init(tx, rx, settings, self.peri_hz, resets)?,
+                }
+                self.bus = Some(bus);
+                Ok(ok_reply(|r| {
+                    write!(r, " uart init bus={} tx={} rx={}", bus.index(), tx, rx)
+                }))
+            }
+            UartCommand::Deinit => {
+                if !self.deinit() {
+                    return Err(CommandError::NotReady);
+                }
+                Ok(ok_reply(|r| r.write_str(" uart deinit")))
+            }
+            UartCommand::Status => {
+                let status = match self.bus {
+                    Some(BusId::Uart0) => self.uart0.status(),
+                    Some(BusId::Uart1) => self.uart1.status(),
+                    None => None,
+                };
+                let (Some(bus), Some((tx, rx, settings))) = (self.bus, status) else {
+                    return Ok(ok_reply(|r| r.write_str(" uart off")));
+                };
+                Ok(ok_reply(|r| {
+                    write!(
+                        r,
+                        " uart bus={} tx={} rx={} baud={} bits={} parity={} stop={}",
+                        bus.index(),
+                        tx,
+                        rx,
+                        settings.baud,
+                        settings.data_bits,
+                        settings.parity_str(),
+                        if settings.two_stop_bits { 2 } else { 1 }
+                    )
+                }))
+            }
+        }
+    }
+
+    pub fn pump<B: UsbBus>(&mut self, serial: &mut SerialPort<'_, B>) {
+        match self.bus {
+            Some(BusId::Uart0) => {
+                self.uart0
+                    .pump(serial, &mut self.to_uart, &mut self.to_host, self.peri_hz)
+            }
+            Some(BusId::Uart1) => {
+                self.uart1
+                    .pump(serial, &mut self.to_uart, &mut self.to_host, self.peri_hz)
+            }
+            None => {
+                // 未接続の間はホストからの送信を捨てて詰まらないようにする
+                let mut buf = [0u8; CHUNK];
+                let _ = serial.read(&mut buf);
+            }
+        }
+    }
+}
+
+pub fn handle_command(args: &mut Args) -> Result<Reply, CommandError> {
+    let cmd = UartCommand::parse(args)?;
+    interrupt::free(|cs| {
+        let mut bridge = UART_BRIDGE.borrow(cs).borrow_mut();
+        let bridge = bridge.as_mut().ok_or(CommandError::NotReady)?;
+        let mut resets = RESETS.borrow(cs).borrow_mut();
+        let resets = resets.as_mut().ok_or(CommandError::NotReady)?;
+        bridge.execute(&cmd, resets)
+    })
+}
+
+// USBポーリングの直後に呼ぶ
+pub fn pump<B: UsbBus>(serial: &mut SerialPort<'_, B>) {
+    interrupt::free(|cs| {
+        if let Some(bridge) = UART_BRIDGE.borrow(cs).borrow_mut().as_mut() {
+            bridge.pump(serial);
+        }
+    });
+}
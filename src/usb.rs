@@ -1,20 +1,68 @@
 extern crate alloc;
+use crate::capture;
+use crate::command;
+#[cfg(feature = "hid")]
+use crate::globals::KEYBOARD;
 use crate::globals::MAX_MESSAGE_SIZE;
-use crate::globals::{SERIAL, USB_DEV};
+#[cfg(feature = "midi")]
+use crate::globals::MIDI_CLASS;
+use crate::globals::{BRIDGE_SERIAL, SERIAL, USB_DEV, VENDOR};
+#[cfg(feature = "midi")]
+use crate::midi;
 use crate::sharedmessage::SHARED_MESSAGE_CORE0_TO_CORE1;
+use crate::uartbridge;
 use cortex_m::interrupt;
 use defmt::{info, warn};
 use heapless::{String, Vec};
+use rp_pico::hal::usb::UsbBus as HalUsbBus;
 use usb_device::bus::UsbBus;
+use usb_device::class::UsbClass;
 use usbd_serial::SerialPort;
 
 pub fn poll_usb() {
     interrupt::free(|cs| {
-        if let (Some(usb_dev), Some(serial)) = (
+        #[cfg(feature = "hid")]
+        let mut keyboard = KEYBOARD.borrow(cs).borrow_mut();
+        #[cfg(feature = "midi")]
+        let mut midi_class = MIDI_CLASS.borrow(cs).borrow_mut();
+        if let (Some(usb_dev), Some(serial), Some(bridge_serial), Some(vendor)) = (
             USB_DEV.borrow(cs).borrow_mut().as_mut(),
             SERIAL.borrow(cs).borrow_mut().as_mut(),
+            BRIDGE_SERIAL.borrow(cs).borrow_mut().as_mut(),
+            VENDOR.borrow(cs).borrow_mut().as_mut(),
         ) {
-            usb_dev.poll(&mut [serial]);
+            // フィーチャーで増えるクラスも含めてまとめてポーリングする
+            let mut classes: Vec<&mut dyn UsbClass<HalUsbBus>, 5> = Vec::new();
+            let _ = classes.push(serial);
+            let _ = classes.push(bridge_serial);
+            let _ = classes.push(vendor);
+            #[cfg(feature = "hid")]
+            if let Some(keyboard) = keyboard.as_mut() {
+                let _ = classes.push(keyboard);
+            }
+            #[cfg(feature = "midi")]
+            if let Some(midi_class) = midi_class.as_mut() {
+                let _ = classes.push(midi_class);
+            }
+            usb_dev.poll(&mut classes);
+        }
+    });
+    interrupt::free(|cs| {
+        if let (Some(serial), Some(bridge_serial), Some(vendor)) = (
+            SERIAL.borrow(cs).borrow_mut().as_mut(),
+            BRIDGE_SERIAL.borrow(cs).borrow_mut().as_mut(),
+            VENDOR.borrow(cs).borrow_mut().as_mut(),
+        ) {
+            capture::pump_dump(serial, vendor);
+            uartbridge::pump(bridge_serial);
+        }
+        #[cfg(feature = "hid")]
+        if let Some(keyboard) = KEYBOARD.borrow(cs).borrow_mut().as_mut() {
+            keyboard.pump();
+        }
+        #[cfg(feature = "midi")]
+        if let Some(midi_class) = MIDI_CLASS.borrow(cs).borrow_mut().as_mut() {
+            midi::pump(midi_class);
         }
     });
 }
@@ -50,7 +98,7 @@ impl UsbMessageReciver {
                     b'\n' if self.in_message => {
                         if let Ok(s) = String::<MAX_MESSAGE_SIZE>::from_utf8(self.buffer.clone()) {
                             info!("Message: *{}", s.as_str());
-                            self.handle_message(s);
+                            self.handle_message(s, serial);
                         } else {
                             warn!("Invalid UTF-8: {:?}", self.buffer[..]);
                         }
@@ -72,11 +120,36 @@ impl UsbMessageReciver {
         }
     }
 
-    fn handle_message(&self, msg: heapless::String<MAX_MESSAGE_SIZE>) {
-        // パースや処理はここに追加
+    fn handle_message<B: UsbBus>(
+        &self,
+        msg: heapless::String<MAX_MESSAGE_SIZE>,
+        serial: &mut SerialPort<'_, B>,
+    ) {
         info!("Handling message: {}", msg.as_str());
+        // コマンドとして解釈できたものはその場で応答し、それ以外はcore1へ転送
+        if let Some(reply) = command::dispatch(msg.as_str()) {
+            write_line(serial, reply.as_str());
+            return;
+        }
         interrupt::free(|cs| {
             SHARED_MESSAGE_CORE0_TO_CORE1.borrow(cs).write(msg);
         });
     }
 }
+
+// 1行をシリアルへ書き出す
+// 割り込み内から呼ぶので送信バッファが一杯なら残りは捨てる
+pub fn write_line<B: UsbBus>(serial: &mut SerialPort<'_, B>, line: &str) {
+    for chunk in [line.as_bytes(), b"\r\n"] {
+        let mut rest = chunk;
+        while !rest.is_empty() {
+            match serial.write(rest) {
+                Ok(n) if n > 0 => rest = &rest[n..],
+                _ => {
+                    warn!("USB write buffer full, dropping output");
+                    return;
+                }
+            }
+        }
+    }
+}
@@ -0,0 +1,316 @@
+// ベンダー固有クラスのバルクIN/OUTインターフェース
+// CDCの行単位のやり取りとは別に、キャプチャデータなどのバイナリを大きな単位で流すために使う
+// Microsoft OS 2.0ディスクリプタでWinUSBを指定するので、Windowsでもドライバなしで開ける
+// ホストからバルクOUTで届いたデータは受信バッファに溜め、`read` か `*bulk read [n]` で取り出す
+// バッファに1パケット分の空きが無い間は読まずにNAKを返させ、ホストを待たせる
+use crate::command::{ok_reply, parse_u32, write_hex, Args, CommandError, Reply};
+use crate::globals::VENDOR;
+use core::fmt::Write;
+use cortex_m::interrupt;
+use heapless::Deque;
+use usb_device::class_prelude::*;
+use usb_device::control::{Recipient, RequestType};
+
+pub const MAX_PACKET_SIZE: u16 = 64;
+pub const RX_BUFFER_SIZE: usize = 512;
+// `*bulk read` で1行に返す最大バイト数 (16進で2文字ずつ)
+pub const MAX_READ_LEN: usize = 64;
+pub const INTERFACE_NAME: &str = "RP2040 Bulk";
+// GET_MS_OS_20_DESCRIPTORのbRequest
+pub const MS_VENDOR_CODE: u8 = 0x20;
+// WinUSBデバイスとして開くときのインターフェースGUID
+pub const DEVICE_INTERFACE_GUID: &str = "{8C6E2A4F-3B1D-4E5A-9F07-2D6B1C4E8A31}";
+
+const USB_CLASS_VENDOR: u8 = 0xFF;
+const CAPABILITY_TYPE_PLATFORM: u8 = 0x05;
+const MS_OS_20_DESCRIPTOR_INDEX: u16 = 7;
+// Windows 8.1以降
+const WINDOWS_VERSION: u32 = 0x0603_0000;
+// {D8DD60DF-4589-4CC7-9CD2-659D9E648A9F}
+const MS_OS_20_PLATFORM_UUID: [u8; 16] = [
+    0xDF, 0x60, 0xDD, 0xD8, 0x89, 0x45, 0xC7, 0x4C, 0x9C, 0xD2, 0x65, 0x9D, 0x9E, 0x64, 0x8A, 0x9F,
+];
+
+const SET_HEADER_LEN: usize = 10;
+const CONFIGURATION_SUBSET_LEN: usize = 8;
+const FUNCTION_SUBSET_LEN: usize = 8;
+const COMPATIBLE_ID_LEN: usize = 20;
+const PROPERTY_NAME: &str = "DeviceInterfaceGUIDs";
+// UTF-16で終端NULを含む
+const PROPERTY_NAME_LEN: usize = (PROPERTY_NAME.len() + 1) * 2;
+// REG_MULTI_SZなので終端NULが2つ
+const PROPERTY_DATA_LEN: usize = (DEVICE_INTERFACE_GUID.len() + 2) * 2;
+const REGISTRY_PROPERTY_LEN: usize = 10 + PROPERTY_NAME_LEN + PROPERTY_DATA_LEN;
+pub const MS_OS_20_SET_LEN: usize = SET_HEADER_LEN
+    + CONFIGURATION_SUBSET_LEN
+    + FUNCTION_SUBSET_LEN
+    + COMPATIBLE_ID_LEN
+    + REGISTRY_PROPERTY_LEN;
+
+struct Cursor<'a> {
+    buf: &'a mut [u8],
+    pos: usize,
+}
+
+impl Cursor<'_> {
+    fn bytes(&mut self, data: &[u8]) {
+        self.buf[self.pos..self.pos + data.len()].copy_from_slice(data);
+        self.pos += data.len();
+    }
+
+    fn u16(&mut self, value: u16) {
+        self.bytes(&value.to_le_bytes());
+    }
+
+    fn u32(&mut self, value: u32) {
+        self.bytes(&value.to_le_bytes());
+    }
+
+    fn utf16z(&mut self, s: &str) {
+        for c in s.encode_utf16() {
+            self.u16(c);
+        }
+        self.u16(0);
+    }
+}
+
+// 複合デバイスなのでバルクのインターフェースだけをWinUSBにするファンクションサブセットを作る
+pub fn ms_os_20_descriptor_set(first_interface: u8) -> [u8; MS_OS_20_SET_LEN] {
+    let mut buf = [0u8; MS_OS_20_SET_LEN];
+    let mut w = Cursor {
+        buf: &mut buf,
+        pos: 0,
+    };
+    // セットヘッダー
+    w.u16(SET_HEADER_LEN as u16);
+    w.u16(0x0000);
+    w.u32(WINDOWS_VERSION);
+    w.u16(MS_OS_20_SET_LEN as u16);
+    // コンフィギュレーションサブセット
+    w.u16(CONFIGURATION_SUBSET_LEN as u16);
+    w.u16(0x0001);
+    w.bytes(&[0, 0]);
+    w.u16((MS_OS_20_SET_LEN - SET_HEADER_LEN) as u16);
+    // ファンクションサブセット
+    w.u16(FUNCTION_SUBSET_LEN as u16);
+    w.u16(0x0002);
+    w.bytes(&[first_interface, 0]);
+    w.u16((FUNCTION_SUBSET_LEN + COMPATIBLE_ID_LEN + REGISTRY_PROPERTY_LEN) as u16);
+    // 互換ID
+    w.u16(COMPATIBLE_ID_LEN as u16);
+    w.u16(0x0003);
+    w.bytes(b"WINUSB\0\0");
+    w.bytes(&[0; 8]);
+    // レジストリプロパティ
+    w.u16(REGISTRY_PROPERTY_LEN as u16);
+    w.u16(0x0004);
+    w.u16(0x0007); // REG_MULTI_SZ
+    w.u16(PROPERTY_NAME_LEN as u16);
+    w.utf16z(PROPERTY_NAME);
+    w.u16(PROPERTY_DATA_LEN as u16);
+    w.utf16z(DEVICE_INTERFACE_GUID);
+    w.u16(0);
+    buf
+}
+
+// BOSに入れるMS OS 2.0プラットフォームケーパビリティ (先頭3バイトはBosWriterが書く)
+pub fn ms_os_20_platform_capability() -> [u8; 25] {
+    let mut buf = [0u8; 25];
+    let mut w = Cursor {
+        buf: &mut buf,
+        pos: 0,
+    };
+    w.bytes(&[0]);
+    w.bytes(&MS_OS_20_PLATFORM_UUID);
+    w.u32(WINDOWS_VERSION);
+    w.u16(MS_OS_20_SET_LEN as u16);
+    w.bytes(&[MS_VENDOR_CODE, 0]);
+    buf
+}
+
+pub struct VendorClass<'a, B: UsbBus> {
+    iface: InterfaceNumber,
+    name: StringIndex,
+    read_ep: EndpointOut<'a, B>,
+    write_ep: EndpointIn<'a, B>,
+    // 制御転送の内部バッファに収まらないので静的な領域から返す
+    // ヒープは小さいので置かず、singleton!で.bssに確保する
+    ms_os_descriptor: &'static [u8],
+    rx: Deque<u8, RX_BUFFER_SIZE>,
+    // 受け取った累計バイト数
+    rx_total: u32,
+}
+
+impl<'a, B: UsbBus> VendorClass<'a, B> {
+    pub fn new(alloc: &'a UsbBusAllocator<B>) -> Self {
+        let iface = alloc.interface();
+        let ms_os_descriptor =
+            cortex_m::singleton!(: [u8; MS_OS_20_SET_LEN] = ms_os_20_descriptor_set(iface.into()))
+                .unwrap();
+        Self {
+            iface,
+            name: alloc.string(),
+            read_ep: alloc.bulk(MAX_PACKET_SIZE),
+            write_ep: alloc.bulk(MAX_PACKET_SIZE),
+            ms_os_descriptor,
+            rx: Deque::new(),
+            rx_total: 0,
+        }
+    }
+
+    // 1パケット分まで送る。送れたバイト数を返す
+    pub fn write_packet(&mut self, data: &[u8]) -> usb_device::Result<usize> {
+        let len = data.len().min(MAX_PACKET_SIZE as usize);
+        self.write_ep.write(&data[..len])
+    }
+
+    pub fn read_packet(&mut self, data: &mut [u8]) -> usb_device::Result<usize> {
+        self.read_ep.read(data)
+    }
+
+    // 受信バッファから取り出す。取り出したバイト数を返す
+    pub fn read(&mut self, data: &mut [u8]) -> usize {
+        let mut n = 0;
+        while n < data.len() {
+            let Some(byte) = self.rx.pop_front() else {
+                break;
+            };
+            data[n] = byte;
+            n += 1;
+        }
+        n
+    }
+
+    pub fn rx_len(&self) -> usize {
+        self.rx.len()
+    }
+
+    pub fn rx_total(&self) -> u32 {
+        self.rx_total
+    }
+}
+
+impl<B: UsbBus> UsbClass<B> for VendorClass<'_, B> {
+    fn get_configuration_descriptors(
+        &self,
+        writer: &mut DescriptorWriter,
+    ) -> usb_device::Result<()> {
+        writer.interface_alt(self.iface, 0, USB_CLASS_VENDOR, 0, 0, Some(self.name))?;
+        writer.endpoint(&self.write_ep)?;
+        writer.endpoint(&self.read_ep)?;
+        Ok(())
+    }
+
+    fn get_bos_descriptors(&self, writer: &mut BosWriter) -> usb_device::Result<()> {
+        writer.capability(CAPABILITY_TYPE_PLATFORM, &ms_os_20_platform_capability())
+    }
+
+    fn get_string(&self, index: StringIndex, _lang_id: LangID) -> Option<&str> {
+        (index == self.name).then_some(INTERFACE_NAME)
+    }
+
+    // 空きが無ければ読まずにおく。読むまでは毎回のポーリングで呼ばれる
+    fn endpoint_out(&mut self, addr: EndpointAddress) {
+        if addr != self.read_ep.address() {
+            return;
+        }
+        let mut buf = [0u8; MAX_PACKET_SIZE as usize];
+        while self.rx.capacity() - self.rx.len() >= buf.len() {
+            let n = match self.read_packet(&mut buf) {
+                Ok(n) if n > 0 => n,
+                _ => break,
+            };
+            for &byte in &buf[..n] {
+                let _ = self.rx.push_back(byte);
+            }
+            self.rx_total = self.rx_total.wrapping_add(n as u32);
+        }
+    }
+
+    fn control_in(&mut self, xfer: ControlIn<B>) {
+        let req = xfer.request();
+        if req.request_type == RequestType::Vendor
+            && req.recipient == Recipient::Device
+            && req.request == MS_VENDOR_CODE
+            && req.index == MS_OS_20_DESCRIPTOR_INDEX
+        {
+            let _ = xfer.accept_with_static(self.ms_os_descriptor);
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum BulkCommand {
+    Read { len: usize },
+    Status,
+}
+
+impl BulkCommand {
+    pub fn parse(args: &mut Args) -> Result<Self, CommandError> {
+        let cmd = match args.next_str()? {
+            "read" => {
+                let len = match args.next_opt() {
+                    Some(s) => parse_u32(s)? as usize,
+                    None => MAX_READ_LEN,
+                };
+                if !(1..=MAX_READ_LEN).contains(&len) {
+                    return Err(CommandError::OutOfRange);
+                }
+                BulkCommand::Read { len }
+            }
+            "status" => BulkCommand::Status,
+            _ => return Err(CommandError::InvalidArgument),
+        };
+        if args.next_opt().is_some() {
+            return Err(CommandError::InvalidArgument);
+        }
+        Ok(cmd)
+    }
+}
+
+pub fn handle_command(args: &mut Args) -> Result<Reply, CommandError> {
+    let cmd = BulkCommand::parse(args)?;
+    interrupt::free(|cs| {
+        let mut vendor = VENDOR.borrow(cs).borrow_mut();
+        let vendor = vendor.as_mut().ok_or(CommandError::NotReady)?;
+        match cmd {
+            BulkCommand::Read { len } => {
+                let mut buf = [0u8; MAX_READ_LEN];
+                let n = vendor.read(&mut buf[..len]);
+                Ok(ok_reply(|r| {
+                    write!(r, " bulk read len={} data=", n)?;
+                    write_hex(r, &buf[..n])
+                }))
+            }
+            BulkCommand::Status => Ok(ok_reply(|r| {
+                write!(
+                    r,
+                    " bulk rx_buffered={} rx_total={}",
+                    vendor.rx_len(),
+                    vendor.rx_total()
+                )
+            })),
+        }
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn parse(line: &str) -> Result<BulkCommand, CommandError> {
+        BulkCommand::parse(&mut Args::new(line))
+    }
+
+    #[test]
+    fn parses_commands() {
+        assert_eq!(parse("read"), Ok(BulkCommand::Read { len: MAX_READ_LEN }));
+        assert_eq!(parse("read 16"), Ok(BulkCommand::Read { len: 16 }));
+        assert_eq!(parse("status"), Ok(BulkCommand::Status));
+        assert_eq!(parse("read 0"), Err(CommandError::OutOfRange));
+        assert_eq!(parse("read 65"), Err(CommandError::OutOfRange));
+        assert_eq!(parse("read 1 2"), Err(CommandError::InvalidArgument));
+        assert_eq!(parse("write"), Err(CommandError::InvalidArgument));
+        assert_eq!(parse(""), Err(CommandError::MissingArgument));
+    }
+}
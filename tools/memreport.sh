@@ -0,0 +1,65 @@
+#!/bin/sh
+# ビルドしたELFのメモリ配置の内訳を表示する (実行中の `*mem` と同じ項目)
+# 使い方: tools/memreport.sh [ELF]  (省略時はreleaseビルド)
+# nmは rust-nm (cargo-binutils) か arm-none-eabi-nm を使う。NM=... で指定もできる
+set -eu
+
+ELF=${1:-target/thumbv6m-none-eabi/release/pico-test}
+if [ -z "${NM:-}" ]; then
+    if command -v rust-nm >/dev/null 2>&1; then
+        NM=rust-nm
+    else
+        NM=arm-none-eabi-nm
+    fi
+fi
+
+if [ ! -f "$ELF" ]; then
+    echo "not found: $ELF (cargo build --release を先に実行)" >&2
+    exit 1
+fi
+
+"$NM" -S -C "$ELF" | awk '
+function hex(s,    i, c, v) {
+    v = 0
+    s = tolower(s)
+    for (i = 1; i <= length(s); i++) {
+        c = index("0123456789abcdef", substr(s, i, 1)) - 1
+        v = v * 16 + c
+    }
+    return v
+}
+# 大きさ付きの行は "アドレス 大きさ 種類 名前"、無い行は "アドレス 種類 名前"
+{
+    name = $NF
+    sym[name] = hex($1)
+    if (NF >= 4 && name ~ /CORE1_STACK$/) core1_stack = hex($2)
+}
+function span(a, b) { return sym[b] - sym[a] }
+function row(label, size, total) {
+    if (total > 0)
+        printf "%-10s %8d / %8d (%5.1f%%)\n", label, size, total, size * 100 / total
+    else
+        printf "%-10s %8d\n", label, size
+}
+END {
+    ram = span("_ram_start", "_ram_end")
+    data = span("__sdata", "__edata")
+    bss = span("__sbss", "__ebss")
+    uninit = span("__suninit", "__euninit")
+    heap = span("__heap_start", "__heap_end")
+    row("text", span("__stext", "__etext"), 0)
+    row("rodata", span("__srodata", "__erodata"), 0)
+    row("data", data, 0)
+    row("ram_text", span("__sram_text", "__eram_text"), 0)
+    row("bss", bss, 0)
+    row("uninit", uninit, 0)
+    row("heap", heap, 0)
+    # flip-linkの配置では_stack_endがスタックより上に残るので、RAMの先頭までがスタック (stack.rsと同じ)
+    stack0_bottom = sym["_stack_end"] <= sym["_stack_start"] ? sym["_stack_end"] : hex("20000000")
+    row("stack0", sym["_stack_start"] - stack0_bottom, 0)
+    # core1のスタックは.bssの内数
+    row("stack1", core1_stack, 0)
+    row("ram", data + bss + uninit + heap, ram)
+    row("sram4", span("__ssram4", "__esram4"), 4096)
+    row("sram5", span("__ssram5", "__esram5"), 4096)
+}'